@@ -0,0 +1,262 @@
+//! `#[derive(DBusType)]`, which implements `rdbus::marshal::DBusType` and
+//! `rdbus::marshal::DecodeOwned` for structs and fieldless enums.
+//!
+//! - Structs are marshalled as D-Bus structs, with their fields in order. Tuple structs are too.
+//!   They implement `DecodeOwned` only when all their fields do, so structs which borrow can be
+//!   encoded but not decoded.
+//! - `#[dbus(dict)]` on a struct marshals it as `a{sv}` instead, keyed by field name. Use
+//!   `#[dbus(rename = "Name")]` on a field to choose a different key. Decoding ignores keys that
+//!   are not fields, and fails if a field is missing or repeated.
+//! - Fieldless enums are marshalled as their discriminant, which is a `u32` unless
+//!   `#[dbus(repr = "...")]` names another integer type.
+//! - `#[dbus(string)]` on a fieldless enum marshals it as the name of the variant instead. Use
+//!   `#[dbus(rename = "name")]` on a variant to choose a different name.
+
+extern crate proc_macro;
+
+use proc_macro2::{Span, TokenStream};
+use quote::{format_ident, quote};
+use syn::spanned::Spanned;
+use syn::{parse_macro_input, parse_quote, Attribute, Data, DeriveInput, Error, Fields, Ident, LitStr, Result};
+
+#[proc_macro_derive(DBusType, attributes(dbus))]
+pub fn derive_dbus_type(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input).unwrap_or_else(Error::into_compile_error).into()
+}
+
+/// The options that `#[dbus(...)]` can hold
+#[derive(Default)]
+struct Options {
+    dict: bool,
+    string: bool,
+    repr: Option<Ident>,
+    rename: Option<String>,
+}
+
+fn options(attrs: &[Attribute]) -> Result<Options> {
+    let mut o = Options::default();
+    for attr in attrs.iter().filter(|a| a.path().is_ident("dbus")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("dict") {
+                o.dict = true;
+            } else if meta.path.is_ident("string") {
+                o.string = true;
+            } else if meta.path.is_ident("repr") {
+                let s: LitStr = meta.value()?.parse()?;
+                o.repr = Some(s.parse()?);
+            } else if meta.path.is_ident("rename") {
+                let s: LitStr = meta.value()?.parse()?;
+                o.rename = Some(s.value());
+            } else {
+                return Err(meta.error("unknown dbus option"));
+            }
+            Ok(())
+        })?;
+    }
+    Ok(o)
+}
+
+fn expand(mut input: DeriveInput) -> Result<TokenStream> {
+    let opts = options(&input.attrs)?;
+    for p in input.generics.type_params_mut() {
+        p.bounds.push(parse_quote!(::rdbus::marshal::DBusType));
+    }
+
+    let (signature, encode, decode) = match input.data {
+        Data::Struct(ref s) if opts.dict => dict_struct(&s.fields)?,
+        Data::Struct(ref s) => plain_struct(&s.fields)?,
+        Data::Enum(ref e) => {
+            let mut variants = vec![];
+            for v in &e.variants {
+                if !v.fields.is_empty() {
+                    return Err(Error::new(v.span(), "only enums without fields can derive DBusType"));
+                }
+                let name = options(&v.attrs)?.rename.unwrap_or_else(|| v.ident.to_string());
+                variants.push((v.ident.clone(), name));
+            }
+            if opts.string {
+                string_enum(&variants)
+            } else {
+                let repr = opts.repr.unwrap_or_else(|| Ident::new("u32", Span::call_site()));
+                integer_enum(&variants, &repr)
+            }
+        }
+        Data::Union(ref u) => return Err(Error::new(u.union_token.span, "unions cannot derive DBusType")),
+    };
+
+    /* decoding needs every field to decode, which borrowed fields cannot */
+    let mut decode_generics = input.generics.clone();
+    if let Data::Struct(ref s) = input.data {
+        let predicates = &mut decode_generics.make_where_clause().predicates;
+        for f in &s.fields {
+            let ty = &f.ty;
+            predicates.push(parse_quote!(#ty: ::rdbus::marshal::DecodeOwned));
+        }
+    }
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let (_, _, decode_where_clause) = decode_generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::rdbus::marshal::DBusType for #name #ty_generics #where_clause {
+            fn signature(sig: &mut ::std::vec::Vec<u8>) {
+                #signature
+            }
+
+            fn encode_into(&self, msg: &mut ::rdbus::marshal::Data)
+                -> ::std::result::Result<(), ::rdbus::marshal::EncodeError>
+            {
+                #encode
+            }
+        }
+
+        impl #impl_generics ::rdbus::marshal::DecodeOwned for #name #ty_generics #decode_where_clause {
+            fn decode_from(iter: &mut ::rdbus::marshal::DataIter)
+                -> ::std::result::Result<Self, ::rdbus::marshal::DecodeError>
+            {
+                #decode
+            }
+        }
+    })
+}
+
+/*
+ * The names of the fields, which are indexes for tuple structs
+ */
+fn members(fields: &Fields) -> Vec<syn::Member> {
+    fields.iter().enumerate().map(|(i, f)| match f.ident {
+        Some(ref ident) => syn::Member::Named(ident.clone()),
+        None => syn::Member::Unnamed(i.into()),
+    }).collect()
+}
+
+fn plain_struct(fields: &Fields) -> Result<(TokenStream, TokenStream, TokenStream)> {
+    if fields.is_empty() {
+        return Err(Error::new(fields.span(), "D-Bus structs must have at least one field"));
+    }
+    let types: Vec<_> = fields.iter().map(|f| &f.ty).collect();
+    let members = members(fields);
+
+    let signature = quote! {
+        sig.push(b'(');
+        #(<#types as ::rdbus::marshal::DBusType>::signature(sig);)*
+        sig.push(b')');
+    };
+    let encode = quote! {
+        ::rdbus::marshal::encode_struct(msg, |msg| {
+            #(::rdbus::marshal::DBusType::encode_into(&self.#members, msg)?;)*
+            Ok(())
+        })
+    };
+    let decode = quote! {
+        ::rdbus::marshal::decode_struct(iter, |iter| {
+            Ok(Self {
+                #(#members: <#types as ::rdbus::marshal::DecodeOwned>::decode_from(iter)?,)*
+            })
+        })
+    };
+    Ok((signature, encode, decode))
+}
+
+fn dict_struct(fields: &Fields) -> Result<(TokenStream, TokenStream, TokenStream)> {
+    let mut names = vec![];
+    let mut slots = vec![];
+    for f in fields {
+        let ident = match f.ident {
+            Some(ref i) => i,
+            None => return Err(Error::new(f.span(), "#[dbus(dict)] structs must have named fields")),
+        };
+        let name = options(&f.attrs)?.rename.unwrap_or_else(|| ident.to_string());
+        names.push(name);
+        slots.push(format_ident!("__field_{}", ident));
+    }
+    let types: Vec<_> = fields.iter().map(|f| &f.ty).collect();
+    let members = members(fields);
+
+    let signature = quote! {
+        sig.extend_from_slice(b"a{sv}");
+    };
+    let encode = quote! {
+        ::rdbus::marshal::encode_array(msg, 8, |msg| {
+            #(
+                ::rdbus::marshal::encode_struct(msg, |msg| {
+                    ::rdbus::marshal::DBusType::encode_into(#names, msg)?;
+                    ::rdbus::marshal::DBusType::encode_into(&::rdbus::marshal::Variant(&self.#members), msg)
+                })?;
+            )*
+            Ok(())
+        })
+    };
+    let decode = quote! {
+        let offset = iter.offset();
+        #(let mut #slots: ::std::option::Option<#types> = ::std::option::Option::None;)*
+        ::rdbus::marshal::decode_array(iter, 8, |iter| ::rdbus::marshal::decode_struct(iter, |iter| {
+            let entry = iter.offset();
+            let key = <::std::string::String as ::rdbus::marshal::DecodeOwned>::decode_from(iter)?;
+            match &key[..] {
+                #(#names => {
+                    if #slots.is_some() {
+                        return Err(::rdbus::marshal::DecodeError::Invalid {
+                            offset: entry, reason: "dict has a duplicate key" });
+                    }
+                    let v = <::rdbus::marshal::Variant<#types> as ::rdbus::marshal::DecodeOwned>::decode_from(iter)?;
+                    #slots = ::std::option::Option::Some(v.0);
+                })*
+                _ => {
+                    <::rdbus::value::Value as ::rdbus::marshal::DecodeOwned>::decode_from(iter)?;
+                }
+            }
+            Ok(())
+        }))?;
+        Ok(Self {
+            #(#members: #slots.ok_or(::rdbus::marshal::DecodeError::Invalid {
+                offset, reason: "dict is missing a field" })?,)*
+        })
+    };
+    Ok((signature, encode, decode))
+}
+
+fn integer_enum(variants: &[(Ident, String)], repr: &Ident) -> (TokenStream, TokenStream, TokenStream) {
+    let idents: Vec<_> = variants.iter().map(|v| &v.0).collect();
+
+    let signature = quote! {
+        <#repr as ::rdbus::marshal::DBusType>::signature(sig);
+    };
+    let encode = quote! {
+        let v = match *self {
+            #(Self::#idents => Self::#idents as #repr,)*
+        };
+        ::rdbus::marshal::DBusType::encode_into(&v, msg)
+    };
+    let decode = quote! {
+        let offset = iter.offset();
+        let v = <#repr as ::rdbus::marshal::DecodeOwned>::decode_from(iter)?;
+        #(if v == Self::#idents as #repr { return Ok(Self::#idents); })*
+        Err(::rdbus::marshal::DecodeError::Invalid { offset, reason: "value is not one of the enum's variants" })
+    };
+    (signature, encode, decode)
+}
+
+fn string_enum(variants: &[(Ident, String)]) -> (TokenStream, TokenStream, TokenStream) {
+    let idents: Vec<_> = variants.iter().map(|v| &v.0).collect();
+    let names: Vec<_> = variants.iter().map(|v| &v.1).collect();
+
+    let signature = quote! {
+        sig.push(b's');
+    };
+    let encode = quote! {
+        let s = match *self {
+            #(Self::#idents => #names,)*
+        };
+        ::rdbus::marshal::DBusType::encode_into(s, msg)
+    };
+    let decode = quote! {
+        let offset = iter.offset();
+        match &<::std::string::String as ::rdbus::marshal::DecodeOwned>::decode_from(iter)?[..] {
+            #(#names => Ok(Self::#idents),)*
+            _ => Err(::rdbus::marshal::DecodeError::Invalid { offset, reason: "value is not one of the enum's variants" }),
+        }
+    };
+    (signature, encode, decode)
+}
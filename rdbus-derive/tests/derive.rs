@@ -0,0 +1,134 @@
+use std::collections::BTreeMap;
+
+use rdbus::marshal::{DBusType, Data, DataIter, DecodeError, DecodeOwned};
+use rdbus::value::Value;
+
+#[derive(DBusType, Debug, PartialEq)]
+struct Point {
+    x: i32,
+    label: String,
+    tags: Vec<u8>,
+}
+
+#[derive(DBusType, Debug, PartialEq)]
+struct Pair<T>(u8, T);
+
+/* encodes, but does not decode as it borrows */
+#[derive(DBusType)]
+struct Borrowed<'a> {
+    name: &'a str,
+    id: u32,
+}
+
+#[derive(DBusType, Debug, PartialEq)]
+#[dbus(dict)]
+struct Props {
+    #[dbus(rename = "Name")]
+    name: String,
+    count: u32,
+}
+
+#[derive(DBusType, Debug, PartialEq, Clone, Copy)]
+enum Level {
+    Low = 1,
+    High = 7,
+}
+
+#[derive(DBusType, Debug, PartialEq)]
+#[dbus(repr = "u8")]
+enum Small {
+    A,
+    B,
+}
+
+#[derive(DBusType, Debug, PartialEq)]
+#[dbus(string)]
+enum State {
+    Running,
+    #[dbus(rename = "stopped")]
+    Stopped,
+}
+
+fn sig<T: DBusType>() -> String {
+    let mut s = vec![];
+    T::signature(&mut s);
+    String::from_utf8(s).unwrap()
+}
+
+fn round_trip<T: DecodeOwned + PartialEq + std::fmt::Debug>(v: T) -> Vec<u8> {
+    let mut m = Data::new();
+    m.append(1u8).unwrap();
+    m.append(&v).unwrap();
+    let mut i = m.iter();
+    i.read::<u8>().unwrap();
+    assert_eq!(i.read::<T>().unwrap(), v);
+    assert!(i.is_empty());
+    m.bytes().to_vec()
+}
+
+#[test]
+fn structs() {
+    assert_eq!(sig::<Point>(), "(isay)");
+    assert_eq!(sig::<Pair<Point>>(), "(y(isay))");
+
+    let p = Point { x: -1, label: "a".to_owned(), tags: vec![2, 3] };
+    let b = round_trip(Point { x: -1, label: "a".to_owned(), tags: vec![2, 3] });
+
+    /* the same bytes as the equivalent tuple */
+    let mut m = Data::new();
+    m.append(1u8).unwrap();
+    m.append((p.x, &p.label, &p.tags)).unwrap();
+    assert_eq!(m.bytes(), &b[..]);
+
+    round_trip(Pair(4, String::from("x")));
+
+    assert_eq!(sig::<Borrowed>(), "(su)");
+    let mut m = Data::new();
+    m.append(Borrowed { name: "a", id: 2 }).unwrap();
+    assert_eq!(m.bytes(), [1, 0, 0, 0, b'a', 0, 0, 0, 2, 0, 0, 0]);
+}
+
+#[test]
+fn dicts() {
+    assert_eq!(sig::<Props>(), "a{sv}");
+    round_trip(Props { name: "n".to_owned(), count: 3 });
+
+    /* decodes from any a{sv} with the right keys, ignoring others */
+    let mut d = BTreeMap::new();
+    d.insert("Name".to_owned(), Value::from("z"));
+    d.insert("count".to_owned(), Value::UInt32(9));
+    d.insert("Other".to_owned(), Value::from(vec![1u64]));
+    let mut m = Data::new();
+    m.append(&d).unwrap();
+    assert_eq!(m.iter().read::<Props>().unwrap(), Props { name: "z".to_owned(), count: 9 });
+
+    d.remove("count");
+    let mut m = Data::new();
+    m.append(&d).unwrap();
+    assert_eq!(m.iter().read::<Props>(), Err(DecodeError::Invalid {
+        offset: 0, reason: "dict is missing a field" }));
+
+    d.insert("count".to_owned(), Value::Int32(9));
+    let mut m = Data::new();
+    m.append(&d).unwrap();
+    match m.iter().read::<Props>() {
+        Err(DecodeError::WrongType { ref expected, ref found, .. }) if expected == "u" && found == "i" => {}
+        r => panic!("{:?}", r),
+    }
+}
+
+#[test]
+fn enums() {
+    assert_eq!(sig::<Level>(), "u");
+    assert_eq!(sig::<Small>(), "y");
+    assert_eq!(sig::<State>(), "s");
+
+    assert_eq!(round_trip(Level::High), [1, 0, 0, 0, 7, 0, 0, 0]);
+    assert_eq!(round_trip(Small::B), [1, 1]);
+    assert_eq!(round_trip(State::Stopped), [1, 0, 0, 0, 7, 0, 0, 0, b's', b't', b'o', b'p', b'p', b'e', b'd', 0]);
+    round_trip(State::Running);
+
+    let mut i = DataIter::new(&[2, 0, 0, 0], b"u", &[], rdbus::header::ENDIAN_LITTLE);
+    assert_eq!(i.read::<Level>(), Err(DecodeError::Invalid {
+        offset: 0, reason: "value is not one of the enum's variants" }));
+}
@@ -0,0 +1,501 @@
+//! D-Bus server addresses, as found in `DBUS_SESSION_BUS_ADDRESS` and friends.
+//!
+//! An address string is a ';' separated list of alternatives. Each alternative is a transport
+//! name, a ':', and a ',' separated list of `key=value` pairs. Values may contain `%XX` escapes.
+//!
+//! ```text
+//! unix:path=/run/dbus/system_bus_socket;tcp:host=localhost,port=1234,guid=...
+//! ```
+
+use std::ffi::OsString;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+#[cfg(unix)]
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+/// A single server address: a transport and the (optional) guid of the server behind it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Address {
+    transport: Transport,
+    guid: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Family {
+    Ipv4,
+    Ipv6,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Transport {
+    /// `unix:path=`, a unix socket in the filesystem
+    UnixPath(PathBuf),
+    /// `unix:abstract=`, a unix socket in the (linux specific) abstract namespace
+    UnixAbstract(Vec<u8>),
+    /// `unix:tmpdir=`, only meaningful to servers, which pick a socket name inside the directory
+    UnixTmpdir(PathBuf),
+    /// `unix:runtime=yes`, the socket `bus` in `$XDG_RUNTIME_DIR`
+    UnixRuntime,
+    Tcp {
+        host: Option<String>,
+        port: Option<u16>,
+        family: Option<Family>,
+    },
+    NonceTcp {
+        host: Option<String>,
+        port: Option<u16>,
+        family: Option<Family>,
+        noncefile: Option<PathBuf>,
+    },
+    /// `unixexec:`, spawn `path` with arguments `argv` and talk over its stdin & stdout
+    UnixExec {
+        path: PathBuf,
+        argv: Vec<String>,
+    },
+    /// A transport we do not know about. Kept so that addresses round trip.
+    Other {
+        name: String,
+        params: Vec<(String, String)>,
+    },
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AddressError {
+    /// The address has no ':' seperating the transport from the parameters
+    MissingTransport,
+    /// A parameter was not of the form `key=value`
+    MissingValue(String),
+    /// A '%' was not followed by 2 hex digits
+    BadEscape(String),
+    DuplicateKey(String),
+    /// A key is not understood by the transport
+    UnknownKey(String),
+    /// A key the transport requires was not given
+    MissingKey(&'static str),
+    /// Keys that may not be combined were given together
+    ConflictingKeys(&'static str, &'static str),
+    InvalidValue(String, String),
+}
+
+impl fmt::Display for AddressError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AddressError::MissingTransport => write!(fmt, "Address has no transport"),
+            AddressError::MissingValue(ref p) => write!(fmt, "Address parameter '{}' has no value", p),
+            AddressError::BadEscape(ref v) => write!(fmt, "Address value '{}' contains an invalid escape", v),
+            AddressError::DuplicateKey(ref k) => write!(fmt, "Address key '{}' given more than once", k),
+            AddressError::UnknownKey(ref k) => write!(fmt, "Address key '{}' is not known to the transport", k),
+            AddressError::MissingKey(k) => write!(fmt, "Address is missing required key '{}'", k),
+            AddressError::ConflictingKeys(a, b) => write!(fmt, "Address keys '{}' and '{}' conflict", a, b),
+            AddressError::InvalidValue(ref k, ref v) => write!(fmt, "Address key '{}' has invalid value '{}'", k, v),
+        }
+    }
+}
+
+impl ::std::error::Error for AddressError {}
+
+impl Address {
+    pub fn new(transport: Transport) -> Address {
+        Address { transport, guid: None }
+    }
+
+    /// Parse a ';' seperated list of addresses.
+    pub fn parse_list(s: &str) -> Result<Vec<Address>, AddressError> {
+        s.split(';').filter(|a| !a.is_empty()).map(|a| a.parse()).collect()
+    }
+
+    pub fn transport(&self) -> &Transport {
+        &self.transport
+    }
+
+    /// The server guid, if the address includes one
+    pub fn guid(&self) -> Option<&str> {
+        self.guid.as_ref().map(|g| &g[..])
+    }
+
+    pub fn set_guid(&mut self, guid: Option<String>) {
+        self.guid = guid;
+    }
+}
+
+/*
+ * Accumulates the key value pairs of a single address so each transport can pull out the keys it
+ * understands and complain about the rest.
+ */
+struct Params {
+    kv: Vec<(String, Vec<u8>)>,
+}
+
+impl Params {
+    fn take(&mut self, key: &str) -> Option<Vec<u8>> {
+        let p = self.kv.iter().position(|(k, _)| k == key)?;
+        Some(self.kv.remove(p).1)
+    }
+
+    fn take_str(&mut self, key: &str) -> Result<Option<String>, AddressError> {
+        match self.take(key) {
+            Some(v) => String::from_utf8(v)
+                .map(Some)
+                .map_err(|e| AddressError::InvalidValue(key.to_owned(),
+                                                        String::from_utf8_lossy(e.as_bytes()).into_owned())),
+            None => Ok(None),
+        }
+    }
+
+    fn take_path(&mut self, key: &str) -> Option<PathBuf> {
+        self.take(key).map(|v| PathBuf::from(os_string_from_vec(v)))
+    }
+
+    fn take_port(&mut self) -> Result<Option<u16>, AddressError> {
+        match self.take_str("port")? {
+            Some(p) => p.parse().map(Some).map_err(|_| AddressError::InvalidValue("port".to_owned(), p)),
+            None => Ok(None),
+        }
+    }
+
+    fn take_family(&mut self) -> Result<Option<Family>, AddressError> {
+        match self.take_str("family")? {
+            Some(ref f) if f == "ipv4" => Ok(Some(Family::Ipv4)),
+            Some(ref f) if f == "ipv6" => Ok(Some(Family::Ipv6)),
+            Some(f) => Err(AddressError::InvalidValue("family".to_owned(), f)),
+            None => Ok(None),
+        }
+    }
+
+    fn finish(self) -> Result<(), AddressError> {
+        match self.kv.into_iter().next() {
+            Some((k, _)) => Err(AddressError::UnknownKey(k)),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn os_string_from_vec(v: Vec<u8>) -> OsString {
+    OsString::from_vec(v)
+}
+
+#[cfg(unix)]
+fn os_str_bytes(p: &::std::path::Path) -> &[u8] {
+    p.as_os_str().as_bytes()
+}
+
+fn hex_val(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Decode `%XX` escapes in an address value
+pub fn unescape(v: &str) -> Result<Vec<u8>, AddressError> {
+    let b = v.as_bytes();
+    let mut out = Vec::with_capacity(b.len());
+    let mut i = 0;
+    while i < b.len() {
+        if b[i] == b'%' {
+            if i + 2 >= b.len() {
+                return Err(AddressError::BadEscape(v.to_owned()));
+            }
+            match (hex_val(b[i + 1]), hex_val(b[i + 2])) {
+                (Some(h), Some(l)) => out.push(h << 4 | l),
+                _ => return Err(AddressError::BadEscape(v.to_owned())),
+            }
+            i += 3;
+        } else {
+            out.push(b[i]);
+            i += 1;
+        }
+    }
+    Ok(out)
+}
+
+/// Escape a value for inclusion in an address. Only the "optionally escaped" bytes are left as is.
+pub fn escape(v: &[u8]) -> String {
+    let mut out = String::with_capacity(v.len());
+    for &c in v {
+        match c {
+            b'-' | b'0'..=b'9' | b'A'..=b'Z' | b'a'..=b'z' | b'_' | b'/' | b'.' | b'\\' | b'*' => {
+                out.push(c as char)
+            }
+            _ => out.push_str(&format!("%{:02x}", c)),
+        }
+    }
+    out
+}
+
+impl FromStr for Address {
+    type Err = AddressError;
+
+    fn from_str(s: &str) -> Result<Address, AddressError> {
+        let colon = s.find(':').ok_or(AddressError::MissingTransport)?;
+        let (name, rest) = (&s[..colon], &s[colon + 1..]);
+        if name.is_empty() {
+            return Err(AddressError::MissingTransport);
+        }
+
+        let mut params = Params { kv: vec![] };
+        for p in rest.split(',').filter(|p| !p.is_empty()) {
+            let eq = p.find('=').ok_or_else(|| AddressError::MissingValue(p.to_owned()))?;
+            let k = &p[..eq];
+            if params.kv.iter().any(|(e, _)| e == k) {
+                return Err(AddressError::DuplicateKey(k.to_owned()));
+            }
+            params.kv.push((k.to_owned(), unescape(&p[eq + 1..])?));
+        }
+
+        let guid = params.take_str("guid")?;
+        let transport = match name {
+            "unix" => {
+                let path = params.take_path("path");
+                let abs = params.take("abstract");
+                let tmpdir = params.take_path("tmpdir");
+                let runtime = params.take_str("runtime")?;
+                params.finish()?;
+
+                match (path, abs, tmpdir, runtime) {
+                    (Some(p), None, None, None) => Transport::UnixPath(p),
+                    (None, Some(a), None, None) => Transport::UnixAbstract(a),
+                    (None, None, Some(t), None) => Transport::UnixTmpdir(t),
+                    (None, None, None, Some(r)) => {
+                        if r != "yes" {
+                            return Err(AddressError::InvalidValue("runtime".to_owned(), r));
+                        }
+                        Transport::UnixRuntime
+                    }
+                    (None, None, None, None) => return Err(AddressError::MissingKey("path")),
+                    (Some(_), Some(_), _, _) => return Err(AddressError::ConflictingKeys("path", "abstract")),
+                    (Some(_), _, Some(_), _) => return Err(AddressError::ConflictingKeys("path", "tmpdir")),
+                    (Some(_), _, _, Some(_)) => return Err(AddressError::ConflictingKeys("path", "runtime")),
+                    (_, Some(_), Some(_), _) => return Err(AddressError::ConflictingKeys("abstract", "tmpdir")),
+                    (_, Some(_), _, Some(_)) => return Err(AddressError::ConflictingKeys("abstract", "runtime")),
+                    (_, _, Some(_), Some(_)) => return Err(AddressError::ConflictingKeys("tmpdir", "runtime")),
+                }
+            }
+            "tcp" => {
+                let t = Transport::Tcp {
+                    host: params.take_str("host")?,
+                    port: params.take_port()?,
+                    family: params.take_family()?,
+                };
+                params.finish()?;
+                t
+            }
+            "nonce-tcp" => {
+                let t = Transport::NonceTcp {
+                    host: params.take_str("host")?,
+                    port: params.take_port()?,
+                    family: params.take_family()?,
+                    noncefile: params.take_path("noncefile"),
+                };
+                params.finish()?;
+                t
+            }
+            "unixexec" => {
+                let path = params.take_path("path").ok_or(AddressError::MissingKey("path"))?;
+                let mut argv = vec![];
+                let mut i = 0;
+                while let Some(a) = params.take_str(&format!("argv{}", i))? {
+                    argv.push(a);
+                    i += 1;
+                }
+                params.finish()?;
+                Transport::UnixExec { path, argv }
+            }
+            _ => {
+                let params = params.kv
+                    .into_iter()
+                    .map(|(k, v)| (k, String::from_utf8_lossy(&v).into_owned()))
+                    .collect();
+                Transport::Other { name: name.to_owned(), params }
+            }
+        };
+
+        Ok(Address { transport, guid })
+    }
+}
+
+fn push_inet(params: &mut Vec<(String, String)>, host: &Option<String>, port: Option<u16>,
+             family: Option<Family>) {
+    if let Some(ref h) = *host {
+        params.push(("host".to_owned(), escape(h.as_bytes())));
+    }
+    if let Some(p) = port {
+        params.push(("port".to_owned(), p.to_string()));
+    }
+    match family {
+        Some(Family::Ipv4) => params.push(("family".to_owned(), "ipv4".to_owned())),
+        Some(Family::Ipv6) => params.push(("family".to_owned(), "ipv6".to_owned())),
+        None => {}
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let mut params: Vec<(String, String)> = vec![];
+        let name = match self.transport {
+            Transport::UnixPath(ref p) => {
+                params.push(("path".to_owned(), escape(os_str_bytes(p))));
+                "unix"
+            }
+            Transport::UnixAbstract(ref a) => {
+                params.push(("abstract".to_owned(), escape(a)));
+                "unix"
+            }
+            Transport::UnixTmpdir(ref p) => {
+                params.push(("tmpdir".to_owned(), escape(os_str_bytes(p))));
+                "unix"
+            }
+            Transport::UnixRuntime => {
+                params.push(("runtime".to_owned(), "yes".to_owned()));
+                "unix"
+            }
+            Transport::Tcp { ref host, port, family } => {
+                push_inet(&mut params, host, port, family);
+                "tcp"
+            }
+            Transport::NonceTcp { ref host, port, family, ref noncefile } => {
+                push_inet(&mut params, host, port, family);
+                if let Some(ref n) = *noncefile {
+                    params.push(("noncefile".to_owned(), escape(os_str_bytes(n))));
+                }
+                "nonce-tcp"
+            }
+            Transport::UnixExec { ref path, ref argv } => {
+                params.push(("path".to_owned(), escape(os_str_bytes(path))));
+                for (i, a) in argv.iter().enumerate() {
+                    params.push((format!("argv{}", i), escape(a.as_bytes())));
+                }
+                "unixexec"
+            }
+            Transport::Other { ref name, params: ref p } => {
+                for (k, v) in p {
+                    params.push((k.clone(), escape(v.as_bytes())));
+                }
+                name
+            }
+        };
+
+        if let Some(ref g) = self.guid {
+            params.push(("guid".to_owned(), escape(g.as_bytes())));
+        }
+
+        write!(fmt, "{}:", name)?;
+        for (i, (k, v)) in params.iter().enumerate() {
+            if i != 0 {
+                write!(fmt, ",")?;
+            }
+            write!(fmt, "{}={}", k, v)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Address, AddressError, Family, Transport};
+    use std::path::PathBuf;
+
+    #[test]
+    fn unix() {
+        let a: Address = "unix:path=/run/dbus/system_bus_socket".parse().unwrap();
+        assert_eq!(*a.transport(), Transport::UnixPath(PathBuf::from("/run/dbus/system_bus_socket")));
+        assert_eq!(a.guid(), None);
+
+        let a: Address = "unix:abstract=/tmp/dbus-AbC,guid=0123456789abcdef0123456789abcdef".parse().unwrap();
+        assert_eq!(*a.transport(), Transport::UnixAbstract(b"/tmp/dbus-AbC".to_vec()));
+        assert_eq!(a.guid(), Some("0123456789abcdef0123456789abcdef"));
+
+        let a: Address = "unix:tmpdir=/tmp".parse().unwrap();
+        assert_eq!(*a.transport(), Transport::UnixTmpdir(PathBuf::from("/tmp")));
+
+        let a: Address = "unix:runtime=yes".parse().unwrap();
+        assert_eq!(*a.transport(), Transport::UnixRuntime);
+
+        assert_eq!("unix:runtime=no".parse::<Address>(),
+                   Err(AddressError::InvalidValue("runtime".to_owned(), "no".to_owned())));
+        assert_eq!("unix:path=/a,abstract=b".parse::<Address>(),
+                   Err(AddressError::ConflictingKeys("path", "abstract")));
+        assert_eq!("unix:".parse::<Address>(), Err(AddressError::MissingKey("path")));
+        assert_eq!("unix:path=/a,foo=b".parse::<Address>(), Err(AddressError::UnknownKey("foo".to_owned())));
+        assert_eq!("unix:path=/a,path=/b".parse::<Address>(), Err(AddressError::DuplicateKey("path".to_owned())));
+    }
+
+    #[test]
+    fn tcp() {
+        let a: Address = "tcp:host=127.0.0.1,port=4000,family=ipv4".parse().unwrap();
+        assert_eq!(*a.transport(), Transport::Tcp {
+            host: Some("127.0.0.1".to_owned()),
+            port: Some(4000),
+            family: Some(Family::Ipv4),
+        });
+
+        let a: Address = "nonce-tcp:host=localhost,port=4000,noncefile=/tmp/n".parse().unwrap();
+        assert_eq!(*a.transport(), Transport::NonceTcp {
+            host: Some("localhost".to_owned()),
+            port: Some(4000),
+            family: None,
+            noncefile: Some(PathBuf::from("/tmp/n")),
+        });
+
+        "tcp:port=70000".parse::<Address>().err().unwrap();
+        "tcp:family=ipx".parse::<Address>().err().unwrap();
+    }
+
+    #[test]
+    fn unixexec() {
+        let a: Address = "unixexec:path=/usr/bin/ssh,argv0=ssh,argv1=host,argv2=dbus-stdio".parse().unwrap();
+        assert_eq!(*a.transport(), Transport::UnixExec {
+            path: PathBuf::from("/usr/bin/ssh"),
+            argv: vec!["ssh".to_owned(), "host".to_owned(), "dbus-stdio".to_owned()],
+        });
+        "unixexec:argv0=ssh".parse::<Address>().err().unwrap();
+    }
+
+    #[test]
+    fn escapes() {
+        let a: Address = "unix:path=/tmp/a%20b%2cc".parse().unwrap();
+        assert_eq!(*a.transport(), Transport::UnixPath(PathBuf::from("/tmp/a b,c")));
+        assert_eq!(a.to_string(), "unix:path=/tmp/a%20b%2cc");
+
+        /* paths that are not UTF-8, or hold the characters that separate addresses, round trip */
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+        let p = PathBuf::from(OsStr::from_bytes(b"/run/a;b,c=d%e\xff/bus"));
+        let s = Address::new(Transport::UnixPath(p.clone())).to_string();
+        let l = Address::parse_list(&s).unwrap();
+        assert_eq!(l.len(), 1);
+        assert_eq!(*l[0].transport(), Transport::UnixPath(p));
+
+        assert_eq!("unix:path=/a%2".parse::<Address>(), Err(AddressError::BadEscape("/a%2".to_owned())));
+        assert_eq!("unix:path=/a%zz".parse::<Address>(), Err(AddressError::BadEscape("/a%zz".to_owned())));
+    }
+
+    #[test]
+    fn list() {
+        let l = Address::parse_list("unix:path=/a;tcp:host=h,port=1;foo:bar=baz").unwrap();
+        assert_eq!(l.len(), 3);
+        assert_eq!(*l[2].transport(), Transport::Other {
+            name: "foo".to_owned(),
+            params: vec![("bar".to_owned(), "baz".to_owned())],
+        });
+        assert_eq!(l[1].to_string(), "tcp:host=h,port=1");
+        assert_eq!(l[2].to_string(), "foo:bar=baz");
+        "nocolon".parse::<Address>().err().unwrap();
+    }
+
+    #[test]
+    fn round_trip() {
+        for s in &["unix:path=/run/dbus/system_bus_socket,guid=abc",
+                   "unix:abstract=%00x",
+                   "unix:runtime=yes",
+                   "nonce-tcp:host=localhost,port=9,family=ipv6,noncefile=/n"] {
+            let a: Address = s.parse().unwrap();
+            assert_eq!(a.to_string(), *s);
+        }
+    }
+}
@@ -1,9 +1,1313 @@
 //! Before normal communcations start, a seperate authentication protocol is used
+//!
+//! The protocol is line based: each command is ASCII text terminated by "\r\n". The client opens
+//! with a single nul byte, picks a mechanism with `AUTH`, and once the server says `OK` sends
+//! `BEGIN`, after which the stream carries dbus messages.
 
+use libc;
+use sha1_smol;
+use std::env;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::str;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Longest line we will accept from the peer. libdbus uses the same limit.
+const MAX_LINE_LEN: usize = 16 * 1024;
+
+#[derive(Debug)]
+pub enum AuthError {
+    Io(io::Error),
+    /// The server rejected our mechanism. Contains the mechanisms the server does support.
+    Rejected(Vec<String>),
+    /// The server sent an `ERROR` command, with its (optional) explanation
+    Error(String),
+    /// The peer sent something which is not valid in the auth protocol
+    Protocol(String),
+    /// A `DBUS_COOKIE_SHA1` keyring could not be used
+    Keyring(String),
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AuthError::Io(ref e) => write!(fmt, "I/O error during authentication: {}", e),
+            AuthError::Rejected(ref m) => {
+                write!(fmt, "Authentication rejected, server supports: {}", m.join(" "))
+            }
+            AuthError::Error(ref e) => write!(fmt, "Authentication error from server: {}", e),
+            AuthError::Protocol(ref e) => write!(fmt, "Authentication protocol violation: {}", e),
+            AuthError::Keyring(ref e) => write!(fmt, "Unusable cookie keyring: {}", e),
+        }
+    }
+}
+
+impl error::Error for AuthError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            AuthError::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for AuthError {
+    fn from(e: io::Error) -> AuthError {
+        AuthError::Io(e)
+    }
+}
+
+/// The effective user id of the current process, as used by the `EXTERNAL` mechanism. This is
+/// the uid a server sees in our socket credentials (`SO_PEERCRED`), so setuid programs claim it.
+pub fn current_uid() -> u32 {
+    unsafe { libc::geteuid() }
+}
+
+/// Hex encode `b` the way the auth protocol expects (lower case, 2 digits per byte)
+pub fn hex_encode(b: &[u8]) -> String {
+    let mut s = String::with_capacity(b.len() * 2);
+    for c in b {
+        s.push_str(&format!("{:02x}", c));
+    }
+    s
+}
+
+/// Decode a hex string, as found in `DATA` commands and initial responses
+pub fn hex_decode(s: &str) -> Option<Vec<u8>> {
+    if s.len() & 1 != 0 {
+        return None;
+    }
+
+    s.as_bytes()
+        .chunks(2)
+        .map(|c| {
+            let h = (c[0] as char).to_digit(16)?;
+            let l = (c[1] as char).to_digit(16)?;
+            Some((h << 4 | l) as u8)
+        })
+        .collect()
+}
 
 /*
-fn send_auth<T: Write>(w: W, mech: Option<&str>, resp: Option<&str>)
+ * Read a single "\r\n" terminated line, without the terminator.
+ *
+ * Reads a byte at a time so we never consume data that follows the auth conversation.
+ */
+fn read_line<R: Read>(r: &mut R) -> Result<String, AuthError> {
+    let mut line = vec![];
+    let mut b = [0u8];
+    loop {
+        match r.read(&mut b) {
+            Ok(0) => return Err(AuthError::Io(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                                             "stream ended during authentication"))),
+            Ok(_) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(AuthError::Io(e)),
+        }
+
+        line.push(b[0]);
+        if line.ends_with(b"\r\n") {
+            line.truncate(line.len() - 2);
+            break;
+        }
+
+        if line.len() > MAX_LINE_LEN {
+            return Err(AuthError::Protocol("line too long".to_owned()));
+        }
+    }
+
+    String::from_utf8(line)
+        .ok()
+        .filter(|l| l.is_ascii())
+        .ok_or_else(|| AuthError::Protocol("line is not ASCII".to_owned()))
+}
+
+fn write_line<W: Write>(w: &mut W, line: &str) -> Result<(), AuthError> {
+    w.write_all(format!("{}\r\n", line).as_bytes())?;
+    w.flush()?;
+    Ok(())
+}
+
+/*
+ * Split a line into the command and the rest of the line
+ */
+fn split_command(line: &str) -> (&str, &str) {
+    match line.find(' ') {
+        Some(i) => (&line[..i], &line[i + 1..]),
+        None => (line, ""),
+    }
+}
+
+/// An authentication mechanism a client may offer
+#[derive(Debug, Clone)]
+pub enum Mechanism {
+    /// `EXTERNAL`: claim to be `uid`, which the server checks against the socket credentials
+    External(u32),
+    /// `DBUS_COOKIE_SHA1`: as `uid`, prove we can read the secret cookies in `keyring_dir`
+    CookieSha1 { uid: u32, keyring_dir: PathBuf },
+}
+
+impl Mechanism {
+    /// `EXTERNAL` as the current user
+    pub fn external() -> Mechanism {
+        Mechanism::External(current_uid())
+    }
+
+    /// `DBUS_COOKIE_SHA1` as the current user, using the keyrings in `$HOME/.dbus-keyrings`
+    pub fn cookie_sha1() -> Option<Mechanism> {
+        Some(Mechanism::CookieSha1 { uid: current_uid(), keyring_dir: default_keyring_dir()? })
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Mechanism::External(_) => "EXTERNAL",
+            Mechanism::CookieSha1 { .. } => "DBUS_COOKIE_SHA1",
+        }
+    }
+
+    fn initial_response(&self) -> Vec<u8> {
+        match *self {
+            Mechanism::External(uid) | Mechanism::CookieSha1 { uid, .. } => uid.to_string().into_bytes(),
+        }
+    }
+
+    /*
+     * Respond to a challenge sent by the server with `DATA`
+     */
+    fn respond(&self, challenge: &[u8]) -> Result<Vec<u8>, AuthError> {
+        match *self {
+            /* we already sent our identity as the initial response, so there is nothing to add */
+            Mechanism::External(_) => Ok(vec![]),
+            Mechanism::CookieSha1 { ref keyring_dir, .. } => cookie_sha1_respond(keyring_dir, challenge),
+        }
+    }
+}
+
+/**
+ * Authenticate as `uid` with the `EXTERNAL` mechanism, and finish the auth conversation.
+ *
+ * `s` must be freshly connected: the initial nul byte is sent here. On success the stream is left
+ * just after our `BEGIN`, ready for dbus messages, and the server's guid is returned.
+ */
+pub fn authenticate_external<S: Read + Write>(s: &mut S, uid: u32) -> Result<String, AuthError> {
+    authenticate(s, &[Mechanism::External(uid)], false).map(|a| a.guid)
+}
+
+/// The outcome of a successful client side authentication
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Authenticated {
+    /// The server's guid
+    pub guid: String,
+    /// Whether the server agreed to unix fd passing
+    pub unix_fd: bool,
+}
+
+/**
+ * Authenticate with the first of `mechs` the server accepts, and finish the auth conversation.
+ *
+ * As with `authenticate_external()`, `s` must be freshly connected, and is ready for dbus messages
+ * on success. If `unix_fd` is set, we also ask the server to allow passing unix fds.
+ *
+ * A mechanism the server answers with `ERROR` is cancelled, and the next one tried. If no mechanism
+ * works, the last rejection (or the local failure or server error that caused us to abandon a
+ * mechanism) is returned.
+ */
+pub fn authenticate<S: Read + Write>(s: &mut S, mechs: &[Mechanism], unix_fd: bool)
+    -> Result<Authenticated, AuthError>
 {
+    s.write_all(b"\0")?;
+
+    let mut last_err = AuthError::Rejected(vec![]);
+    for m in mechs {
+        match try_mechanism(s, m) {
+            Ok(guid) => {
+                let unix_fd = unix_fd && negotiate_unix_fd(s)?;
+                write_line(s, "BEGIN")?;
+                return Ok(Authenticated { guid, unix_fd });
+            }
+            Err(Abandoned::Rejected(r)) => last_err = AuthError::Rejected(r),
+            Err(Abandoned::Cancelled(e)) => last_err = e,
+            Err(Abandoned::Fatal(e)) => return Err(e),
+        }
+    }
+
+    Err(last_err)
+}
+
+fn negotiate_unix_fd<S: Read + Write>(s: &mut S) -> Result<bool, AuthError> {
+    write_line(s, "NEGOTIATE_UNIX_FD")?;
+    let line = read_line(s)?;
+    match split_command(&line).0 {
+        "AGREE_UNIX_FD" => Ok(true),
+        "ERROR" => Ok(false),
+        _ => Err(AuthError::Protocol(format!("unexpected reply to NEGOTIATE_UNIX_FD: '{}'", line))),
+    }
+}
+
+/*
+ * Why a single mechanism did not succeed. Rejection and local failures allow trying the next
+ * mechanism.
+ */
+enum Abandoned {
+    Rejected(Vec<String>),
+    Cancelled(AuthError),
+    Fatal(AuthError),
+}
+
+impl From<AuthError> for Abandoned {
+    fn from(e: AuthError) -> Abandoned {
+        Abandoned::Fatal(e)
+    }
+}
+
+fn parse_rejected(rest: &str) -> Vec<String> {
+    rest.split(' ').filter(|m| !m.is_empty()).map(|m| m.to_owned()).collect()
+}
+
+fn try_mechanism<S: Read + Write>(s: &mut S, m: &Mechanism) -> Result<String, Abandoned> {
+    write_line(s, &format!("AUTH {} {}", m.name(), hex_encode(&m.initial_response())))?;
+
+    /* a local failure or server error, waiting for the server to acknowledge our CANCEL */
+    let mut cancelled = None;
+    loop {
+        let line = read_line(s)?;
+        let (cmd, rest) = split_command(&line);
+        match cmd {
+            "OK" => {
+                if rest.is_empty() {
+                    let e = AuthError::Protocol("OK without a server guid".to_owned());
+                    return Err(Abandoned::Fatal(e));
+                }
+                return Ok(rest.to_owned());
+            }
+            "REJECTED" => {
+                return Err(match cancelled {
+                    Some(e) => Abandoned::Cancelled(e),
+                    None => Abandoned::Rejected(parse_rejected(rest)),
+                });
+            }
+            "DATA" => {
+                let challenge = hex_decode(rest)
+                    .ok_or_else(|| AuthError::Protocol("DATA is not hex encoded".to_owned()))?;
+                match m.respond(&challenge) {
+                    Ok(r) => write_line(s, format!("DATA {}", hex_encode(&r)).trim_end())?,
+                    Err(e) => {
+                        write_line(s, "CANCEL")?;
+                        cancelled = Some(e);
+                    }
+                }
+            }
+            /* the server could not handle what we sent, so give up on this mechanism */
+            "ERROR" if cancelled.is_none() => {
+                write_line(s, "CANCEL")?;
+                cancelled = Some(AuthError::Error(rest.to_owned()));
+            }
+            "ERROR" => return Err(Abandoned::Fatal(AuthError::Error(rest.to_owned()))),
+            _ => {
+                write_line(s, "ERROR \"unknown command\"")?;
+                let e = AuthError::Protocol(format!("unexpected command from server: '{}'", line));
+                return Err(Abandoned::Fatal(e));
+            }
+        }
+    }
+}
+
+/*
+ * DBUS_COOKIE_SHA1
+ *
+ * The server and client share a secret cookie stored in a keyring file only the user can read,
+ * `~/.dbus-keyrings/<context>`. Each line of the file is `<id> <creation time> <cookie>`.
+ *
+ * The server sends `<context> <cookie id> <server challenge>`, and the client replies with
+ * `<client challenge> <sha1 of "<server challenge>:<client challenge>:<cookie>">`.
+ */
+
+/// Cookies older than this are not used for new challenges
+const COOKIE_NEW_KEY_TIMEOUT: u64 = 5 * 60;
+/// Cookies older than this are removed from the keyring
+const COOKIE_EXPIRE_TIMEOUT: u64 = COOKIE_NEW_KEY_TIMEOUT + 2 * 60;
+/// Cookies dated further than this into the future are removed from the keyring
+const COOKIE_MAX_TIME_TRAVEL: u64 = 5 * 60;
+
+/// `$HOME/.dbus-keyrings`, where the cookies for `DBUS_COOKIE_SHA1` are kept
+pub fn default_keyring_dir() -> Option<PathBuf> {
+    let home = env::var_os("HOME")?;
+    Some(Path::new(&home).join(".dbus-keyrings"))
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn random_bytes(n: usize) -> io::Result<Vec<u8>> {
+    let mut v = vec![0u8; n];
+    fs::File::open("/dev/urandom")?.read_exact(&mut v)?;
+    Ok(v)
+}
+
+fn cookie_hash(server_challenge: &str, client_challenge: &str, cookie: &str) -> String {
+    let s = format!("{}:{}:{}", server_challenge, client_challenge, cookie);
+    sha1_smol::Sha1::from(s).digest().to_string()
+}
+
+/*
+ * A context names a file in the keyring directory, so it must not be able to escape it.
+ */
+fn validate_context(context: &str) -> Result<(), AuthError> {
+    let valid = |c: u8| c.is_ascii_graphic() && c != b'/' && c != b'\\' && c != b'.';
+    if context.is_empty() || !context.bytes().all(valid) {
+        return Err(AuthError::Protocol(format!("invalid cookie context '{}'", context)));
+    }
+    Ok(())
+}
+
+/*
+ * Anyone who can read or replace the cookies can authenticate as us, so refuse to use a keyring
+ * directory others have access to.
+ */
+fn check_keyring_dir(dir: &Path) -> Result<(), AuthError> {
+    let md = fs::metadata(dir)?;
+    if !md.is_dir() {
+        return Err(AuthError::Keyring(format!("'{}' is not a directory", dir.display())));
+    }
+    if md.uid() != current_uid() {
+        return Err(AuthError::Keyring(format!("'{}' is not owned by the current user", dir.display())));
+    }
+    if md.mode() & 0o077 != 0 {
+        return Err(AuthError::Keyring(format!("'{}' is accessible by other users (mode {:o})",
+                                              dir.display(), md.mode() & 0o777)));
+    }
+    Ok(())
+}
+
+/// A single secret in a `DBUS_COOKIE_SHA1` keyring
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cookie {
+    pub id: u32,
+    /// Seconds since the unix epoch
+    pub created: u64,
+    pub secret: String,
+}
+
+/**
+ * The cookies for one context in a keyring directory.
+ *
+ * Clients only read keyrings. Servers also create new cookies as the old ones age, and remove
+ * expired ones.
+ */
+pub struct Keyring {
+    dir: PathBuf,
+    context: String,
+}
+
+impl Keyring {
+    pub fn new(dir: PathBuf, context: &str) -> Result<Keyring, AuthError> {
+        validate_context(context)?;
+        Ok(Keyring { dir, context: context.to_owned() })
+    }
+
+    pub fn context(&self) -> &str {
+        &self.context
+    }
+
+    fn path(&self) -> PathBuf {
+        self.dir.join(&self.context)
+    }
+
+    /// Read all the cookies in the keyring. A missing keyring has no cookies.
+    pub fn load(&self) -> Result<Vec<Cookie>, AuthError> {
+        check_keyring_dir(&self.dir)?;
+        let mut data = String::new();
+        match fs::File::open(self.path()) {
+            Ok(mut f) => f.read_to_string(&mut data)?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e.into()),
+        };
+
+        /* lines we cannot parse are ignored, as libdbus does */
+        Ok(data.lines()
+            .filter_map(|l| {
+                let mut f = l.split(' ');
+                let id = f.next()?.parse().ok()?;
+                let created = f.next()?.parse().ok()?;
+                let secret = f.next()?;
+                if f.next().is_some() || hex_decode(secret).is_none() {
+                    return None;
+                }
+                Some(Cookie { id, created, secret: secret.to_owned() })
+            })
+            .collect())
+    }
+
+    /// Find the cookie with the given id
+    pub fn find(&self, id: u32) -> Result<Option<Cookie>, AuthError> {
+        Ok(self.load()?.into_iter().find(|c| c.id == id))
+    }
+
+    /**
+     * Pick the cookie a server should challenge with.
+     *
+     * Expired cookies are removed, and a new cookie is created if none are recent enough. The
+     * keyring directory is created if needed.
+     */
+    pub fn current(&self) -> Result<Cookie, AuthError> {
+        if let Err(e) = fs::DirBuilder::new().mode(0o700).create(&self.dir) {
+            if e.kind() != io::ErrorKind::AlreadyExists {
+                return Err(e.into());
+            }
+        }
+
+        let _lock = KeyringLock::acquire(&self.dir.join(format!("{}.lock", self.context)))?;
+        let now = now();
+        let mut cookies = self.load()?;
+        let before = cookies.len();
+        cookies.retain(|c| {
+            c.created <= now + COOKIE_MAX_TIME_TRAVEL &&
+                now.saturating_sub(c.created) <= COOKIE_EXPIRE_TIMEOUT
+        });
+        let mut changed = cookies.len() != before;
+
+        let recent = cookies.iter()
+            .filter(|c| now.saturating_sub(c.created) <= COOKIE_NEW_KEY_TIMEOUT)
+            .max_by_key(|c| c.created)
+            .cloned();
+        let cookie = match recent {
+            Some(c) => c,
+            None => {
+                let mut id = 0;
+                while id == 0 || cookies.iter().any(|c| c.id == id) {
+                    let b = random_bytes(4)?;
+                    id = u32::from_le_bytes([b[0], b[1], b[2], b[3]]) & 0x7fff_ffff;
+                }
+                let c = Cookie { id, created: now, secret: hex_encode(&random_bytes(24)?) };
+                cookies.push(c.clone());
+                changed = true;
+                c
+            }
+        };
+
+        if changed {
+            let tmp = self.dir.join(format!("{}.{}.tmp", self.context, hex_encode(&random_bytes(8)?)));
+            {
+                let mut f = fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(&tmp)?;
+                for c in cookies.iter() {
+                    writeln!(f, "{} {} {}", c.id, c.created, c.secret)?;
+                }
+            }
+            fs::rename(&tmp, self.path())?;
+        }
+
+        Ok(cookie)
+    }
+}
+
+/*
+ * Servers serialize updates to a keyring with a lock file. A lock that has been held for too long
+ * is assumed to belong to a process that died, and is broken.
+ */
+struct KeyringLock {
+    path: PathBuf,
+}
+
+impl KeyringLock {
+    fn create(path: &Path) -> io::Result<KeyringLock> {
+        fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)?;
+        Ok(KeyringLock { path: path.to_owned() })
+    }
+
+    fn acquire(path: &Path) -> Result<KeyringLock, AuthError> {
+        for _ in 0..32 {
+            match KeyringLock::create(path) {
+                Ok(l) => return Ok(l),
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    thread::sleep(Duration::from_millis(250));
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        /* whoever holds the lock is taking far too long, assume they died */
+        if let Err(e) = fs::remove_file(path) {
+            if e.kind() != io::ErrorKind::NotFound {
+                return Err(e.into());
+            }
+        }
+        Ok(KeyringLock::create(path)?)
+    }
+}
+
+impl Drop for KeyringLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/*
+ * Client side: answer `<context> <cookie id> <server challenge>`
+ */
+fn cookie_sha1_respond(keyring_dir: &Path, challenge: &[u8]) -> Result<Vec<u8>, AuthError> {
+    let challenge = str::from_utf8(challenge)
+        .map_err(|_| AuthError::Protocol("cookie challenge is not ASCII".to_owned()))?;
+    let mut f = challenge.split(' ');
+    let (context, id, server_challenge) = match (f.next(), f.next(), f.next(), f.next()) {
+        (Some(c), Some(i), Some(s), None) => (c, i, s),
+        _ => return Err(AuthError::Protocol(format!("malformed cookie challenge '{}'", challenge))),
+    };
+    let id = id.parse()
+        .map_err(|_| AuthError::Protocol(format!("malformed cookie id '{}'", id)))?;
+
+    let keyring = Keyring::new(keyring_dir.to_owned(), context)?;
+    let cookie = keyring.find(id)?
+        .ok_or_else(|| AuthError::Keyring(format!("no cookie {} in keyring '{}'", id, context)))?;
+
+    let client_challenge = hex_encode(&random_bytes(16)?);
+    let hash = cookie_hash(server_challenge, &client_challenge, &cookie.secret);
+    Ok(format!("{} {}", client_challenge, hash).into_bytes())
+}
+
+/**
+ * Server side of a single `DBUS_COOKIE_SHA1` exchange
+ */
+pub struct CookieSha1Challenge {
+    cookie: Cookie,
+    server_challenge: String,
+}
+
+impl CookieSha1Challenge {
+    /**
+     * Pick a cookie from `keyring` and a fresh challenge. Returns the challenge and the data to send
+     * to the client.
+     */
+    pub fn new(keyring: &Keyring) -> Result<(CookieSha1Challenge, Vec<u8>), AuthError> {
+        let cookie = keyring.current()?;
+        let server_challenge = hex_encode(&random_bytes(16)?);
+        let data = format!("{} {} {}", keyring.context(), cookie.id, server_challenge).into_bytes();
+        Ok((CookieSha1Challenge { cookie, server_challenge }, data))
+    }
+
+    /// Check the client's response to the challenge
+    pub fn verify(&self, response: &[u8]) -> bool {
+        let response = match str::from_utf8(response) {
+            Ok(r) => r,
+            Err(_) => return false,
+        };
+        let mut f = response.split(' ');
+        match (f.next(), f.next(), f.next()) {
+            (Some(client_challenge), Some(hash), None) => {
+                !client_challenge.is_empty() &&
+                    cookie_hash(&self.server_challenge, client_challenge, &self.cookie.secret) == hash
+            }
+            _ => false,
+        }
+    }
+}
+
+/*
+ * Server side
+ */
+
+/// Maximum number of rejected attempts before the server gives up on a client
+const MAX_REJECTIONS: usize = 8;
+
+/// How an accepted client was authenticated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Peer {
+    /// The client proved it is running as this uid
+    Uid(u32),
+    /// The client used `ANONYMOUS`
+    Anonymous,
+}
+
+/// A stream the server side has finished authenticating
+#[derive(Debug)]
+pub struct Accepted<S> {
+    pub stream: S,
+    pub peer: Peer,
+    /// Whether the client asked for, and we agreed to, unix fd passing
+    pub unix_fd: bool,
+}
+
+/// Generate a new server guid: 32 hex digits, the last 8 of which are the current time
+pub fn generate_guid() -> io::Result<String> {
+    let mut b = random_bytes(12)?;
+    b.extend(&(now() as u32).to_be_bytes());
+    Ok(hex_encode(&b))
+}
+
+/**
+ * The credentials of the process on the other end of a unix socket, from `SO_PEERCRED`
+ */
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn peer_uid(s: &::std::os::unix::net::UnixStream) -> io::Result<u32> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut cred = libc::ucred { pid: 0, uid: 0, gid: 0 };
+    let mut len = ::std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let r = unsafe {
+        libc::getsockopt(s.as_raw_fd(),
+                         libc::SOL_SOCKET,
+                         libc::SO_PEERCRED,
+                         &mut cred as *mut libc::ucred as *mut libc::c_void,
+                         &mut len)
+    };
+    if r != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(cred.uid)
+}
+
+/*
+ * Where the server is in the conversation
+ */
+enum WaitingFor {
+    Auth,
+    /// Sent a challenge for the mechanism, waiting for `DATA`
+    Data(ServerMechanism),
+    Begin(Peer),
+}
+
+enum ServerMechanism {
+    /// `EXTERNAL` without an initial response: the identity comes in `DATA`
+    External,
+    CookieSha1(u32, CookieSha1Challenge),
+}
+
+/*
+ * The outcome of one step of a mechanism
+ */
+enum Step {
+    Ok(Peer),
+    Challenge(ServerMechanism, Vec<u8>),
+    Reject,
+}
+
+/**
+ * The server half of the auth protocol
+ *
+ * By default only `EXTERNAL` is offered, when the transport reports the client's uid. `ANONYMOUS`
+ * and `DBUS_COOKIE_SHA1` must be enabled explicitly.
+ */
+pub struct Server {
+    guid: String,
+    anonymous: bool,
+    keyring: Option<Keyring>,
+    unix_fd: bool,
+}
+
+impl Server {
+    /// `guid` is sent to clients with `OK`. See `generate_guid()`.
+    pub fn new(guid: String) -> Server {
+        Server { guid, anonymous: false, keyring: None, unix_fd: false }
+    }
+
+    /// Accept clients using `ANONYMOUS`, who prove no identity at all
+    pub fn allow_anonymous(mut self, allow: bool) -> Server {
+        self.anonymous = allow;
+        self
+    }
+
+    /// Offer `DBUS_COOKIE_SHA1`, challenging with cookies from `keyring`
+    pub fn cookie_keyring(mut self, keyring: Keyring) -> Server {
+        self.keyring = Some(keyring);
+        self
+    }
+
+    /// Agree to unix fd passing if the client asks for it
+    pub fn unix_fd(mut self, allow: bool) -> Server {
+        self.unix_fd = allow;
+        self
+    }
+
+    pub fn guid(&self) -> &str {
+        &self.guid
+    }
+
+    /*
+     * The mechanisms a client may use. `EXTERNAL` can only succeed if the transport knows the
+     * client's uid.
+     */
+    fn mechanisms(&self, peer_uid: Option<u32>) -> String {
+        let mut m = vec![];
+        if peer_uid.is_some() {
+            m.push("EXTERNAL");
+        }
+        if self.keyring.is_some() {
+            m.push("DBUS_COOKIE_SHA1");
+        }
+        if self.anonymous {
+            m.push("ANONYMOUS");
+        }
+        m.join(" ")
+    }
+
+    /**
+     * Authenticate a unix socket client, checking `EXTERNAL` against the socket credentials
+     */
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn accept_unix(&self, s: ::std::os::unix::net::UnixStream)
+        -> Result<Accepted<::std::os::unix::net::UnixStream>, AuthError>
+    {
+        let uid = peer_uid(&s)?;
+        self.accept(s, Some(uid))
+    }
+
+    /**
+     * Run the server side of the auth conversation on a freshly accepted stream.
+     *
+     * `peer_uid` is the uid the transport says the client has, if it can tell. Without one,
+     * `EXTERNAL` is not offered, and always fails.
+     *
+     * On success the stream is positioned just after the client's `BEGIN`.
+     */
+    pub fn accept<S: Read + Write>(&self, mut s: S, peer_uid: Option<u32>)
+        -> Result<Accepted<S>, AuthError>
+    {
+        let mut nul = [0xffu8];
+        s.read_exact(&mut nul)?;
+        if nul[0] != 0 {
+            return Err(AuthError::Protocol("client did not send the initial nul byte".to_owned()));
+        }
+
+        let mut state = WaitingFor::Auth;
+        let mut unix_fd = false;
+        let mut rejections = 0;
+        loop {
+            let line = read_line(&mut s)?;
+            let (cmd, rest) = split_command(&line);
+
+            let reject = match (mem::replace(&mut state, WaitingFor::Auth), cmd) {
+                (WaitingFor::Begin(peer), "BEGIN") => {
+                    return Ok(Accepted { stream: s, peer, unix_fd });
+                }
+                (_, "BEGIN") => {
+                    return Err(AuthError::Protocol("client sent BEGIN before authenticating".to_owned()));
+                }
+                (WaitingFor::Auth, "AUTH") => {
+                    let (mech, resp) = split_command(rest);
+                    let resp = match hex_decode(resp) {
+                        Some(r) => r,
+                        None => {
+                            write_line(&mut s, "ERROR \"initial response is not hex encoded\"")?;
+                            continue;
+                        }
+                    };
+
+                    match self.start(mech, &resp, peer_uid)? {
+                        Step::Ok(peer) => {
+                            write_line(&mut s, &format!("OK {}", self.guid))?;
+                            state = WaitingFor::Begin(peer);
+                            false
+                        }
+                        Step::Challenge(m, data) => {
+                            write_line(&mut s, format!("DATA {}", hex_encode(&data)).trim_end())?;
+                            state = WaitingFor::Data(m);
+                            false
+                        }
+                        Step::Reject => true,
+                    }
+                }
+                (WaitingFor::Data(m), "DATA") => {
+                    match hex_decode(rest).map(|r| self.data(m, &r, peer_uid)) {
+                        Some(Step::Ok(peer)) => {
+                            write_line(&mut s, &format!("OK {}", self.guid))?;
+                            state = WaitingFor::Begin(peer);
+                            false
+                        }
+                        Some(Step::Challenge(m, data)) => {
+                            write_line(&mut s, format!("DATA {}", hex_encode(&data)).trim_end())?;
+                            state = WaitingFor::Data(m);
+                            false
+                        }
+                        Some(Step::Reject) | None => true,
+                    }
+                }
+                (WaitingFor::Begin(peer), "NEGOTIATE_UNIX_FD") => {
+                    if self.unix_fd {
+                        write_line(&mut s, "AGREE_UNIX_FD")?;
+                        unix_fd = true;
+                    } else {
+                        write_line(&mut s, "ERROR \"unix fd passing is not supported\"")?;
+                    }
+                    state = WaitingFor::Begin(peer);
+                    false
+                }
+                /* AUTH part way through another mechanism, CANCEL & ERROR all abandon the attempt */
+                (WaitingFor::Auth, "CANCEL") |
+                (WaitingFor::Auth, "ERROR") |
+                (WaitingFor::Data(_), "AUTH") |
+                (WaitingFor::Data(_), "CANCEL") |
+                (WaitingFor::Data(_), "ERROR") |
+                (WaitingFor::Begin(_), "CANCEL") |
+                (WaitingFor::Begin(_), "ERROR") => true,
+                (st, _) => {
+                    write_line(&mut s, "ERROR \"unexpected command\"")?;
+                    state = st;
+                    false
+                }
+            };
+
+            if reject {
+                rejections += 1;
+                if rejections > MAX_REJECTIONS {
+                    let e = AuthError::Protocol("client exceeded the maximum number of attempts".to_owned());
+                    return Err(e);
+                }
+                write_line(&mut s, format!("REJECTED {}", self.mechanisms(peer_uid)).trim_end())?;
+                state = WaitingFor::Auth;
+            }
+        }
+    }
+
+    /*
+     * Begin a mechanism in response to `AUTH <mech> <initial response>`
+     */
+    fn start(&self, mech: &str, resp: &[u8], peer_uid: Option<u32>) -> Result<Step, AuthError> {
+        match mech {
+            "EXTERNAL" => {
+                if resp.is_empty() {
+                    return Ok(Step::Challenge(ServerMechanism::External, vec![]));
+                }
+                Ok(external_step(resp, peer_uid))
+            }
+            "ANONYMOUS" if self.anonymous => Ok(Step::Ok(Peer::Anonymous)),
+            "DBUS_COOKIE_SHA1" => {
+                let keyring = match self.keyring {
+                    Some(ref k) => k,
+                    None => return Ok(Step::Reject),
+                };
+                let uid = match parse_uid(resp) {
+                    Some(u) if keyring_owner(keyring) == Some(u) => u,
+                    _ => return Ok(Step::Reject),
+                };
+                let (c, data) = CookieSha1Challenge::new(keyring)?;
+                Ok(Step::Challenge(ServerMechanism::CookieSha1(uid, c), data))
+            }
+            _ => Ok(Step::Reject),
+        }
+    }
+
+    /*
+     * Continue a mechanism in response to `DATA`
+     */
+    fn data(&self, m: ServerMechanism, resp: &[u8], peer_uid: Option<u32>) -> Step {
+        match m {
+            ServerMechanism::External => external_step(resp, peer_uid),
+            ServerMechanism::CookieSha1(uid, c) => {
+                if c.verify(resp) {
+                    Step::Ok(Peer::Uid(uid))
+                } else {
+                    Step::Reject
+                }
+            }
+        }
+    }
+}
+
+fn parse_uid(b: &[u8]) -> Option<u32> {
+    str::from_utf8(b).ok()?.parse().ok()
+}
+
+fn keyring_owner(k: &Keyring) -> Option<u32> {
+    fs::metadata(&k.dir).ok().map(|m| m.uid())
+}
+
+/*
+ * `EXTERNAL` succeeds if the claimed identity is the one the transport reports. An empty response
+ * claims no particular identity, and so authenticates as whoever the transport reports.
+ */
+fn external_step(resp: &[u8], peer_uid: Option<u32>) -> Step {
+    match peer_uid {
+        Some(actual) if resp.is_empty() => Step::Ok(Peer::Uid(actual)),
+        Some(actual) if parse_uid(resp) == Some(actual) => Step::Ok(Peer::Uid(actual)),
+        _ => Step::Reject,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{authenticate, authenticate_external, cookie_hash, current_uid, hex_decode, hex_encode,
+                now, AuthError, Cookie, CookieSha1Challenge, Keyring, Mechanism, Peer, Server};
+    use std::fs;
+    use std::io::{self, Cursor, Read, Write};
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+    use std::os::unix::net::UnixStream;
+    use std::path::{Path, PathBuf};
+    use std::thread;
+
+    /* A peer which replies with a fixed script and records what we send it */
+    #[derive(Debug)]
+    struct Script {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Script {
+        fn new(input: &str) -> Script {
+            Script { input: Cursor::new(input.as_bytes().to_vec()), output: vec![] }
+        }
+    }
+
+    impl Read for Script {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Script {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /* a fresh, private, directory for a keyring */
+    fn keyring_dir(name: &str) -> PathBuf {
+        let d = ::std::env::temp_dir().join(format!("rdbus-test-{}-{}", ::std::process::id(), name));
+        let _ = fs::remove_dir_all(&d);
+        fs::DirBuilder::new().mode(0o700).create(&d).unwrap();
+        d
+    }
+
+    fn write_keyring(dir: &Path, context: &str, cookies: &[Cookie]) {
+        let mut f = fs::File::create(dir.join(context)).unwrap();
+        for c in cookies {
+            writeln!(f, "{} {} {}", c.id, c.created, c.secret).unwrap();
+        }
+    }
+
+    #[test]
+    fn hex() {
+        assert_eq!(hex_encode(b"1000"), "31303030");
+        assert_eq!(hex_decode("31303030").unwrap(), b"1000");
+        assert_eq!(hex_decode("3A3b").unwrap(), b":;");
+        assert!(hex_decode("313").is_none());
+        assert!(hex_decode("3g").is_none());
+    }
+
+    #[test]
+    fn external_ok() {
+        let mut s = Script::new("OK 1234deadbeef\r\ntrailing");
+        assert_eq!(authenticate_external(&mut s, 1000).unwrap(), "1234deadbeef");
+        assert_eq!(s.output, b"\0AUTH EXTERNAL 31303030\r\nBEGIN\r\n");
+
+        /* message data after the auth conversation must not be consumed */
+        let mut rest = String::new();
+        s.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "trailing");
+    }
+
+    #[test]
+    fn external_effective_uid() {
+        /* the server compares our claim with SO_PEERCRED, which holds the effective uid */
+        let euid = unsafe { ::libc::geteuid() };
+        let mut s = Script::new("OK abc\r\n");
+        authenticate(&mut s, &[Mechanism::external()], false).unwrap();
+        let expected = format!("\0AUTH EXTERNAL {}\r\nBEGIN\r\n", hex_encode(euid.to_string().as_bytes()));
+        assert_eq!(String::from_utf8(s.output).unwrap(), expected);
+    }
+
+    #[test]
+    fn external_data() {
+        let mut s = Script::new("DATA\r\nOK abc\r\n");
+        assert_eq!(authenticate_external(&mut s, 0).unwrap(), "abc");
+        assert_eq!(s.output, b"\0AUTH EXTERNAL 30\r\nDATA\r\nBEGIN\r\n");
+    }
+
+    #[test]
+    fn external_rejected() {
+        let mut s = Script::new("REJECTED DBUS_COOKIE_SHA1 ANONYMOUS\r\n");
+        match authenticate_external(&mut s, 0) {
+            Err(AuthError::Rejected(m)) => assert_eq!(m, ["DBUS_COOKIE_SHA1", "ANONYMOUS"]),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn external_errors() {
+        /* an error cancels the mechanism, and is reported once the server has rejected us */
+        let mut s = Script::new("ERROR \"no\"\r\nREJECTED EXTERNAL\r\n");
+        match authenticate_external(&mut s, 0) {
+            Err(AuthError::Error(e)) => assert_eq!(e, "\"no\""),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        assert_eq!(s.output, &b"\0AUTH EXTERNAL 30\r\nCANCEL\r\n"[..]);
+
+        match authenticate_external(&mut Script::new("ERROR \"no\"\r\nERROR \"still no\"\r\n"), 0) {
+            Err(AuthError::Error(e)) => assert_eq!(e, "\"still no\""),
+            r => panic!("unexpected result: {:?}", r),
+        }
+
+        match authenticate_external(&mut Script::new("WHAT\r\n"), 0) {
+            Err(AuthError::Protocol(_)) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+
+        match authenticate_external(&mut Script::new("OK abc"), 0) {
+            Err(AuthError::Io(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn keyring_rotation() {
+        let dir = keyring_dir("rotation").join("keyrings");
+        let k = Keyring::new(dir.clone(), "org_freedesktop_general").unwrap();
+        assert!(k.load().is_err());
+
+        let c = k.current().unwrap();
+        assert_eq!(fs::metadata(&dir).unwrap().mode() & 0o777, 0o700);
+        assert_eq!(k.current().unwrap(), c);
+        assert_eq!(k.find(c.id).unwrap(), Some(c.clone()));
+        assert!(!dir.join("org_freedesktop_general.lock").exists());
+
+        /* too old to challenge with, but not yet expired */
+        let aging = Cookie { id: 5, created: now() - 6 * 60, secret: "aa".to_owned() };
+        let expired = Cookie { id: 6, created: now() - 60 * 60, secret: "bb".to_owned() };
+        let future = Cookie { id: 7, created: now() + 60 * 60, secret: "cc".to_owned() };
+        write_keyring(&dir, "org_freedesktop_general", &[aging.clone(), expired, future]);
+
+        let c = k.current().unwrap();
+        assert!(c.id != 5);
+        assert_eq!(k.load().unwrap(), [aging, c]);
+
+        fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn keyring_checks() {
+        Keyring::new(PathBuf::from("/"), "../etc").err().unwrap();
+        Keyring::new(PathBuf::from("/"), "a/b").err().unwrap();
+        Keyring::new(PathBuf::from("/"), "").err().unwrap();
+
+        let dir = keyring_dir("checks");
+        fs::set_permissions(&dir, ::std::os::unix::fs::PermissionsExt::from_mode(0o755)).unwrap();
+        match Keyring::new(dir.clone(), "ctx").unwrap().load() {
+            Err(AuthError::Keyring(_)) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn cookie_sha1_challenge() {
+        let dir = keyring_dir("challenge");
+        let k = Keyring::new(dir.clone(), "ctx").unwrap();
+        let (challenge, data) = CookieSha1Challenge::new(&k).unwrap();
+
+        let resp = super::cookie_sha1_respond(&dir, &data).unwrap();
+        assert!(challenge.verify(&resp));
+        assert!(!challenge.verify(b"abcd 0123"));
+        assert!(!challenge.verify(b""));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn cookie_sha1_client() {
+        let dir = keyring_dir("client");
+        let cookie = Cookie { id: 42, created: now(), secret: "0123456789abcdef".to_owned() };
+        write_keyring(&dir, "ctx", ::std::slice::from_ref(&cookie));
+
+        let mechs = [Mechanism::External(1000),
+                     Mechanism::CookieSha1 { uid: 1000, keyring_dir: dir.clone() }];
+        let mut s = Script::new(&format!("REJECTED DBUS_COOKIE_SHA1\r\nDATA {}\r\nOK abc\r\n",
+                                         hex_encode(b"ctx 42 5e4ec")));
+        assert_eq!(authenticate(&mut s, &mechs, false).unwrap().guid, "abc");
+
+        let out = String::from_utf8(s.output).unwrap();
+        let lines: Vec<&str> = out.split("\r\n").collect();
+        assert_eq!(lines[0], "\0AUTH EXTERNAL 31303030");
+        assert_eq!(lines[1], "AUTH DBUS_COOKIE_SHA1 31303030");
+        assert_eq!(lines[3], "BEGIN");
+
+        let resp = String::from_utf8(hex_decode(lines[2].trim_start_matches("DATA ")).unwrap()).unwrap();
+        let mut f = resp.split(' ');
+        let client_challenge = f.next().unwrap();
+        assert_eq!(f.next().unwrap(), cookie_hash("5e4ec", client_challenge, &cookie.secret));
+
+        /* after an error, the next mechanism is tried */
+        let mut s = Script::new(&format!("ERROR \"no\"\r\nREJECTED DBUS_COOKIE_SHA1\r\nDATA {}\r\nOK abc\r\n",
+                                         hex_encode(b"ctx 42 5e4ec")));
+        assert_eq!(authenticate(&mut s, &mechs, false).unwrap().guid, "abc");
+        let out = String::from_utf8(s.output).unwrap();
+        let lines: Vec<&str> = out.split("\r\n").collect();
+        assert_eq!(lines[..3], ["\0AUTH EXTERNAL 31303030", "CANCEL", "AUTH DBUS_COOKIE_SHA1 31303030"]);
+        assert_eq!(lines[4], "BEGIN");
+
+        /* an unknown cookie is cancelled, and reported once the server has rejected us */
+        let mut s = Script::new(&format!("DATA {}\r\nREJECTED EXTERNAL\r\n", hex_encode(b"ctx 41 5e4ec")));
+        match authenticate(&mut s, &mechs[1..], false) {
+            Err(AuthError::Keyring(_)) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+        assert!(String::from_utf8(s.output).unwrap().ends_with("\r\nCANCEL\r\n"));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn server() -> Server {
+        Server::new("0123456789abcdef0123456789abcdef".to_owned())
+    }
+
+    #[test]
+    fn server_external() {
+        let (c, s) = UnixStream::pair().unwrap();
+        let client = thread::spawn(move || {
+            let mut c = c;
+            authenticate_external(&mut c, current_uid()).unwrap()
+        });
+
+        let a = server().accept_unix(s).unwrap();
+        assert_eq!(a.peer, Peer::Uid(current_uid()));
+        assert!(!a.unix_fd);
+        assert_eq!(client.join().unwrap(), "0123456789abcdef0123456789abcdef");
+    }
+
+    #[test]
+    fn server_external_scripted() {
+        let mut s = Script::new("\0AUTH EXTERNAL 31323334\r\nAUTH EXTERNAL 30\r\nBEGIN\r\n");
+        s = {
+            let a = server().accept(s, Some(0)).unwrap();
+            assert_eq!(a.peer, Peer::Uid(0));
+            a.stream
+        };
+        assert_eq!(s.output, &b"REJECTED EXTERNAL\r\nOK 0123456789abcdef0123456789abcdef\r\n"[..]);
+
+        /* identity supplied in DATA rather than as an initial response */
+        let a = server().accept(Script::new("\0AUTH EXTERNAL\r\nDATA 30\r\nBEGIN\r\n"), Some(0)).unwrap();
+        assert_eq!(a.stream.output, &b"DATA\r\nOK 0123456789abcdef0123456789abcdef\r\n"[..]);
+
+        /* an empty response authenticates as the uid the transport reports */
+        let a = server().accept(Script::new("\0AUTH EXTERNAL\r\nDATA\r\nBEGIN\r\n"), Some(1000)).unwrap();
+        assert_eq!(a.peer, Peer::Uid(1000));
+        assert_eq!(a.stream.output, &b"DATA\r\nOK 0123456789abcdef0123456789abcdef\r\n"[..]);
+
+        /* no credentials from the transport, so EXTERNAL is not even offered */
+        let mut s = Script::new("\0AUTH EXTERNAL 30\r\n");
+        match server().accept(&mut s, None) {
+            Err(AuthError::Io(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+        assert_eq!(s.output, b"REJECTED\r\n");
+    }
+
+    #[test]
+    fn server_anonymous() {
+        let script = "\0AUTH\r\nAUTH ANONYMOUS 7472616365\r\nBEGIN\r\n";
+        let a = server().allow_anonymous(true).accept(Script::new(script), None).unwrap();
+        assert_eq!(a.peer, Peer::Anonymous);
+        assert_eq!(a.stream.output, &b"REJECTED ANONYMOUS\r\nOK 0123456789abcdef0123456789abcdef\r\n"[..]);
+
+        let a = server().allow_anonymous(true).accept(Script::new(script), Some(0)).unwrap();
+        assert!(a.stream.output.starts_with(b"REJECTED EXTERNAL ANONYMOUS\r\n"));
+
+        let script = "\0AUTH ANONYMOUS\r\nBEGIN\r\n";
+        match server().accept(Script::new(script), None) {
+            Err(AuthError::Protocol(_)) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn server_unix_fd() {
+        let script = "\0AUTH EXTERNAL 30\r\nNEGOTIATE_UNIX_FD\r\nBEGIN\r\n";
+        let a = server().unix_fd(true).accept(Script::new(script), Some(0)).unwrap();
+        assert!(a.unix_fd);
+        assert!(a.stream.output.ends_with(b"\r\nAGREE_UNIX_FD\r\n"));
+
+        let a = server().accept(Script::new(script), Some(0)).unwrap();
+        assert!(!a.unix_fd);
+        assert!(a.stream.output.ends_with(b"\r\nERROR \"unix fd passing is not supported\"\r\n"));
+
+        /* only valid once authenticated */
+        let script = "\0NEGOTIATE_UNIX_FD\r\nAUTH EXTERNAL 30\r\nBEGIN\r\n";
+        let a = server().unix_fd(true).accept(Script::new(script), Some(0)).unwrap();
+        assert!(!a.unix_fd);
+    }
+
+    #[test]
+    fn client_unix_fd() {
+        for &allow in &[true, false] {
+            let (c, s) = UnixStream::pair().unwrap();
+            let client = thread::spawn(move || {
+                let mut c = c;
+                authenticate(&mut c, &[Mechanism::external()], true).unwrap()
+            });
+
+            let a = server().unix_fd(allow).accept_unix(s).unwrap();
+            assert_eq!(a.unix_fd, allow);
+            assert_eq!(client.join().unwrap().unix_fd, allow);
+        }
+
+        let mut s = Script::new("OK abc\r\nAGREE_UNIX_FD\r\n");
+        assert!(authenticate(&mut s, &[Mechanism::External(0)], true).unwrap().unix_fd);
+        assert_eq!(s.output, &b"\0AUTH EXTERNAL 30\r\nNEGOTIATE_UNIX_FD\r\nBEGIN\r\n"[..]);
+    }
+
+    #[test]
+    fn server_cancel_and_errors() {
+        let script = "\0AUTH EXTERNAL\r\nCANCEL\r\nDATA 30\r\nFOO\r\nAUTH EXTERNAL zz\r\n\
+                      AUTH EXTERNAL 30\r\nERROR\r\nAUTH EXTERNAL 30\r\nBEGIN\r\n";
+        let a = server().accept(Script::new(script), Some(0)).unwrap();
+        let out = String::from_utf8(a.stream.output).unwrap();
+        let lines: Vec<&str> = out.split("\r\n").collect();
+        assert_eq!(lines, ["DATA",
+                           "REJECTED EXTERNAL",
+                           "ERROR \"unexpected command\"",
+                           "ERROR \"unexpected command\"",
+                           "ERROR \"initial response is not hex encoded\"",
+                           "OK 0123456789abcdef0123456789abcdef",
+                           "REJECTED EXTERNAL",
+                           "OK 0123456789abcdef0123456789abcdef",
+                           ""]);
+
+        match server().accept(Script::new("AUTH EXTERNAL 30\r\n"), Some(0)) {
+            Err(AuthError::Protocol(_)) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+
+        let script = "AUTH FOO\r\n".repeat(20);
+        match server().accept(Script::new(&format!("\0{}", script)), Some(0)) {
+            Err(AuthError::Protocol(_)) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn server_cookie_sha1() {
+        let dir = keyring_dir("server");
+        let keyring = Keyring::new(dir.clone(), "org_freedesktop_general").unwrap();
+
+        let (c, s) = UnixStream::pair().unwrap();
+        let client_dir = dir.clone();
+        let client = thread::spawn(move || {
+            let mut c = c;
+            let mechs = [Mechanism::External(current_uid()),
+                         Mechanism::CookieSha1 { uid: current_uid(), keyring_dir: client_dir }];
+            authenticate(&mut c, &mechs, false).unwrap().guid
+        });
+
+        /* no socket credentials, so EXTERNAL will be rejected */
+        let a = server().cookie_keyring(keyring).accept(s, None).unwrap();
+        assert_eq!(a.peer, Peer::Uid(current_uid()));
+        assert_eq!(client.join().unwrap(), "0123456789abcdef0123456789abcdef");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
 
+    #[test]
+    fn guid() {
+        let g = super::generate_guid().unwrap();
+        assert_eq!(g.len(), 32);
+        assert!(hex_decode(&g).is_some());
+    }
 }
-*/
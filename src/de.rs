@@ -0,0 +1,411 @@
+//! Unmarshal types that implement `serde::Deserialize`, following the signature of the data
+//!
+//! - structs (`(...)`) are sequences, as are arrays, except that dicts (`a{..}`) are maps
+//! - variants (`v`) hold whatever type their signature gives
+//! - strings, object paths and signatures are strings, and strings are borrowed from the data
+//! - `ay` is also bytes, which are borrowed from the data
+//! - options are always `Some`, and strings can be unit enum variants
+//!
+//! serde structs may be decoded from either a D-Bus struct, whose fields are in order, or from a
+//! dict keyed by field name, such as `a{sv}`.
+
+use std::error;
+use std::fmt;
+
+use serde::de::{self, Deserialize, DeserializeSeed, IntoDeserializer, Visitor};
+
+use marshal::{self, DataIter, DecodeError, DecodeOwned};
+use names::ObjectPathBuf;
+use type_sig::TypeBuf;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The data does not hold a valid value of its signature
+    Decode(DecodeError),
+    /// The data holds a type that has no serde equivalent
+    Unsupported(&'static str),
+    /// An error reported by a `Deserialize` impl, such as finding a different type than it wanted
+    Custom(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Decode(ref e) => e.fmt(fmt),
+            Error::Unsupported(e) => write!(fmt, "cannot deserialize value: {}", e),
+            Error::Custom(ref e) => write!(fmt, "{}", e),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Decode(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Error {
+        Error::Custom(msg.to_string())
+    }
+}
+
+impl From<DecodeError> for Error {
+    fn from(e: DecodeError) -> Error {
+        Error::Decode(e)
+    }
+}
+
+/**
+ * Deserialize the next value from `iter`, whatever its signature
+ */
+pub fn read<'de, T: Deserialize<'de>>(iter: &mut DataIter<'de>) -> Result<T, Error> {
+    let rest = iter.signature();
+    let l = marshal::single_type_len(rest)
+        .ok_or(DecodeError::Invalid { offset: iter.offset(), reason: "signature does not begin with a complete type" })?;
+    iter.sig_pos += l;
+    T::deserialize(&mut Deserializer { iter, sig: &rest[..l] })
+}
+
+/**
+ * A serde `Deserializer` for a single value of a `DataIter`. Use `read()` to create one.
+ */
+pub struct Deserializer<'a, 'de: 'a> {
+    iter: &'a mut DataIter<'de>,
+    /// The complete type of the value
+    sig: &'de [u8],
+}
+
+impl<'a, 'de> Deserializer<'a, 'de> {
+    fn str(&mut self) -> Result<&'de str, Error> {
+        let len = u32::decode_from(self.iter)? as usize;
+        Ok(marshal::decode_str(self.iter, len)?)
+    }
+
+    /*
+     * The signature a variant holds, borrowed from the data
+     */
+    fn variant_signature(&mut self) -> Result<&'de [u8], Error> {
+        let offset = self.iter.offset();
+        let len = u8::decode_from(self.iter)? as usize;
+        let s = marshal::decode_str(self.iter, len)?;
+        TypeBuf::from_string(s.to_owned()).map_err(|e| marshal::decode_type_error(e, offset))?;
+        if marshal::single_type_len(s.as_bytes()) != Some(s.len()) {
+            return Err(DecodeError::Invalid { offset, reason: "variant signature is not a single complete type" }.into());
+        }
+        Ok(s.as_bytes())
+    }
+
+    /*
+     * Read the length of an array and the padding before its elements, returning where they end
+     */
+    fn array(&mut self, align: usize) -> Result<usize, Error> {
+        self.iter.align(4)?;
+        let offset = self.iter.offset();
+        let len = u32::decode_from(self.iter)? as usize;
+        if len > marshal::MAX_ARRAY_LEN {
+            return Err(DecodeError::ArrayTooLong { offset }.into());
+        }
+        self.iter.align(align)?;
+        let start = self.iter.offset();
+        if self.iter.data.len() - start < len {
+            return Err(DecodeError::Truncated { offset: self.iter.data.len() }.into());
+        }
+        Ok(start + len)
+    }
+
+    /*
+     * Deserialize the value with `f`, counting it towards the limit on nesting if it is a container.
+     * A dict counts twice, as it is an array of dict entries.
+     */
+    fn nested<T, F>(&mut self, f: F) -> Result<T, Error>
+        where F: FnOnce(&mut Self) -> Result<T, Error>
+    {
+        let depth = match self.sig {
+            [b'a', b'{', ..] => 2,
+            [b'a', ..] | [b'(', ..] | b"v" => 1,
+            _ => 0,
+        };
+        for entered in 0..depth {
+            if let Err(e) = self.iter.enter() {
+                for _ in 0..entered {
+                    self.iter.leave();
+                }
+                return Err(e.into());
+            }
+        }
+        let r = f(self);
+        for _ in 0..depth {
+            self.iter.leave();
+        }
+        r
+    }
+
+    fn any<V: Visitor<'de>>(&mut self, visitor: V) -> Result<V::Value, Error> {
+        let iter = &mut *self.iter;
+        match self.sig {
+            b"y" => visitor.visit_u8(u8::decode_from(iter)?),
+            b"b" => visitor.visit_bool(bool::decode_from(iter)?),
+            b"n" => visitor.visit_i16(i16::decode_from(iter)?),
+            b"q" => visitor.visit_u16(u16::decode_from(iter)?),
+            b"i" => visitor.visit_i32(i32::decode_from(iter)?),
+            b"u" => visitor.visit_u32(u32::decode_from(iter)?),
+            b"x" => visitor.visit_i64(i64::decode_from(iter)?),
+            b"t" => visitor.visit_u64(u64::decode_from(iter)?),
+            b"d" => visitor.visit_f64(f64::decode_from(iter)?),
+            b"s" => visitor.visit_borrowed_str(self.str()?),
+            b"o" => visitor.visit_string(ObjectPathBuf::decode_from(iter)?.as_str().to_owned()),
+            b"g" => visitor.visit_string(TypeBuf::decode_from(iter)?.as_ref().to_owned()),
+            b"h" => Err(Error::Unsupported("unix fds cannot be deserialized")),
+            b"v" => {
+                let sig = self.variant_signature()?;
+                de::Deserializer::deserialize_any(&mut Deserializer { iter: &mut *self.iter, sig }, visitor)
+            }
+            _ if self.sig.starts_with(b"a{") => {
+                let end = self.array(8)?;
+                let (key, value) = (&self.sig[2..3], &self.sig[3..self.sig.len() - 1]);
+                let v = visitor.visit_map(Dict { iter: &mut *self.iter, key, value, end })?;
+                if self.iter.offset() != end {
+                    let offset = self.iter.offset();
+                    return Err(DecodeError::Invalid { offset, reason: "array elements overrun its length" }.into());
+                }
+                Ok(v)
+            }
+            _ if self.sig[0] == b'a' || self.sig[0] == b'(' => self.seq(visitor),
+            _ => Err(DecodeError::Invalid { offset: iter.offset(), reason: "signature is not valid" }.into()),
+        }
+    }
+
+    fn seq<V: Visitor<'de>>(&mut self, visitor: V) -> Result<V::Value, Error> {
+        match self.sig[0] {
+            b'a' => {
+                let element = &self.sig[1..];
+                let end = self.array(marshal::alignment(element[0]))?;
+                let v = visitor.visit_seq(Array { iter: &mut *self.iter, element, end })?;
+                if self.iter.offset() != end {
+                    let offset = self.iter.offset();
+                    return Err(DecodeError::Invalid { offset, reason: "array elements overrun its length" }.into());
+                }
+                Ok(v)
+            }
+            _ => {
+                self.iter.align(8)?;
+                let mut fields = Struct { iter: &mut *self.iter, fields: &self.sig[1..self.sig.len() - 1] };
+                let v = visitor.visit_seq(&mut fields)?;
+                if !fields.fields.is_empty() {
+                    return Err(de::Error::custom("struct has fields that were not deserialized"));
+                }
+                Ok(v)
+            }
+        }
+    }
+}
+
+impl<'a, 'b, 'de> de::Deserializer<'de> for &'b mut Deserializer<'a, 'de> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.nested(|de| de.any(visitor))
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        if self.sig != b"ay" {
+            return self.deserialize_any(visitor);
+        }
+        self.nested(|de| {
+            let end = de.array(1)?;
+            let len = end - de.iter.offset();
+            visitor.visit_borrowed_bytes(de.iter.take(len)?)
+        })
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_bytes(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V)
+        -> Result<V::Value, Error>
+    {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(self, name: &'static str, variants: &'static [&'static str],
+                                         visitor: V)
+        -> Result<V::Value, Error>
+    {
+        match self.sig {
+            b"s" => visitor.visit_enum(self.str()?.into_deserializer()),
+            b"v" => self.nested(|de| {
+                let sig = de.variant_signature()?;
+                let mut de = Deserializer { iter: &mut *de.iter, sig };
+                de::Deserializer::deserialize_enum(&mut de, name, variants, visitor)
+            }),
+            _ => Err(Error::Unsupported("only strings can be deserialized as enums")),
+        }
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        unit unit_struct seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+/*
+ * The elements of an array, which end at the offset `end`
+ */
+struct Array<'a, 'de: 'a> {
+    iter: &'a mut DataIter<'de>,
+    element: &'de [u8],
+    end: usize,
+}
+
+impl<'a, 'de> de::SeqAccess<'de> for Array<'a, 'de> {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, Error> {
+        if self.iter.offset() >= self.end {
+            return Ok(None);
+        }
+        seed.deserialize(&mut Deserializer { iter: &mut *self.iter, sig: self.element }).map(Some)
+    }
+}
+
+/*
+ * The fields of a struct, whose types are in `fields`
+ */
+struct Struct<'a, 'de: 'a> {
+    iter: &'a mut DataIter<'de>,
+    fields: &'de [u8],
+}
+
+impl<'a, 'b, 'de> de::SeqAccess<'de> for &'b mut Struct<'a, 'de> {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, Error> {
+        if self.fields.is_empty() {
+            return Ok(None);
+        }
+        let l = marshal::single_type_len(self.fields)
+            .ok_or(DecodeError::Invalid { offset: self.iter.offset(), reason: "signature is not valid" })?;
+        let sig = &self.fields[..l];
+        self.fields = &self.fields[l..];
+        seed.deserialize(&mut Deserializer { iter: &mut *self.iter, sig }).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        let mut n = 0;
+        let mut rest = self.fields;
+        while let Some(l) = marshal::single_type_len(rest) {
+            rest = &rest[l..];
+            n += 1;
+        }
+        Some(n)
+    }
+}
+
+/*
+ * The entries of a dict, which end at the offset `end`
+ */
+struct Dict<'a, 'de: 'a> {
+    iter: &'a mut DataIter<'de>,
+    key: &'de [u8],
+    value: &'de [u8],
+    end: usize,
+}
+
+impl<'a, 'de> de::MapAccess<'de> for Dict<'a, 'de> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, Error> {
+        if self.iter.offset() >= self.end {
+            return Ok(None);
+        }
+        self.iter.align(8)?;
+        seed.deserialize(&mut Deserializer { iter: &mut *self.iter, sig: self.key }).map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        seed.deserialize(&mut Deserializer { iter: &mut *self.iter, sig: self.value })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::{read, Error};
+    use header::ENDIAN_LITTLE as LE;
+    use marshal::{Data, DataIter, DecodeError};
+    use value::Value;
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    struct Config<'a> {
+        name: &'a str,
+        level: Level,
+        ids: Vec<(u8, u64)>,
+        extra: Option<u32>,
+    }
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    enum Level {
+        Low,
+        High,
+    }
+
+    #[test]
+    fn structs() {
+        let mut m = Data::new();
+        m.append(("x", "High", vec![(1u8, 2u64)], 5u32)).unwrap();
+        let mut props = HashMap::new();
+        props.insert("name".to_owned(), Value::from("y"));
+        props.insert("level".to_owned(), Value::from("Low"));
+        props.insert("ids".to_owned(), Value::from(Vec::<(u8, u64)>::new()));
+        props.insert("unknown".to_owned(), Value::Bool(true));
+        m.append(&props).unwrap();
+        m.append(7u8).unwrap();
+
+        let mut i = m.iter();
+        assert_eq!(read::<Config>(&mut i).unwrap(), Config {
+            name: "x", level: Level::High, ids: vec![(1, 2)], extra: Some(5) });
+        assert_eq!(read::<Config>(&mut i).unwrap(), Config {
+            name: "y", level: Level::Low, ids: vec![], extra: None });
+        assert_eq!(read::<u8>(&mut i).unwrap(), 7);
+        assert!(i.is_empty());
+    }
+
+    #[test]
+    fn values() {
+        let mut m = Data::new();
+        m.append(&[1u8, 2, 3][..]).unwrap();
+        m.append(Value::from(vec!["a".to_owned()])).unwrap();
+        m.append((1i16, 2u16)).unwrap();
+
+        let mut i = m.iter();
+        let b: &[u8] = read(&mut i).unwrap();
+        assert_eq!(b, [1, 2, 3]);
+        assert_eq!(read::<Vec<String>>(&mut i).unwrap(), ["a"]);
+        assert_eq!(read::<(i16, u16)>(&mut i).unwrap(), (1, 2));
+
+        /* the fields of a struct must all be read */
+        let mut i = m.iter();
+        read::<Vec<u8>>(&mut i).unwrap();
+        read::<Vec<String>>(&mut i).unwrap();
+        read::<(i16,)>(&mut i).err().unwrap();
+
+        let mut i = DataIter::new(&[2, 0, 0, 0], b"b", &[], LE);
+        assert_eq!(read::<bool>(&mut i), Err(Error::Decode(DecodeError::Invalid {
+            offset: 0, reason: "boolean is neither 0 nor 1" })));
+        let mut i = DataIter::new(&[1, 0, 0, 0], b"u", &[], LE);
+        match read::<String>(&mut i) {
+            Err(Error::Custom(_)) => {}
+            r => panic!("{:?}", r),
+        }
+    }
+}
@@ -0,0 +1,124 @@
+//! The error type shared by the connection & bus level operations of this crate
+
+use std::error;
+use std::fmt;
+use std::io;
+
+use address::AddressError;
+use auth::AuthError;
+use marshal::{DecodeError, EncodeError};
+
+#[derive(Debug)]
+pub enum Error {
+    /// An address string could not be parsed. Contains the address and what was wrong with it.
+    Address(String, AddressError),
+    /// The connection cannot do what was asked: there is no address to connect to, the address
+    /// uses an unknown transport or lacks a key, or unix fds were sent without negotiating them
+    Unsupported(String),
+    /// An I/O operation failed. The string says what we were doing at the time.
+    Io(String, io::Error),
+    /// Authentication with the server did not succeed
+    Auth(AuthError),
+    /// The peer sent something which is not valid D-Bus, or not what we expected
+    Protocol(String),
+    /// A message could not be marshalled
+    Encode(EncodeError),
+    /// A message body does not hold the values asked for
+    Decode(DecodeError),
+    /// A path, interface, member, or bus name is not valid. Contains the rejected name and what
+    /// was wrong with it.
+    InvalidName { name: String, reason: &'static str },
+    /// No message arrived before the read timeout expired
+    Timeout,
+    /// The connection was closed by the peer
+    Closed,
+    /// The peer replied with a D-Bus error. Contains the error name and its message, if it sent
+    /// one.
+    Remote(String, Option<String>),
+}
+
+impl Error {
+    /**
+     * Classify an I/O error, turning timeouts into `Error::Timeout`
+     */
+    pub(crate) fn io<S: Into<String>>(context: S, e: io::Error) -> Error {
+        match e.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => Error::Timeout,
+            _ => Error::Io(context.into(), e),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Address(ref a, ref e) => write!(fmt, "Invalid address '{}': {}", a, e),
+            Error::Unsupported(ref e) => write!(fmt, "Unsupported: {}", e),
+            Error::Io(ref c, ref e) => write!(fmt, "Could not {}: {}", c, e),
+            Error::Auth(ref e) => e.fmt(fmt),
+            Error::Protocol(ref e) => write!(fmt, "Protocol violation: {}", e),
+            Error::Encode(ref e) => write!(fmt, "Could not encode message: {}", e),
+            Error::Decode(ref e) => write!(fmt, "Could not decode message: {}", e),
+            Error::InvalidName { ref name, reason } => write!(fmt, "Invalid name '{}': {}", name, reason),
+            Error::Timeout => write!(fmt, "Timed out waiting for a message"),
+            Error::Closed => write!(fmt, "Connection closed"),
+            Error::Remote(ref n, Some(ref m)) => write!(fmt, "{}: {}", n, m),
+            Error::Remote(ref n, None) => write!(fmt, "{}", n),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Address(_, ref e) => Some(e),
+            Error::Io(_, ref e) => Some(e),
+            Error::Auth(ref e) => Some(e),
+            Error::Encode(ref e) => Some(e),
+            Error::Decode(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<AuthError> for Error {
+    fn from(e: AuthError) -> Error {
+        Error::Auth(e)
+    }
+}
+
+impl From<EncodeError> for Error {
+    fn from(e: EncodeError) -> Error {
+        Error::Encode(e)
+    }
+}
+
+impl From<DecodeError> for Error {
+    fn from(e: DecodeError) -> Error {
+        Error::Decode(e)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Error;
+    use auth::AuthError;
+    use std::error::Error as StdError;
+    use std::io;
+
+    #[test]
+    fn classify() {
+        match Error::io("read", io::Error::new(io::ErrorKind::WouldBlock, "x")) {
+            Error::Timeout => {}
+            e => panic!("{:?}", e),
+        }
+
+        let e = Error::io("read a message", io::Error::new(io::ErrorKind::BrokenPipe, "gone"));
+        assert_eq!(e.to_string(), "Could not read a message: gone");
+        assert!(e.source().is_some());
+
+        let e = Error::from(AuthError::Rejected(vec!["EXTERNAL".to_owned()]));
+        assert!(e.source().is_some());
+        assert_eq!(Error::Remote("a.B".to_owned(), Some("no".to_owned())).to_string(), "a.B: no");
+    }
+}
@@ -0,0 +1,231 @@
+//! Splits a byte stream into complete D-Bus messages
+//!
+//! D-Bus runs over stream sockets, so a single read may return part of a message, or the end of
+//! one message and the start of the next. The fixed 16 byte prefix of every message is enough to
+//! determine how long the rest of it is.
+
+use std::io;
+use std::os::unix::io::OwnedFd;
+
+use header;
+use marshal::DecodeError;
+use transport::{RecvFds, Transport};
+
+pub use marshal::{MAX_ARRAY_LEN, MAX_MESSAGE_LEN};
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/**
+ * Determine the total length of a message (header, header fields, padding, and body) from its
+ * first `header::FIXED_LEN` bytes.
+ */
+pub fn message_len(prefix: &[u8; header::FIXED_LEN]) -> io::Result<usize> {
+    let (h, fields_len) = header::Bus::from_bytes(prefix)
+        .ok_or_else(|| invalid_data("message has an invalid endian marker"))?;
+
+    if h.version() != 1 {
+        return Err(invalid_data("message has an unsupported protocol version"));
+    }
+
+    let fields_len = fields_len as usize;
+    if fields_len > MAX_ARRAY_LEN {
+        /* the length of the header field array is at offset 12 */
+        return Err(io::Error::new(io::ErrorKind::InvalidData, DecodeError::ArrayTooLong { offset: 12 }));
+    }
+
+    /* the body starts on an 8 byte boundary */
+    let body_start = (header::FIXED_LEN + fields_len + 7) & !7;
+    let len = body_start + h.body_size() as usize;
+    if len > MAX_MESSAGE_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, DecodeError::MessageTooLong { len }));
+    }
+
+    Ok(len)
+}
+
+/**
+ * Reads complete messages from a stream.
+ *
+ * Bytes read beyond the end of one message are kept and used for the next. Unix fds that arrive
+ * are queued until a message claims them with `take_fds()`.
+ */
+pub struct MessageReader<R> {
+    inner: R,
+    buf: Vec<u8>,
+    fds: RecvFds,
+}
+
+impl<R: Transport> MessageReader<R> {
+    pub fn new(inner: R) -> MessageReader<R> {
+        MessageReader { inner, buf: vec![], fds: RecvFds::new() }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /*
+     * Read until at least `len` bytes are buffered. Returns false if the stream ended first.
+     */
+    fn fill_to(&mut self, len: usize) -> io::Result<bool> {
+        let mut chunk = [0u8; 4096];
+        while self.buf.len() < len {
+            match self.inner.recv_with_fds(&mut chunk, &mut self.fds) {
+                Ok(0) => return Ok(false),
+                Ok(n) => self.buf.extend_from_slice(&chunk[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(true)
+    }
+
+    /**
+     * Read the next complete message, including its header.
+     *
+     * Returns `Ok(None)` if the stream ends cleanly between messages. A stream that ends part way
+     * through a message is an error.
+     */
+    pub fn read_message(&mut self) -> io::Result<Option<Vec<u8>>> {
+        if !self.fill_to(header::FIXED_LEN)? {
+            if self.buf.is_empty() {
+                return Ok(None);
+            }
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "stream ended within a message header"));
+        }
+
+        let mut prefix = [0u8; header::FIXED_LEN];
+        prefix.copy_from_slice(&self.buf[..header::FIXED_LEN]);
+        let len = message_len(&prefix)?;
+
+        if !self.fill_to(len)? {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "stream ended within a message"));
+        }
+
+        let rest = self.buf.split_off(len);
+        Ok(Some(::std::mem::replace(&mut self.buf, rest)))
+    }
+
+    /**
+     * Claim the next `n` received unix fds, for a message whose `UNIX_FDS` header field says it
+     * carries `n` of them.
+     *
+     * Fds always arrive no later than the first byte of the message they belong to, so if fewer
+     * than `n` are queued the peer did not send them, and `None` is returned.
+     */
+    pub fn take_fds(&mut self, n: usize) -> Option<Vec<OwnedFd>> {
+        self.fds.take(n)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{message_len, MessageReader};
+    use marshal::DecodeError;
+    use std::io::{self, Read, Write};
+    use transport::Transport;
+
+    /* a reader which hands out its data in the given chunk sizes */
+    struct Chunked {
+        data: Vec<u8>,
+        chunks: Vec<usize>,
+    }
+
+    impl Read for Chunked {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = if self.chunks.is_empty() { self.data.len() } else { self.chunks.remove(0) };
+            let n = ::std::cmp::min(::std::cmp::min(n, buf.len()), self.data.len());
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data.drain(..n);
+            Ok(n)
+        }
+    }
+
+    impl Write for Chunked {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Transport for Chunked {}
+
+    /* a method call with 3 bytes of header fields and a 4 byte body */
+    fn msg_le(serial: u8) -> Vec<u8> {
+        vec![b'l', 1, 0, 1,  4, 0, 0, 0,  serial, 0, 0, 0,  3, 0, 0, 0,
+             1, 2, 3, 0, 0, 0, 0, 0,
+             9, 9, 9, 9]
+    }
+
+    #[test]
+    fn len() {
+        let m = msg_le(1);
+        let mut p = [0u8; 16];
+        p.copy_from_slice(&m[..16]);
+        assert_eq!(message_len(&p).unwrap(), m.len());
+
+        let mut p = [b'B', 1, 0, 1,  0, 0, 0, 4,  0, 0, 0, 1,  0, 0, 0, 8];
+        assert_eq!(message_len(&p).unwrap(), 16 + 8 + 4);
+
+        p[0] = b'b';
+        message_len(&p).err().unwrap();
+        p[0] = b'B';
+        p[3] = 2;
+        message_len(&p).err().unwrap();
+
+        let p = [b'l', 1, 0, 1,  0, 0, 0, 0x10,  1, 0, 0, 0,  0, 0, 0, 0];
+        let e = message_len(&p).err().unwrap();
+        assert_eq!(e.into_inner().unwrap().downcast::<DecodeError>().ok().map(|e| *e),
+                   Some(DecodeError::MessageTooLong { len: 16 + 0x1000_0000 }));
+        let p = [b'l', 1, 0, 1,  0, 0, 0, 0,  1, 0, 0, 0,  0, 0, 0, 0x10];
+        let e = message_len(&p).err().unwrap();
+        assert_eq!(e.into_inner().unwrap().downcast::<DecodeError>().ok().map(|e| *e),
+                   Some(DecodeError::ArrayTooLong { offset: 12 }));
+    }
+
+    #[test]
+    fn split_reads() {
+        let mut data = msg_le(1);
+        data.extend(msg_le(2));
+        let mut r = MessageReader::new(Chunked { data, chunks: vec![1, 1, 5, 20, 3, 1, 30] });
+        assert_eq!(r.read_message().unwrap().unwrap(), msg_le(1));
+        assert_eq!(r.read_message().unwrap().unwrap(), msg_le(2));
+        assert!(r.read_message().unwrap().is_none());
+    }
+
+    #[test]
+    fn single_read() {
+        let mut data = msg_le(1);
+        data.extend(msg_le(2));
+        data.extend(msg_le(3));
+        let mut r = MessageReader::new(Chunked { data, chunks: vec![] });
+        assert_eq!(r.read_message().unwrap().unwrap(), msg_le(1));
+        assert_eq!(r.read_message().unwrap().unwrap(), msg_le(2));
+        assert_eq!(r.read_message().unwrap().unwrap(), msg_le(3));
+        assert!(r.read_message().unwrap().is_none());
+    }
+
+    #[test]
+    fn truncated() {
+        let mut data = msg_le(1);
+        data.truncate(20);
+        let mut r = MessageReader::new(Chunked { data, chunks: vec![] });
+        assert_eq!(r.read_message().err().unwrap().kind(), io::ErrorKind::UnexpectedEof);
+
+        let mut r = MessageReader::new(Chunked { data: vec![b'l', 1], chunks: vec![] });
+        assert_eq!(r.read_message().err().unwrap().kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
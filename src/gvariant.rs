@@ -0,0 +1,622 @@
+//! The GVariant serialization format, used by GLib for dconf databases, `GSettings` overrides, and
+//! other files and blobs
+//!
+//! GVariant has the same types as D-Bus, plus maybe types ('m') and the unit type ('()'), but lays
+//! them out differently:
+//!
+//! - values are aligned to their natural alignment, and structs to the largest alignment of their
+//!   fields. Strings are not prefixed by their length, so they are only byte aligned.
+//! - types are either fixed size or variable size. The ends of variable size array elements and
+//!   struct fields are recorded in a table of "framing offsets" at the end of their container,
+//!   rather than lengths ahead of them.
+//! - a variant is its contents followed by a nul byte and the type string.
+//!
+//! Values are converted to and from `Value`. Unix fds (type 'h') are indexes into a separate list
+//! in GVariant, and are not supported.
+
+use std::cell::Cell;
+use std::cmp;
+
+use header::{self, Endian};
+use marshal::{self, DecodeError, EncodeError, MAX_DEPTH};
+use names::ObjectPathBuf;
+use type_sig::{Type, TypeBuf};
+use value::Value;
+
+/**
+ * Serialize `value` as GVariant, with numbers in the byte order `endian`. The type of the result
+ * is `value.signature_bytes()`.
+ */
+pub fn encode(value: &Value, endian: Endian) -> Result<Vec<u8>, EncodeError> {
+    let sig = String::from_utf8(value.signature_bytes())
+        .map_err(|_| EncodeError::Invalid("value does not have a valid GVariant type"))?;
+    Type::from_gvariant_str(&sig)
+        .map_err(|_| EncodeError::Invalid("value does not have a valid GVariant type"))?;
+
+    let mut out = vec![];
+    Encoder { big: endian == header::ENDIAN_BIG }.value(value, &mut out)?;
+    Ok(out)
+}
+
+/**
+ * Deserialize `data`, which holds a single value of type `sig` with numbers in the byte order
+ * `endian`.
+ */
+pub fn decode(data: &[u8], sig: Type, endian: Endian) -> Result<Value, DecodeError> {
+    let sig = sig.as_ref().as_bytes();
+    if marshal::single_type_len(sig) != Some(sig.len()) {
+        return Err(DecodeError::Invalid { offset: 0, reason: "type is not a single complete type" });
+    }
+    Decoder { data, big: endian == header::ENDIAN_BIG, depth: Cell::new(0) }.value(0, data.len(), sig)
+}
+
+fn align(pos: usize, align: usize) -> usize {
+    pos.div_ceil(align) * align
+}
+
+/*
+ * The alignment of `sig`, which must be a single complete type
+ */
+fn alignment(sig: &[u8]) -> usize {
+    match sig[0] {
+        b'y' | b'b' | b's' | b'o' | b'g' => 1,
+        b'n' | b'q' => 2,
+        b'i' | b'u' | b'h' => 4,
+        b'x' | b't' | b'd' | b'v' => 8,
+        b'a' | b'm' => alignment(&sig[1..]),
+        _ => fields(sig).into_iter().map(alignment).max().unwrap_or(1),
+    }
+}
+
+/*
+ * The size of every value of type `sig`, if they all have the same size
+ */
+fn fixed_size(sig: &[u8]) -> Option<usize> {
+    match sig[0] {
+        b'y' | b'b' => Some(1),
+        b'n' | b'q' => Some(2),
+        b'i' | b'u' | b'h' => Some(4),
+        b'x' | b't' | b'd' => Some(8),
+        b'(' | b'{' => {
+            let fields = fields(sig);
+            if fields.is_empty() {
+                /* the unit type is a single zero byte */
+                return Some(1);
+            }
+            let mut size = 0;
+            for f in fields {
+                size = align(size, alignment(f)) + fixed_size(f)?;
+            }
+            Some(align(size, alignment(sig)))
+        }
+        _ => None,
+    }
+}
+
+/*
+ * The types of the fields of the struct or dict entry `sig`
+ */
+fn fields(sig: &[u8]) -> Vec<&[u8]> {
+    let mut rest = &sig[1..sig.len() - 1];
+    let mut fields = vec![];
+    while let Some(l) = marshal::single_type_len(rest) {
+        fields.push(&rest[..l]);
+        rest = &rest[l..];
+    }
+    fields
+}
+
+/*
+ * The size of the framing offsets in a container that is `size` bytes long, including them
+ */
+fn offset_size(size: usize) -> usize {
+    if size == 0 {
+        0
+    } else if size <= 0xff {
+        1
+    } else if size <= 0xffff {
+        2
+    } else if size <= 0xffff_ffff {
+        4
+    } else {
+        8
+    }
+}
+
+fn pad(out: &mut Vec<u8>, to: usize) {
+    let l = align(out.len(), to);
+    out.resize(l, 0);
+}
+
+struct Encoder {
+    big: bool,
+}
+
+impl Encoder {
+    fn number<const N: usize>(&self, out: &mut Vec<u8>, le: [u8; N], be: [u8; N]) {
+        out.extend_from_slice(if self.big { &be } else { &le });
+    }
+
+    fn string(&self, out: &mut Vec<u8>, s: &str) -> Result<(), EncodeError> {
+        if s.as_bytes().contains(&0) {
+            return Err(EncodeError::Invalid("string contains a nul byte"));
+        }
+        out.extend_from_slice(s.as_bytes());
+        out.push(0);
+        Ok(())
+    }
+
+    /*
+     * Append `value`. `out` must already be padded to the alignment of its type.
+     */
+    fn value(&self, value: &Value, out: &mut Vec<u8>) -> Result<(), EncodeError> {
+        match *value {
+            Value::Byte(v) => out.push(v),
+            Value::Bool(v) => out.push(v as u8),
+            Value::Int16(v) => self.number(out, v.to_le_bytes(), v.to_be_bytes()),
+            Value::UInt16(v) => self.number(out, v.to_le_bytes(), v.to_be_bytes()),
+            Value::Int32(v) => self.number(out, v.to_le_bytes(), v.to_be_bytes()),
+            Value::UInt32(v) => self.number(out, v.to_le_bytes(), v.to_be_bytes()),
+            Value::Int64(v) => self.number(out, v.to_le_bytes(), v.to_be_bytes()),
+            Value::UInt64(v) => self.number(out, v.to_le_bytes(), v.to_be_bytes()),
+            Value::Double(v) => self.number(out, v.to_le_bytes(), v.to_be_bytes()),
+            Value::String(ref v) => self.string(out, v)?,
+            Value::ObjectPath(ref v) => self.string(out, v.as_str())?,
+            Value::Signature(ref v) => self.string(out, v.as_ref())?,
+            Value::UnixFd(_) => return Err(EncodeError::Invalid("unix fds cannot be serialized as GVariant")),
+            Value::Variant(ref v) => {
+                self.value(v, out)?;
+                out.push(0);
+                out.extend(v.signature_bytes());
+            }
+            Value::Maybe { ref element, value: Some(ref v) } => {
+                let element = element.as_ref().as_bytes();
+                if v.signature_bytes() != element {
+                    return Err(EncodeError::Invalid("maybe does not hold a value of its element type"));
+                }
+                self.value(v, out)?;
+                if fixed_size(element).is_none() {
+                    out.push(0);
+                }
+            }
+            Value::Maybe { value: None, .. } => {}
+            Value::Array { ref element, ref values } => {
+                let element = element.as_ref().as_bytes();
+                self.array(out, element, values.len(), |i, out| {
+                    if values[i].signature_bytes() != element {
+                        return Err(EncodeError::Invalid("array element does not have the array's element type"));
+                    }
+                    self.value(&values[i], out)
+                })?;
+            }
+            Value::Struct(ref fields) => {
+                let fields: Vec<_> = fields.iter().collect();
+                self.structure(out, &fields)?;
+            }
+            Value::Dict { ref key, ref value, ref entries } => {
+                let mut element = b"{".to_vec();
+                element.extend(key.as_ref().as_bytes());
+                element.extend(value.as_ref().as_bytes());
+                element.push(b'}');
+                self.array(out, &element, entries.len(), |i, out| {
+                    let (ref k, ref v) = entries[i];
+                    if k.signature_bytes() != key.as_ref().as_bytes() || v.signature_bytes() != value.as_ref().as_bytes() {
+                        return Err(EncodeError::Invalid("dict entry does not have the dict's entry type"));
+                    }
+                    self.structure(out, &[k, v])
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    /*
+     * Append an array of `len` elements of type `element`, which `f` appends
+     */
+    fn array<F>(&self, out: &mut Vec<u8>, element: &[u8], len: usize, mut f: F) -> Result<(), EncodeError>
+        where F: FnMut(usize, &mut Vec<u8>) -> Result<(), EncodeError>
+    {
+        let start = out.len();
+        let fixed = fixed_size(element).is_some();
+        let mut ends = vec![];
+        for i in 0..len {
+            pad(out, alignment(element));
+            f(i, out)?;
+            if !fixed {
+                ends.push(out.len() - start);
+            }
+        }
+        write_offsets(out, start, &ends);
+        Ok(())
+    }
+
+    /*
+     * Append a struct or dict entry holding `fields`
+     */
+    fn structure(&self, out: &mut Vec<u8>, fields: &[&Value]) -> Result<(), EncodeError> {
+        if fields.is_empty() {
+            out.push(0);
+            return Ok(());
+        }
+
+        let start = out.len();
+        let mut fixed = true;
+        let mut max_align = 1;
+        let mut ends = vec![];
+        for (i, f) in fields.iter().enumerate() {
+            let sig = f.signature_bytes();
+            max_align = cmp::max(max_align, alignment(&sig));
+            pad(out, alignment(&sig));
+            self.value(f, out)?;
+            if fixed_size(&sig).is_none() {
+                fixed = false;
+                /* the last field ends where the framing offsets start */
+                if i + 1 < fields.len() {
+                    ends.push(out.len() - start);
+                }
+            }
+        }
+
+        if fixed {
+            pad(out, max_align);
+        } else {
+            ends.reverse();
+            write_offsets(out, start, &ends);
+        }
+        Ok(())
+    }
+}
+
+/*
+ * Append the framing offsets `ends` to the container that starts at `start`, using the smallest
+ * offset size that can address the container once they are added. Offsets are always little
+ * endian.
+ */
+fn write_offsets(out: &mut Vec<u8>, start: usize, ends: &[usize]) {
+    if ends.is_empty() {
+        return;
+    }
+    let body = out.len() - start;
+    let size = [1, 2, 4, 8].iter().cloned()
+        .find(|&s| offset_size(body + ends.len() * s) <= s)
+        .unwrap_or(8);
+    for &e in ends {
+        out.extend_from_slice(&(e as u64).to_le_bytes()[..size]);
+    }
+}
+
+struct Decoder<'a> {
+    data: &'a [u8],
+    big: bool,
+    /// The number of containers being decoded, which is limited as it is for D-Bus
+    depth: Cell<usize>,
+}
+
+impl<'a> Decoder<'a> {
+    fn invalid(&self, offset: usize, reason: &'static str) -> DecodeError {
+        DecodeError::Invalid { offset, reason }
+    }
+
+    fn number<const N: usize>(&self, start: usize, end: usize) -> Result<[u8; N], DecodeError> {
+        if end - start != N {
+            return Err(self.invalid(start, "value is not the size of its type"));
+        }
+        let mut b = [0; N];
+        b.copy_from_slice(&self.data[start..end]);
+        if self.big {
+            b.reverse();
+        }
+        /* the bytes are now little endian */
+        Ok(b)
+    }
+
+    fn string(&self, start: usize, end: usize) -> Result<&'a str, DecodeError> {
+        let s = &self.data[start..end];
+        match s.iter().position(|&b| b == 0) {
+            Some(l) if l + 1 == s.len() => {
+                ::std::str::from_utf8(&s[..l]).map_err(|_| self.invalid(start, "string is not valid UTF-8"))
+            }
+            _ => Err(self.invalid(start, "string is not terminated by its only nul byte")),
+        }
+    }
+
+    /*
+     * Read the framing offset at `at`, which is relative to the container starting at `start`
+     */
+    fn offset(&self, start: usize, at: usize, size: usize) -> usize {
+        let mut b = [0; 8];
+        b[..size].copy_from_slice(&self.data[at..at + size]);
+        start.saturating_add(u64::from_le_bytes(b) as usize)
+    }
+
+    /*
+     * Decode the value of type `sig` held in `data[start..end]`
+     */
+    fn value(&self, start: usize, end: usize, sig: &[u8]) -> Result<Value, DecodeError> {
+        /* the entries of a dict are containers within it */
+        let containers = match sig {
+            [b'a', b'{', ..] => 2,
+            [b'a' | b'm' | b'v' | b'(', ..] => 1,
+            _ => 0,
+        };
+        let depth = self.depth.get() + containers;
+        if depth > MAX_DEPTH {
+            return Err(DecodeError::TooDeep { offset: start });
+        }
+        self.depth.set(depth);
+        let r = self.contents(start, end, sig);
+        self.depth.set(depth - containers);
+        r
+    }
+
+    fn contents(&self, start: usize, end: usize, sig: &[u8]) -> Result<Value, DecodeError> {
+        if let Some(size) = fixed_size(sig) {
+            if end - start != size {
+                return Err(self.invalid(start, "value is not the size of its type"));
+            }
+        }
+
+        let v = match sig[0] {
+            b'y' => Value::Byte(self.data[start]),
+            b'b' => match self.data[start] {
+                0 => Value::Bool(false),
+                1 => Value::Bool(true),
+                _ => return Err(self.invalid(start, "boolean is neither 0 nor 1")),
+            },
+            b'n' => Value::Int16(i16::from_le_bytes(self.number(start, end)?)),
+            b'q' => Value::UInt16(u16::from_le_bytes(self.number(start, end)?)),
+            b'i' => Value::Int32(i32::from_le_bytes(self.number(start, end)?)),
+            b'u' => Value::UInt32(u32::from_le_bytes(self.number(start, end)?)),
+            b'x' => Value::Int64(i64::from_le_bytes(self.number(start, end)?)),
+            b't' => Value::UInt64(u64::from_le_bytes(self.number(start, end)?)),
+            b'd' => Value::Double(f64::from_le_bytes(self.number(start, end)?)),
+            b's' => Value::String(self.string(start, end)?.to_owned()),
+            b'o' => Value::ObjectPath(ObjectPathBuf::new(self.string(start, end)?)
+                .map_err(|_| self.invalid(start, "object path is not valid"))?),
+            b'g' => Value::Signature(TypeBuf::from_string(self.string(start, end)?.to_owned())
+                .map_err(|_| self.invalid(start, "signature is not valid"))?),
+            b'h' => return Err(self.invalid(start, "unix fds cannot be deserialized from GVariant")),
+            b'v' => {
+                let sep = self.data[start..end].iter().rposition(|&b| b == 0)
+                    .ok_or_else(|| self.invalid(start, "variant has no type"))? + start;
+                let t = &self.data[sep + 1..end];
+                let valid = ::std::str::from_utf8(t).map(|t| Type::from_gvariant_str(t).is_ok()).unwrap_or(false);
+                if !valid || marshal::single_type_len(t) != Some(t.len()) {
+                    return Err(self.invalid(sep + 1, "variant type is not a single complete type"));
+                }
+                Value::Variant(Box::new(self.value(start, sep, t)?))
+            }
+            b'm' => {
+                let element = &sig[1..];
+                let value = if start == end {
+                    None
+                } else if fixed_size(element).is_some() {
+                    Some(Box::new(self.value(start, end, element)?))
+                } else if self.data[end - 1] == 0 {
+                    Some(Box::new(self.value(start, end - 1, element)?))
+                } else {
+                    return Err(self.invalid(end - 1, "maybe does not end with a nul byte"));
+                };
+                Value::Maybe { element: self.type_buf(element, start)?, value }
+            }
+            b'a' if sig[1] == b'{' => {
+                let entry = &sig[1..];
+                let f = fields(entry);
+                let mut entries = vec![];
+                self.array(start, end, entry, |s, e| {
+                    let mut kv = self.structure(s, e, entry)?.into_iter();
+                    entries.push((kv.next().unwrap(), kv.next().unwrap()));
+                    Ok(())
+                })?;
+                Value::Dict { key: self.type_buf(f[0], start)?, value: self.type_buf(f[1], start)?, entries }
+            }
+            b'a' => {
+                let element = &sig[1..];
+                let mut values = vec![];
+                self.array(start, end, element, |s, e| {
+                    values.push(self.value(s, e, element)?);
+                    Ok(())
+                })?;
+                Value::Array { element: self.type_buf(element, start)?, values }
+            }
+            _ => Value::Struct(self.structure(start, end, sig)?),
+        };
+        Ok(v)
+    }
+
+    fn type_buf(&self, sig: &[u8], offset: usize) -> Result<TypeBuf, DecodeError> {
+        ::std::str::from_utf8(sig).ok()
+            .and_then(|s| TypeBuf::from_gvariant_string(s.to_owned()).ok())
+            .ok_or_else(|| self.invalid(offset, "type is not valid"))
+    }
+
+    /*
+     * Call `f` with the range of each element of the array in `data[start..end]`
+     */
+    fn array<F>(&self, start: usize, end: usize, element: &[u8], mut f: F) -> Result<(), DecodeError>
+        where F: FnMut(usize, usize) -> Result<(), DecodeError>
+    {
+        if let Some(size) = fixed_size(element) {
+            if (end - start) % size != 0 {
+                return Err(self.invalid(start, "array is not a multiple of its element size"));
+            }
+            for s in (start..end).step_by(size) {
+                f(s, s + size)?;
+            }
+            return Ok(());
+        }
+        if start == end {
+            return Ok(());
+        }
+
+        /* the last offset is the end of the last element, and so the start of the offsets */
+        let size = offset_size(end - start);
+        let table = self.offset(start, end - size, size);
+        if table > end - size || (end - table) % size != 0 {
+            return Err(self.invalid(end - size, "array framing offset is out of range"));
+        }
+        let mut pos = start;
+        for at in (table..end).step_by(size) {
+            let s = align(pos, alignment(element));
+            let e = self.offset(start, at, size);
+            if e < s || e > table {
+                return Err(self.invalid(at, "array framing offset is out of range"));
+            }
+            f(s, e)?;
+            pos = e;
+        }
+        Ok(())
+    }
+
+    /*
+     * Decode the fields of the struct or dict entry of type `sig` in `data[start..end]`
+     */
+    fn structure(&self, start: usize, end: usize, sig: &[u8]) -> Result<Vec<Value>, DecodeError> {
+        let fields = fields(sig);
+        if fields.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let size = offset_size(end - start);
+        /* framing offsets are read from the end, which moves back as they are */
+        let mut table = end;
+        let mut pos = start;
+        let mut values = vec![];
+        for (i, f) in fields.iter().enumerate() {
+            pos = align(pos, alignment(f));
+            let e = match fixed_size(f) {
+                Some(s) => pos + s,
+                None if i + 1 == fields.len() => table,
+                None => {
+                    if table < pos + size {
+                        return Err(self.invalid(start, "struct framing offsets are missing"));
+                    }
+                    table -= size;
+                    self.offset(start, table, size)
+                }
+            };
+            if e < pos || e > table {
+                return Err(self.invalid(pos, "struct field is out of range"));
+            }
+            values.push(self.value(pos, e, f)?);
+            pos = e;
+        }
+        if fixed_size(sig).is_none() && pos != table {
+            return Err(self.invalid(pos, "struct has data after its last field"));
+        }
+        Ok(values)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decode, encode};
+    use header::{ENDIAN_BIG, ENDIAN_LITTLE as LE};
+    use marshal::{DecodeError, EncodeError};
+    use names::ObjectPathBuf;
+    use type_sig::{Type, TypeBuf};
+    use value::Value;
+
+    fn t(s: &str) -> TypeBuf {
+        TypeBuf::from_gvariant_string(s.to_owned()).unwrap()
+    }
+
+    fn round_trip(v: Value, bytes: &[u8]) {
+        let sig = String::from_utf8(v.signature_bytes()).unwrap();
+        assert_eq!(encode(&v, LE).unwrap(), bytes);
+        assert_eq!(decode(bytes, Type::from_gvariant_str(&sig).unwrap(), LE).unwrap(), v);
+    }
+
+    /* examples from the GVariant specification */
+    #[test]
+    fn spec() {
+        round_trip(Value::from("hello world"), b"hello world\0");
+        round_trip(Value::Maybe { element: t("s"), value: Some(Box::new(Value::from("hello world"))) },
+            b"hello world\0\0");
+        round_trip(Value::from(vec![true, false, false, true, true]), &[1, 0, 0, 1, 1]);
+        round_trip(Value::Struct(vec![Value::from("foo"), Value::Int32(-1)]),
+            b"foo\0\xff\xff\xff\xff\x04");
+        round_trip(Value::Struct(vec![Value::Int32(96), Value::Byte(0x70)]),
+            &[0x60, 0, 0, 0, 0x70, 0, 0, 0]);
+        round_trip(Value::from(vec!["i".to_owned(), "can".to_owned(), "has".to_owned(),
+            "strings?".to_owned()]), b"i\0can\0has\0strings?\0\x02\x06\x0a\x13");
+        round_trip(Value::Variant(Box::new(Value::from(vec![(1i32, 2u8), (3, 4)]))),
+            &[1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0, 4, 0, 0, 0, 0, b'a', b'(', b'i', b'y', b')']);
+        round_trip(Value::Array { element: t("as"), values: vec![
+                Value::from(vec!["hello".to_owned(), "world".to_owned()]),
+                Value::from(vec!["this".to_owned(), "is".to_owned(), "a".to_owned()]),
+                Value::from(vec!["test".to_owned()]),
+            ] },
+            b"hello\0world\0\x06\x0cthis\0is\0a\0\x05\x08\x0atest\0\x05\x0e\x1b\x21");
+        round_trip(Value::Dict { key: t("s"), value: t("i"), entries: vec![
+                (Value::from("hi"), Value::Int32(-2)),
+                (Value::from("bye"), Value::Int32(4)),
+            ] },
+            b"hi\0\0\xfe\xff\xff\xff\x03\0\0\0bye\0\x04\0\0\0\x04\x09\x15");
+    }
+
+    #[test]
+    fn maybes_and_units() {
+        round_trip(Value::Maybe { element: t("s"), value: None }, b"");
+        round_trip(Value::Maybe { element: t("i"), value: Some(Box::new(Value::Int32(5))) }, &[5, 0, 0, 0]);
+        round_trip(Value::Maybe { element: t("mi"), value: Some(Box::new(
+            Value::Maybe { element: t("i"), value: None })) }, &[0]);
+        round_trip(Value::Struct(vec![]), &[0]);
+        round_trip(Value::Array { element: t("()"), values: vec![Value::Struct(vec![]), Value::Struct(vec![])] },
+            &[0, 0]);
+        round_trip(Value::Struct(vec![
+                Value::Maybe { element: t("s"), value: Some(Box::new(Value::from("a"))) },
+                Value::ObjectPath(ObjectPathBuf::new("/").unwrap()),
+                Value::Int64(1),
+            ]),
+            b"a\0\0/\0\0\0\0\x01\0\0\0\0\0\0\0\x05\x03");
+        round_trip(Value::Variant(Box::new(Value::Maybe { element: t("b"), value: None })), b"\0mb");
+    }
+
+    #[test]
+    fn endian() {
+        let v = Value::Struct(vec![Value::UInt16(1), Value::from("ab"), Value::Double(1.0)]);
+        let b = encode(&v, ENDIAN_BIG).unwrap();
+        assert_eq!(b, b"\0\x01ab\0\0\0\0\x3f\xf0\0\0\0\0\0\0\x05");
+        assert_eq!(decode(&b, Type::from_gvariant_str("(qsd)").unwrap(), ENDIAN_BIG).unwrap(), v);
+    }
+
+    #[test]
+    fn large() {
+        /* offsets grow to two bytes once the container would be longer than 255 bytes */
+        let v = Value::from(vec!["x".repeat(100), "y".repeat(100), "z".repeat(60)]);
+        let b = encode(&v, LE).unwrap();
+        assert_eq!(b.len(), 263 + 3 * 2);
+        assert_eq!(&b[263..], &[101, 0, 202, 0, 7, 1]);
+        assert_eq!(decode(&b, Type::from_gvariant_str("as").unwrap(), LE).unwrap(), v);
+    }
+
+    #[test]
+    fn errors() {
+        match encode(&Value::String("a\0b".to_owned()), LE) {
+            Err(EncodeError::Invalid(_)) => {}
+            r => panic!("{:?}", r),
+        }
+        match encode(&Value::Maybe { element: t("s"), value: Some(Box::new(Value::Int32(1))) }, LE) {
+            Err(EncodeError::Invalid(_)) => {}
+            r => panic!("{:?}", r),
+        }
+
+        let bad = |b: &[u8], sig: &str| decode(b, Type::from_gvariant_str(sig).unwrap(), LE).err().unwrap();
+        assert_eq!(bad(&[2], "b"), DecodeError::Invalid { offset: 0, reason: "boolean is neither 0 nor 1" });
+        assert_eq!(bad(&[1, 0, 0], "i"), DecodeError::Invalid { offset: 0, reason: "value is not the size of its type" });
+        assert_eq!(bad(b"ab", "s"), DecodeError::Invalid { offset: 0, reason: "string is not terminated by its only nul byte" });
+        assert_eq!(bad(b"a\0\x09", "as"), DecodeError::Invalid { offset: 2, reason: "array framing offset is out of range" });
+        assert_eq!(bad(b"a\0b", "ms"), DecodeError::Invalid { offset: 2, reason: "maybe does not end with a nul byte" });
+        assert_eq!(bad(b"\x01\0ii", "v"), DecodeError::Invalid { offset: 2, reason: "variant type is not a single complete type" });
+        assert_eq!(bad(&[1, 0, 0], "ai"), DecodeError::Invalid { offset: 0, reason: "array is not a multiple of its element size" });
+        decode(&[], Type::from_gvariant_str("ii").unwrap(), LE).err().unwrap();
+
+        /* variants nest as deeply as D-Bus allows */
+        let mut nested = vec![5, 0, b'y'];
+        nested.extend(b"\0v".repeat(63));
+        decode(&nested, Type::from_gvariant_str("v").unwrap(), LE).unwrap();
+        nested.extend(b"\0v");
+        assert_eq!(bad(&nested, "v"), DecodeError::TooDeep { offset: 0 });
+    }
+}
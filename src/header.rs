@@ -9,6 +9,16 @@ pub struct Bus {
     serial: u32,
 }
 
+/// Length of the fixed part of the header, plus the length of the header field array that follows
+/// it. Enough to determine the length of the entire message.
+pub const FIXED_LEN: usize = 16;
+
+impl ::std::default::Default for Bus {
+    fn default() -> Bus {
+        Bus::new()
+    }
+}
+
 impl Bus {
     pub fn new() -> Bus {
         Bus {
@@ -21,6 +31,104 @@ impl Bus {
             serial: 0,
         }
     }
+
+    /**
+     * Parse the fixed portion of a message header
+     *
+     * Returns the parsed header and the length of the header field array that follows it, or
+     * `None` if the endian marker is not one we know about.
+     */
+    pub fn from_bytes(b: &[u8; FIXED_LEN]) -> Option<(Bus, u32)> {
+        let endian = match b[0] {
+            b'l' => ENDIAN_LITTLE,
+            b'B' => ENDIAN_BIG,
+            _ => return None,
+        };
+        let u32_at = |i: usize| {
+            let v = [b[i], b[i + 1], b[i + 2], b[i + 3]];
+            if endian == ENDIAN_BIG {
+                u32::from_be_bytes(v)
+            } else {
+                u32::from_le_bytes(v)
+            }
+        };
+
+        Some((Bus {
+            endian,
+            typ: Type::from_bits_truncate(b[1]),
+            flags: Flags::from_bits_truncate(b[2]),
+            version: b[3],
+            body_size: u32_at(4),
+            serial: u32_at(8),
+        }, u32_at(12)))
+    }
+
+    /**
+     * Serialize the fixed portion of the header, followed by the length of the header field array
+     */
+    pub fn to_bytes(&self, fields_len: u32) -> [u8; FIXED_LEN] {
+        let u32_bytes = |v: u32| {
+            if self.endian == ENDIAN_BIG {
+                v.to_be_bytes()
+            } else {
+                v.to_le_bytes()
+            }
+        };
+
+        let mut b = [0u8; FIXED_LEN];
+        b[0] = self.endian.bits();
+        b[1] = self.typ.bits();
+        b[2] = self.flags.bits();
+        b[3] = self.version;
+        b[4..8].copy_from_slice(&u32_bytes(self.body_size));
+        b[8..12].copy_from_slice(&u32_bytes(self.serial));
+        b[12..16].copy_from_slice(&u32_bytes(fields_len));
+        b
+    }
+
+    pub fn set_typ(&mut self, typ: Type) {
+        self.typ = typ;
+    }
+
+    pub fn set_flags(&mut self, flags: Flags) {
+        self.flags = flags;
+    }
+
+    pub fn set_body_size(&mut self, body_size: u32) {
+        self.body_size = body_size;
+    }
+
+    pub fn set_endian(&mut self, endian: Endian) {
+        self.endian = endian;
+    }
+
+    pub fn set_serial(&mut self, serial: u32) {
+        self.serial = serial;
+    }
+
+    pub fn endian(&self) -> Endian {
+        self.endian
+    }
+
+    pub fn typ(&self) -> Type {
+        self.typ
+    }
+
+    pub fn flags(&self) -> Flags {
+        self.flags
+    }
+
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    pub fn body_size(&self) -> u32 {
+        self.body_size
+    }
+
+    pub fn serial(&self) -> u32 {
+        self.serial
+    }
 }
 
 bitflags! {
@@ -36,7 +144,7 @@ bitflags! {
 bitflags! {
     pub flags Endian: u8 {
         const ENDIAN_LITTLE = b'l',
-        const ENDIAN_BIG = b'b',
+        const ENDIAN_BIG = b'B',
     }
 }
 
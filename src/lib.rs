@@ -1,14 +1,39 @@
 extern crate utf8_cstr;
+extern crate libc;
+extern crate sha1_smol;
 #[macro_use]
 extern crate bitflags;
+#[cfg(feature = "derive")]
+extern crate rdbus_derive;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
+#[cfg(all(test, feature = "serde"))]
+#[macro_use]
+extern crate serde_derive;
 
 
+pub mod address;
+pub mod error;
 pub mod auth;
+pub mod framing;
 pub mod type_sig;
 pub mod marshal;
+pub mod value;
+pub mod gvariant;
+pub mod stream;
+#[cfg(feature = "serde")]
+pub mod ser;
+#[cfg(feature = "serde")]
+pub mod de;
 pub mod names;
+/* the `Debug` impls bitflags 0.7 generates use `try!` */
+#[allow(deprecated)]
 pub mod header;
 pub mod message;
+pub mod transport;
+
+pub use error::Error;
 
 
 /**
@@ -21,38 +46,492 @@ pub mod message;
  * one name)
  */
 pub struct Bus {
-    /* FIXME: allow non-unix sockets. Tcp is typically used on windows systems */
-    sock: std::os::unix::net::UnixDatagram,
+    reader: framing::MessageReader<Box<dyn transport::Transport + Send>>,
+    guid: String,
+    /// Whether unix fd passing was negotiated during authentication
+    unix_fd: bool,
+    serial: u32,
+    /// Assigned by the bus in reply to `Hello()`. Peer to peer connections have none.
+    unique_name: Option<names::BusNameBuf>,
 }
 
 impl Bus {
     /**
      * Open the appropriate bus
+     *
+     * The user bus is used if one appears to exist, otherwise the system bus is used.
      */
-    pub fn open() -> Result<Bus, String> {
-        unimplemented!();
+    pub fn open() -> Result<Bus, Error> {
+        if user_bus_address().is_some() {
+            Bus::open_user()
+        } else {
+            Bus::open_system()
+        }
     }
 
     /**
      * Open the user bus
+     *
+     * `DBUS_SESSION_BUS_ADDRESS` is used if set, otherwise `$XDG_RUNTIME_DIR/bus`.
      */
-    pub fn open_user() -> Result<Bus, String> {
-        unimplemented!();
+    pub fn open_user() -> Result<Bus, Error> {
+        match user_bus_address() {
+            Some(addr) => Bus::open_address_list(&addr),
+            None => Err(Error::Unsupported("no user bus found: DBUS_SESSION_BUS_ADDRESS is unset and \
+                                            $XDG_RUNTIME_DIR/bus does not exist".to_owned())),
+        }
     }
 
     /**
      * Open the system bus
+     *
+     * `DBUS_SYSTEM_BUS_ADDRESS` is used if set, otherwise `/run/dbus/system_bus_socket`.
+     */
+    pub fn open_system() -> Result<Bus, Error> {
+        Bus::open_address_list(&system_bus_address())
+    }
+
+    /*
+     * Try each of the ';' seperated addresses in turn, using the first that we can connect to.
+     */
+    fn open_address_list(addrs: &str) -> Result<Bus, Error> {
+        let addrs = address::Address::parse_list(addrs)
+            .map_err(|e| Error::Address(addrs.to_owned(), e))?;
+
+        let mut last_err = Error::Unsupported("no addresses to connect to".to_owned());
+        for addr in addrs.iter() {
+            match Bus::open_address(addr) {
+                Ok(b) => return Ok(b),
+                Err(e) => last_err = e,
+            }
+        }
+
+        Err(last_err)
+    }
+
+    /**
+     * Connect to the bus at the given server address
+     */
+    pub fn open_address(addr: &address::Address) -> Result<Bus, Error> {
+        Bus::open_stream(Bus::connect(addr)?)
+    }
+
+    /**
+     * Connect directly to another program at the given server address, rather than to a bus
+     *
+     * No `Hello()` is sent, and the connection has no unique name.
      */
-    pub fn open_system() -> Result<Bus, String> {
-        unimplemented!();
+    pub fn open_peer_address(addr: &address::Address) -> Result<Bus, Error> {
+        Bus::open_peer_stream(Bus::connect(addr)?)
+    }
+
+    fn connect(addr: &address::Address) -> Result<Box<dyn transport::Transport + Send>, Error> {
+        use address::Transport;
+        use std::os::unix::net::UnixStream;
+
+        let unix_err = |path: &std::path::Path, e| {
+            Error::io(format!("connect to '{}'", path.display()), e)
+        };
+        let inet_err = |e| Error::io(format!("connect to '{}'", addr), e);
+        let missing = |key| Error::Unsupported(format!("cannot connect to '{}' without a {}", addr, key));
+        let s: Box<dyn transport::Transport + Send> = match *addr.transport() {
+            Transport::UnixPath(ref path) => {
+                Box::new(UnixStream::connect(path).map_err(|e| unix_err(path, e))?)
+            }
+            Transport::UnixRuntime => {
+                let dir = std::env::var_os("XDG_RUNTIME_DIR")
+                    .ok_or_else(|| Error::Unsupported("unix:runtime=yes given, but XDG_RUNTIME_DIR is unset"
+                                                      .to_owned()))?;
+                let path = std::path::Path::new(&dir).join("bus");
+                Box::new(UnixStream::connect(&path).map_err(|e| unix_err(&path, e))?)
+            }
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            Transport::UnixAbstract(ref name) => {
+                #[cfg(target_os = "linux")]
+                use std::os::linux::net::SocketAddrExt;
+                #[cfg(target_os = "android")]
+                use std::os::android::net::SocketAddrExt;
+
+                let sa = std::os::unix::net::SocketAddr::from_abstract_name(name)
+                    .map_err(|e| Error::io("use abstract socket name", e))?;
+                Box::new(UnixStream::connect_addr(&sa).map_err(inet_err)?)
+            }
+            Transport::Tcp { ref host, port, family } => {
+                let port = port.ok_or_else(|| missing("port"))?;
+                Box::new(transport::connect_tcp(host.as_ref().map(|h| &h[..]), port, family)
+                    .map_err(inet_err)?)
+            }
+            Transport::NonceTcp { ref host, port, family, ref noncefile } => {
+                let port = port.ok_or_else(|| missing("port"))?;
+                let noncefile = noncefile.as_ref().ok_or_else(|| missing("noncefile"))?;
+                Box::new(transport::connect_nonce_tcp(host.as_ref().map(|h| &h[..]), port, family, noncefile)
+                    .map_err(inet_err)?)
+            }
+            _ => return Err(Error::Unsupported(format!("connecting to '{}' is not supported", addr))),
+        };
+
+        Ok(s)
     }
 
     /**
      * Create a new bus connection from an already openned & connected unix socket
+     *
+     * Authenticates to the server before returning, using `EXTERNAL` or (if that is rejected)
+     * `DBUS_COOKIE_SHA1`.
      */
     #[cfg(unix)]
-    pub fn open_unix(s: std::os::unix::net::UnixDatagram) -> Result<Bus, String> {
-        Ok(Bus { sock: s } )
+    pub fn open_unix(s: std::os::unix::net::UnixStream) -> Result<Bus, Error> {
+        Bus::open_stream(s)
+    }
+
+    /**
+     * Create a new bus connection from an already openned & connected tcp socket
+     *
+     * For a `nonce-tcp:` server, the nonce must already have been sent.
+     */
+    pub fn open_tcp(s: std::net::TcpStream) -> Result<Bus, Error> {
+        Bus::open_stream(s)
+    }
+
+    /**
+     * Create a new bus connection over any connected transport
+     *
+     * Authenticates as `open_unix()` does, then calls `Hello()` to obtain our unique name. Unix fd
+     * passing is only requested if the transport can carry fds.
+     */
+    pub fn open_stream<T: transport::Transport + Send + 'static>(s: T) -> Result<Bus, Error> {
+        let mut b = Bus::open_peer_stream(s)?;
+        b.hello()?;
+        Ok(b)
+    }
+
+    /**
+     * Create a peer to peer connection over any connected transport
+     *
+     * Authenticates as `open_stream()` does, but does not call `Hello()`.
+     */
+    pub fn open_peer_stream<T: transport::Transport + Send + 'static>(s: T) -> Result<Bus, Error> {
+        let mut s: Box<dyn transport::Transport + Send> = Box::new(s);
+        let mut mechs = vec![auth::Mechanism::external()];
+        mechs.extend(auth::Mechanism::cookie_sha1());
+        let unix_fd = s.supports_fds();
+        let a = auth::authenticate(&mut s, &mechs, unix_fd)?;
+        Ok(Bus {
+            reader: framing::MessageReader::new(s),
+            guid: a.guid,
+            unix_fd: a.unix_fd,
+            serial: 0,
+            unique_name: None,
+        })
+    }
+
+    /*
+     * Register with the bus. Until this is done the bus will not route any messages for us, so
+     * the reply is the first message we can receive.
+     */
+    fn hello(&mut self) -> Result<(), Error> {
+        let mut m = message::Message::method_call("/org/freedesktop/DBus", "Hello")?;
+        m.set_interface("org.freedesktop.DBus")?;
+        m.set_destination("org.freedesktop.DBus")?;
+        let serial = self.send(&m)?;
+
+        let r = loop {
+            let r = self.recv()?;
+            if r.reply_serial() == Some(serial) {
+                break r;
+            }
+        };
+
+        if r.typ() == header::TYPE_METHOD_ERROR {
+            return Err(remote_error(&r));
+        }
+
+        let name = body_string(&r)
+            .ok_or_else(|| Error::Protocol("Hello() reply does not hold a string".to_owned()))?;
+        let name = names::BusNameBuf::new(&name).map_err(|e| {
+            Error::Protocol(format!("Hello() returned an invalid unique name '{}': {}", name, e))
+        })?;
+        self.unique_name = Some(name);
+        Ok(())
+    }
+
+    /**
+     * The guid of the server we are connected to, as reported during authentication
+     */
+    pub fn server_guid(&self) -> &str {
+        &self.guid
+    }
+
+    /**
+     * The unique name the bus assigned us, or `None` for a peer to peer connection
+     */
+    pub fn unique_name(&self) -> Option<&names::BusName> {
+        self.unique_name.as_deref()
+    }
+
+    /**
+     * Whether unix fds (type 'h') may be sent & received on this connection
+     */
+    pub fn unix_fd(&self) -> bool {
+        self.unix_fd
+    }
+
+    /**
+     * Limit how long `recv()` waits for a message, after which it fails with `Error::Timeout`.
+     * `None` (the default) waits indefinitely.
+     *
+     * A message that is only partly received when the timeout expires is kept, and completed by
+     * the next `recv()`.
+     */
+    pub fn set_read_timeout(&mut self, timeout: Option<std::time::Duration>) -> Result<(), Error> {
+        self.reader.get_ref().set_read_timeout(timeout)
+            .map_err(|e| Error::io("set read timeout", e))
+    }
+
+    /**
+     * Send a message, returning the serial it was assigned
+     */
+    pub fn send(&mut self, msg: &message::Message) -> Result<u32, Error> {
+        use std::os::unix::io::AsFd;
+
+        if !msg.body().fds().is_empty() && !self.unix_fd {
+            return Err(Error::Unsupported("message contains unix fds, but fd passing was not negotiated"
+                                          .to_owned()));
+        }
+
+        self.serial = self.serial.wrapping_add(1);
+        if self.serial == 0 {
+            self.serial = 1;
+        }
+
+        let b = msg.encode(self.serial)?;
+        let fds: Vec<_> = msg.body().fds().iter().map(|f| f.as_fd()).collect();
+        transport::send_all(self.reader.get_mut(), &b, &fds)
+            .map_err(|e| Error::io("send message", e))?;
+        Ok(self.serial)
+    }
+
+    /**
+     * Receive the next message from the bus
+     */
+    pub fn recv(&mut self) -> Result<message::Message, Error> {
+        let b = self.read_message()?;
+
+        /* claim the message's fds first, so they are not left for the next one if it is invalid */
+        let n = message::Message::unix_fds_in(&b)? as usize;
+        let fds = if n != 0 {
+            let fds = self.reader.take_fds(n);
+            if !self.unix_fd {
+                return Err(Error::Protocol("received unix fds, but fd passing was not negotiated"
+                                           .to_owned()));
+            }
+            fds.ok_or_else(|| Error::Protocol(format!("message claims {} unix fds that were not received", n)))?
+        } else {
+            vec![]
+        };
+
+        let mut m = message::Message::decode(&b)?;
+        if !fds.is_empty() {
+            m.set_fds(fds);
+        }
+        Ok(m)
+    }
+
+    /**
+     * Read the next complete message from the bus, including its header
+     */
+    pub fn read_message(&mut self) -> Result<Vec<u8>, Error> {
+        match self.reader.read_message() {
+            Ok(Some(m)) => Ok(m),
+            Ok(None) => Err(Error::Closed),
+            Err(ref e) if e.kind() == std::io::ErrorKind::InvalidData => Err(Error::Protocol(e.to_string())),
+            Err(e) => Err(Error::io("read message", e)),
+        }
+    }
+}
+
+/*
+ * The error an ERROR message carries
+ */
+fn remote_error(m: &message::Message) -> Error {
+    let name = m.error_name().unwrap_or("org.freedesktop.DBus.Error.Failed");
+    Error::Remote(name.to_owned(), body_string(m))
+}
+
+/*
+ * The body of a message whose signature is exactly "s"
+ */
+fn body_string(m: &message::Message) -> Option<String> {
+    let mut i = m.body().iter();
+    let s = i.read::<String>().ok()?;
+    if i.is_empty() { Some(s) } else { None }
+}
+
+const SYSTEM_BUS_DEFAULT_ADDRESS: &str = "unix:path=/run/dbus/system_bus_socket";
+
+fn system_bus_address() -> String {
+    match std::env::var("DBUS_SYSTEM_BUS_ADDRESS") {
+        Ok(a) => a,
+        Err(_) => SYSTEM_BUS_DEFAULT_ADDRESS.to_owned(),
     }
 }
 
+/// The user bus address, if the environment tells us where one might be.
+fn user_bus_address() -> Option<String> {
+    if let Ok(a) = std::env::var("DBUS_SESSION_BUS_ADDRESS") {
+        return Some(a);
+    }
+
+    let dir = std::env::var_os("XDG_RUNTIME_DIR")?;
+    let path = std::path::Path::new(&dir).join("bus");
+    if path.exists() {
+        /* the path is escaped, as it may hold bytes that are special in an address */
+        Some(address::Address::new(address::Transport::UnixPath(path)).to_string())
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Bus, Error};
+    use address::Address;
+    use auth;
+    use framing::MessageReader;
+    use message::Message;
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn nonce_tcp_loopback() {
+        let l = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = l.local_addr().unwrap().port();
+        let path = ::std::env::temp_dir().join(format!("rdbus-test-{}-bus-nonce", ::std::process::id()));
+        fs::write(&path, b"fedcba9876543210").unwrap();
+
+        let t = thread::spawn(move || {
+            let (mut s, _) = l.accept().unwrap();
+            let mut nonce = [0u8; 16];
+            s.read_exact(&mut nonce).unwrap();
+            assert_eq!(&nonce, b"fedcba9876543210");
+            /* tcp carries no credentials, so vouch for the client as if it had some */
+            let server = auth::Server::new("0123456789abcdef0123456789abcdef".to_owned()).unix_fd(true);
+            server.accept(s, Some(auth::current_uid())).unwrap().unix_fd
+        });
+
+        let addr: Address = format!("nonce-tcp:host=127.0.0.1,port={},noncefile={}", port, path.display())
+            .parse().unwrap();
+        let bus = Bus::open_peer_address(&addr).unwrap();
+        assert_eq!(bus.server_guid(), "0123456789abcdef0123456789abcdef");
+        assert!(!bus.unix_fd());
+        assert!(bus.unique_name().is_none());
+        assert!(!t.join().unwrap());
+        fs::remove_file(&path).unwrap();
+
+        let addr: Address = "tcp:host=127.0.0.1".parse().unwrap();
+        match Bus::open_address(&addr) {
+            Err(Error::Unsupported(_)) => {}
+            _ => panic!(),
+        }
+    }
+
+    /* accept a client, answer its Hello() with `reply`, and hand back the connection */
+    fn fake_bus(s: UnixStream, reply: &'static str) -> MessageReader<UnixStream> {
+        let server = auth::Server::new("00112233445566778899aabbccddeeff".to_owned());
+        let mut r = MessageReader::new(server.accept_unix(s).unwrap().stream);
+        let call = Message::decode(&r.read_message().unwrap().unwrap()).unwrap();
+        assert_eq!(call.member(), Some("Hello"));
+        assert_eq!(call.interface(), Some("org.freedesktop.DBus"));
+        assert_eq!(call.destination(), Some("org.freedesktop.DBus"));
+
+        let mut m = Message::method_return(&call);
+        m.append(reply).unwrap();
+        r.get_mut().write_all(&m.encode(1).unwrap()).unwrap();
+        r
+    }
+
+    #[test]
+    fn hello() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let t = thread::spawn(move || fake_bus(b, ":1.42"));
+        let bus = Bus::open_unix(a).unwrap();
+        assert_eq!(bus.unique_name().unwrap().to_bytes(), b":1.42");
+        t.join().unwrap();
+
+        let (a, b) = UnixStream::pair().unwrap();
+        let t = thread::spawn(move || fake_bus(b, "not a name"));
+        match Bus::open_unix(a) {
+            Err(Error::Protocol(_)) => {}
+            Err(e) => panic!("{}", e),
+            Ok(_) => panic!(),
+        }
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn recv_fds() {
+        use std::os::unix::io::AsFd;
+        use transport;
+
+        let (a, b) = UnixStream::pair().unwrap();
+        let t = thread::spawn(move || {
+            let server = auth::Server::new("00112233445566778899aabbccddeeff".to_owned()).unix_fd(true);
+            let mut s = server.accept_unix(b).unwrap().stream;
+
+            /* a method return without REPLY_SERIAL, then a valid call, each with an fd */
+            let null = fs::File::open("/dev/null").unwrap();
+            let mut m = Message::method_call("/a", "Get").unwrap();
+            m.append(null.as_fd()).unwrap();
+            let mut bad = m.encode(1).unwrap();
+            bad[1] = 2;
+            transport::send_all(&mut s, &bad, &[null.as_fd()]).unwrap();
+
+            let manifest = fs::File::open(concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml")).unwrap();
+            let mut m = Message::method_call("/a", "Get").unwrap();
+            m.append(manifest.as_fd()).unwrap();
+            transport::send_all(&mut s, &m.encode(2).unwrap(), &[manifest.as_fd()]).unwrap();
+            s
+        });
+        let mut bus = Bus::open_peer_stream(a).unwrap();
+        assert!(bus.unix_fd());
+
+        match bus.recv() {
+            Err(Error::Protocol(_)) => {}
+            r => panic!("{:?}", r.err()),
+        }
+        let mut m = bus.recv().unwrap();
+        assert_eq!(m.serial(), 2);
+        let fds = m.take_fds();
+        assert_eq!(fds.len(), 1);
+        let f = fs::File::from(fds.into_iter().next().unwrap());
+        assert_ne!(f.metadata().unwrap().len(), 0);
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn timeout() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let t = thread::spawn(move || {
+            let server = auth::Server::new("00112233445566778899aabbccddeeff".to_owned());
+            server.accept_unix(b).unwrap().stream
+        });
+        let mut bus = Bus::open_peer_stream(a).unwrap();
+        let s = t.join().unwrap();
+
+        bus.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+        match bus.recv() {
+            Err(Error::Timeout) => {}
+            r => panic!("{:?}", r.err()),
+        }
+
+        drop(s);
+        match bus.recv() {
+            Err(Error::Closed) => {}
+            r => panic!("{:?}", r.err()),
+        }
+    }
+}
@@ -1,63 +1,382 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error;
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+use std::io;
+use std::os::unix::io::{AsFd, BorrowedFd, OwnedFd};
+
+use header::{self, Endian};
+use names::{ObjectPath, ObjectPathBuf};
+use type_sig::{Type, TypeBuf, TypeError, MAX_ARRAY_DEPTH, MAX_STRUCT_DEPTH};
+use value::Value;
+
+/// `#[derive(DBusType)]`, see the `rdbus-derive` crate for the options it takes
+#[cfg(feature = "derive")]
+pub use rdbus_derive::DBusType;
+
 #[derive(Debug)]
-enum EncodeError {
+pub enum EncodeError {
     TooLong,
+    /// A file descriptor could not be duplicated for inclusion in the message
+    Fd(io::Error),
+    /// A dynamically typed value does not form a valid D-Bus value
+    Invalid(&'static str),
+    /// An array is longer than `MAX_ARRAY_LEN` bytes
+    ArrayTooLong,
+    /// A message is longer than `MAX_MESSAGE_LEN` bytes
+    MessageTooLong,
+    /// A signature nests arrays more than `type_sig::MAX_ARRAY_DEPTH` deep
+    ArrayTooDeep,
+    /// A signature nests structs and dict entries more than `type_sig::MAX_STRUCT_DEPTH` deep
+    StructTooDeep,
+    /// Containers, including variants, are nested more than `MAX_DEPTH` deep
+    TooDeep,
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EncodeError::TooLong => write!(fmt, "value is too long to marshal"),
+            EncodeError::Fd(ref e) => write!(fmt, "could not duplicate unix fd: {}", e),
+            EncodeError::Invalid(e) => write!(fmt, "invalid value: {}", e),
+            EncodeError::ArrayTooLong => write!(fmt, "array is longer than {} bytes", MAX_ARRAY_LEN),
+            EncodeError::MessageTooLong => write!(fmt, "message is longer than {} bytes", MAX_MESSAGE_LEN),
+            EncodeError::ArrayTooDeep => write!(fmt, "arrays are nested more than {} deep", MAX_ARRAY_DEPTH),
+            EncodeError::StructTooDeep => write!(fmt, "structs are nested more than {} deep", MAX_STRUCT_DEPTH),
+            EncodeError::TooDeep => write!(fmt, "containers are nested more than {} deep", MAX_DEPTH),
+        }
+    }
+}
+
+impl error::Error for EncodeError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            EncodeError::Fd(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Offsets are in bytes from the start of the data being decoded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The signature has a different type at this point than the one asked for. `found` is empty
+    /// if the signature has ended.
+    WrongType { offset: usize, expected: String, found: String },
+    /// The data ends part way through a value
+    Truncated { offset: usize },
+    /// Alignment padding contains something other than zeros
+    NonZeroPadding { offset: usize },
+    /// The bytes do not form a valid value of the type
+    Invalid { offset: usize, reason: &'static str },
+    /// An array is longer than `MAX_ARRAY_LEN` bytes
+    ArrayTooLong { offset: usize },
+    /// A message of `len` bytes is longer than `MAX_MESSAGE_LEN`
+    MessageTooLong { len: usize },
+    /// A signature nests arrays more than `type_sig::MAX_ARRAY_DEPTH` deep
+    ArrayTooDeep { offset: usize },
+    /// A signature nests structs and dict entries more than `type_sig::MAX_STRUCT_DEPTH` deep
+    StructTooDeep { offset: usize },
+    /// Containers, including variants, are nested more than `MAX_DEPTH` deep
+    TooDeep { offset: usize },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecodeError::WrongType { offset, ref expected, ref found } => {
+                write!(fmt, "expected type '{}' at offset {}, but ", expected, offset)?;
+                if found.is_empty() {
+                    write!(fmt, "the signature has ended")
+                } else {
+                    write!(fmt, "the signature has '{}'", found)
+                }
+            }
+            DecodeError::Truncated { offset } => write!(fmt, "data ends within a value at offset {}", offset),
+            DecodeError::NonZeroPadding { offset } => write!(fmt, "non-zero padding at offset {}", offset),
+            DecodeError::Invalid { offset, reason } => {
+                write!(fmt, "invalid value at offset {}: {}", offset, reason)
+            }
+            DecodeError::ArrayTooLong { offset } => {
+                write!(fmt, "array at offset {} is longer than {} bytes", offset, MAX_ARRAY_LEN)
+            }
+            DecodeError::MessageTooLong { len } => {
+                write!(fmt, "message of {} bytes is longer than {} bytes", len, MAX_MESSAGE_LEN)
+            }
+            DecodeError::ArrayTooDeep { offset } => {
+                write!(fmt, "signature at offset {} nests arrays more than {} deep", offset, MAX_ARRAY_DEPTH)
+            }
+            DecodeError::StructTooDeep { offset } => {
+                write!(fmt, "signature at offset {} nests structs more than {} deep", offset, MAX_STRUCT_DEPTH)
+            }
+            DecodeError::TooDeep { offset } => {
+                write!(fmt, "containers are nested more than {} deep at offset {}", MAX_DEPTH, offset)
+            }
+        }
+    }
 }
 
-trait DBusType {
+impl error::Error for DecodeError {}
+
+/// Maximum length of an array in bytes, from the dbus spec
+pub const MAX_ARRAY_LEN: usize = 1 << 26;
+
+/// Maximum length of an entire message, from the dbus spec
+pub const MAX_MESSAGE_LEN: usize = 1 << 27;
+
+/// Containers (arrays, structs, dict entries and variants) may be nested at most this deep, from
+/// the dbus spec
+pub const MAX_DEPTH: usize = 64;
+
+/*
+ * A signature which is needed to encode a value is not valid. Exceeding the nesting limits has its
+ * own error, otherwise `reason` says which signature it was.
+ */
+pub(crate) fn encode_type_error(e: TypeError, reason: &'static str) -> EncodeError {
+    match e {
+        TypeError::ArrayTooDeep => EncodeError::ArrayTooDeep,
+        TypeError::StructTooDeep => EncodeError::StructTooDeep,
+        _ => EncodeError::Invalid(reason),
+    }
+}
+
+/*
+ * A signature found at `offset` while decoding is not valid
+ */
+pub(crate) fn decode_type_error(e: TypeError, offset: usize) -> DecodeError {
+    match e {
+        TypeError::ArrayTooDeep => DecodeError::ArrayTooDeep { offset },
+        TypeError::StructTooDeep => DecodeError::StructTooDeep { offset },
+        _ => DecodeError::Invalid { offset, reason: "signature is not valid" },
+    }
+}
+
+pub trait DBusType {
+    /// Append the signature of the type to `sig`
+    fn signature(sig: &mut Vec<u8>);
+
     /*
-     * - use little endian if you are supplying bytes directly (this should only be needed for the
-     *   basic types)
+     * - if you are supplying bytes directly, write them big endian if `msg.is_big()` and little
+     *   endian otherwise (this should only be needed for the basic types)
      * - failures (potentially) occur due to violating message rules, like depth
      */
-    fn encode_into(&self, &mut Data) -> Result<(),EncodeError>;
+    fn encode_into(&self, msg: &mut Data) -> Result<(),EncodeError>;
+}
 
-    /*
-    fn decode_from(&self, &mut DataIter) -> Result<(),DecodeError>
-    */
+/**
+ * Types that can be decoded without borrowing from the data, into a value which owns what it holds
+ *
+ * Borrowed types (`&str`, `&ObjectPath`, `Type`, `&[T]` and the like) do not implement this, as
+ * their lifetime cannot be tied to the data here. They decode through `DecodeBorrowed`.
+ */
+pub trait DecodeOwned: DBusType + Sized {
+    /**
+     * Decode a value from the current position of `iter`.
+     *
+     * The caller is responsible for checking that the signature calls for this type here, which
+     * `DataIter::read()` does. Containers decode their elements by calling this directly.
+     */
+    fn decode_from(iter: &mut DataIter) -> Result<Self, DecodeError>;
+}
+
+/**
+ * Types whose signature is a single basic type code, which are the only types that may be the key
+ * of a dict entry
+ */
+pub trait BasicType: DBusType {}
+
+/**
+ * Types that can be decoded from a `DataIter<'a>` without copying, by borrowing from the data it
+ * decodes for `'a`
+ *
+ * `&str`, `&ObjectPath`, `Type` and `&[u8]` borrow directly. Arrays of other fixed size numbers
+ * borrow as `&[T]` only if the data is in native byte order and suitably aligned in memory, and
+ * `Cow<[T]>` falls back to copying when they are not. Owned types decode as `decode_from()` does,
+ * and containers of borrowing types borrow their elements.
+ */
+pub trait DecodeBorrowed<'a>: DBusType + Sized {
+    /// Decode a value from the current position of `iter`, as `DecodeOwned::decode_from()` does
+    fn decode_borrowed(iter: &mut DataIter<'a>) -> Result<Self, DecodeError>;
+}
+
+/*
+ * Types which own what they decode borrow nothing
+ */
+macro_rules! decode_owned {
+    ($($t:ty),+) => {
+        $(
+            impl<'a> DecodeBorrowed<'a> for $t {
+                fn decode_borrowed(iter: &mut DataIter<'a>) -> Result<Self, DecodeError> {
+                    Self::decode_from(iter)
+                }
+            }
+        )+
+    }
+}
+
+decode_owned!(u8, i16, u16, i32, u32, i64, u64, f64, bool, String, ObjectPathBuf, TypeBuf, OwnedFd, Value);
+
+/*
+ * The alignment of values whose signature begins with `code`
+ */
+pub(crate) fn alignment(code: u8) -> usize {
+    match code {
+        b'y' | b'g' | b'v' => 1,
+        b'n' | b'q' => 2,
+        b'b' | b'i' | b'u' | b'h' | b's' | b'o' | b'a' => 4,
+        _ => 8,
+    }
 }
 
-fn try_cast(v: usize) -> Result<u32, EncodeError>
+/*
+ * The length of the single complete type at the start of `sig`, or `None` if `sig` is empty or
+ * does not begin with a complete type.
+ */
+pub(crate) fn single_type_len(sig: &[u8]) -> Option<usize> {
+    match *sig.first()? {
+        b'a' | b'm' => single_type_len(&sig[1..]).map(|l| l + 1),
+        open @ b'(' | open @ b'{' => {
+            let close = if open == b'(' { b')' } else { b'}' };
+            let mut l = 1;
+            while *sig.get(l)? != close {
+                l += single_type_len(&sig[l..])?;
+            }
+            Some(l + 1)
+        }
+        b')' | b'}' => None,
+        _ => Some(1),
+    }
+}
+
+pub(crate) fn try_cast(v: usize) -> Result<u32, EncodeError>
 {
-    if v > (::std::u32::MAX as usize) {
+    if v > (u32::MAX as usize) {
         Err(EncodeError::TooLong)
     } else {
         Ok(v as u32)
     }
 }
 
-impl<'a, T: DBusType + ?Sized> DBusType for &'a T {
+impl<T: BasicType + ?Sized> BasicType for &T {}
+
+impl<T: DBusType + ?Sized> DBusType for &T {
+    fn signature(sig: &mut Vec<u8>) {
+        T::signature(sig)
+    }
+
     fn encode_into(&self, msg: &mut Data) -> Result<(), EncodeError>
     {
         (*self).encode_into(msg)
     }
 }
 
-impl DBusType for u32 {
-    fn encode_into(&self, msg: &mut Data) -> Result<(), EncodeError>
-    {
-        let i = *self;
-        let v = [i as u8, (i >> 8) as u8, (i >> 16) as u8, (i >> 24) as u8];
-        unsafe {msg.align_to(v.len());}
-        msg.data.extend(&v);
-        Ok(())
+/*
+ * Fixed size numbers, which are aligned to their own size
+ */
+macro_rules! fixed_type {
+    ($t:ty, $code:expr) => {
+        impl DBusType for $t {
+            fn signature(sig: &mut Vec<u8>) {
+                sig.push($code);
+            }
+
+            fn encode_into(&self, msg: &mut Data) -> Result<(), EncodeError>
+            {
+                let v = if msg.is_big() { self.to_be_bytes() } else { self.to_le_bytes() };
+                unsafe {msg.align_to(v.len());}
+                msg.data.extend(&v);
+                Ok(())
+            }
+        }
+
+        impl DecodeOwned for $t {
+            fn decode_from(iter: &mut DataIter) -> Result<Self, DecodeError> {
+                let mut v = [0u8; ::std::mem::size_of::<$t>()];
+                let len = v.len();
+                iter.align(len)?;
+                let big = iter.big;
+                v.copy_from_slice(iter.take(len)?);
+                Ok(if big { <$t>::from_be_bytes(v) } else { <$t>::from_le_bytes(v) })
+            }
+        }
+
+        impl BasicType for $t {}
+
+        impl<'a> DecodeBorrowed<'a> for &'a [$t] {
+            fn decode_borrowed(iter: &mut DataIter<'a>) -> Result<Self, DecodeError> {
+                let offset = iter.pos;
+                match decode_fixed_array(iter)? {
+                    Cow::Borrowed(v) => Ok(v),
+                    Cow::Owned(_) => Err(DecodeError::Invalid { offset,
+                        reason: "array is not in native byte order or is not aligned, decode it as a Cow" }),
+                }
+            }
+        }
+
+        impl<'a> DecodeBorrowed<'a> for Cow<'a, [$t]> {
+            fn decode_borrowed(iter: &mut DataIter<'a>) -> Result<Self, DecodeError> {
+                decode_fixed_array(iter)
+            }
+        }
     }
 }
 
-impl DBusType for u64 {
-    fn encode_into(&self, msg: &mut Data) -> Result<(), EncodeError>
-    {
-        let i = *self;
-        let v = [
-            i as u8, (i >> 8) as u8, (i >> 16) as u8, (i >> 24) as u8,
-            (i >> 32) as u8, (i >> 40) as u8, (i >> 48) as u8, (i >> 56) as u8
-        ];
-        unsafe {msg.align_to(v.len());}
-        msg.data.extend(&v);
-        Ok(())
+/*
+ * Borrow an array of the fixed size numbers `T` if it is in native byte order and its elements are
+ * aligned in memory, otherwise copy the elements out. Byte order does not matter for single bytes.
+ *
+ * Only the numbers of `fixed_type!` may be used, as any bytes are a valid value of them.
+ */
+fn decode_fixed_array<'a, T: DecodeOwned + Clone>(iter: &mut DataIter<'a>) -> Result<Cow<'a, [T]>, DecodeError> {
+    let size = ::std::mem::size_of::<T>();
+    let b = fixed_array_bytes(iter, size)?;
+    let len = b.len();
+
+    let (head, v, tail) = unsafe { b.align_to::<T>() };
+    if (size == 1 || iter.big == cfg!(target_endian = "big")) && head.is_empty() && tail.is_empty() {
+        return Ok(Cow::Borrowed(v));
+    }
+    let mut elements = DataIter { data: b, sig: &[], fds: &[], big: iter.big, pos: 0, sig_pos: 0, depth: 0 };
+    (0..len / size).map(|_| T::decode_from(&mut elements)).collect::<Result<Vec<_>, _>>().map(Cow::Owned)
+}
+
+/*
+ * The bytes of an array of fixed size numbers which are `size` bytes long
+ */
+fn fixed_array_bytes<'a>(iter: &mut DataIter<'a>, size: usize) -> Result<&'a [u8], DecodeError> {
+    iter.align(4)?;
+    let len_pos = iter.pos;
+    let len = u32::decode_from(iter)? as usize;
+    if len > MAX_ARRAY_LEN {
+        return Err(DecodeError::ArrayTooLong { offset: len_pos });
     }
+    /* there are no containers within, so only check that this one may be entered */
+    iter.nested(|_| Ok(()))?;
+    iter.align(size)?;
+    let offset = iter.pos;
+    if len % size != 0 {
+        return Err(DecodeError::Invalid { offset, reason: "array elements overrun its length" });
+    }
+    iter.take(len)
 }
 
+fixed_type!(u8, b'y');
+fixed_type!(i16, b'n');
+fixed_type!(u16, b'q');
+fixed_type!(i32, b'i');
+fixed_type!(u32, b'u');
+fixed_type!(i64, b'x');
+fixed_type!(u64, b't');
+fixed_type!(f64, b'd');
+
+impl BasicType for bool {}
+
 impl DBusType for bool {
+    fn signature(sig: &mut Vec<u8>) {
+        sig.push(b'b');
+    }
+
     fn encode_into(&self, msg: &mut Data) -> Result<(), EncodeError>
     {
         let v = if *self { 1u32 } else { 0u32 };
@@ -65,46 +384,849 @@ impl DBusType for bool {
     }
 }
 
+impl DecodeOwned for bool {
+    fn decode_from(iter: &mut DataIter) -> Result<Self, DecodeError> {
+        iter.align(4)?;
+        let offset = iter.pos;
+        match u32::decode_from(iter)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(DecodeError::Invalid { offset, reason: "boolean is neither 0 nor 1" }),
+        }
+    }
+}
+
+impl BasicType for str {}
+
 impl DBusType for str {
+    fn signature(sig: &mut Vec<u8>) {
+        sig.push(b's');
+    }
+
     fn encode_into(&self, msg: &mut Data) -> Result<(), EncodeError>
     {
-        try!(try!(try_cast(self.len())).encode_into(msg));
+        try_cast(self.len())?.encode_into(msg)?;
         msg.data.extend(self.as_bytes());
         msg.data.push(0);
         Ok(())
     }
 }
 
+impl BasicType for String {}
+
+impl DBusType for String {
+    fn signature(sig: &mut Vec<u8>) {
+        str::signature(sig)
+    }
+
+    fn encode_into(&self, msg: &mut Data) -> Result<(), EncodeError>
+    {
+        self[..].encode_into(msg)
+    }
+}
+
+impl DecodeOwned for String {
+    fn decode_from(iter: &mut DataIter) -> Result<Self, DecodeError> {
+        <&str>::decode_borrowed(iter).map(|s| s.to_owned())
+    }
+}
+
+impl<'a> DecodeBorrowed<'a> for &'a str {
+    fn decode_borrowed(iter: &mut DataIter<'a>) -> Result<Self, DecodeError> {
+        let len = u32::decode_from(iter)? as usize;
+        decode_str(iter, len)
+    }
+}
+
+/*
+ * Decode the body of a string-like type, returning the bytes without the nul
+ */
+pub(crate) fn decode_str<'a>(iter: &mut DataIter<'a>, len: usize) -> Result<&'a str, DecodeError> {
+    let offset = iter.pos;
+    let b = iter.take(len)?;
+    if iter.take(1)? != [0] {
+        return Err(DecodeError::Invalid { offset: offset + len, reason: "string is not nul terminated" });
+    }
+    if b.contains(&0) {
+        return Err(DecodeError::Invalid { offset, reason: "string contains a nul byte" });
+    }
+    ::std::str::from_utf8(b).map_err(|_| DecodeError::Invalid { offset, reason: "string is not valid UTF-8" })
+}
+
+impl BasicType for ObjectPath {}
+
+impl DBusType for ObjectPath {
+    fn signature(sig: &mut Vec<u8>) {
+        sig.push(b'o');
+    }
+
+    fn encode_into(&self, msg: &mut Data) -> Result<(), EncodeError>
+    {
+        /* validation only allows ascii */
+        unsafe { ::std::str::from_utf8_unchecked(self.to_bytes()) }.encode_into(msg)
+    }
+}
+
+/// Borrows the nul terminator along with the path, as `ObjectPath` holds one
+impl<'a> DecodeBorrowed<'a> for &'a ObjectPath {
+    fn decode_borrowed(iter: &mut DataIter<'a>) -> Result<Self, DecodeError> {
+        let len = u32::decode_from(iter)? as usize;
+        let offset = iter.pos;
+        decode_str(iter, len)?;
+        ObjectPath::from_bytes(&iter.data[offset..offset + len + 1])
+            .map_err(|_| DecodeError::Invalid { offset, reason: "object path is not valid" })
+    }
+}
+
+impl BasicType for ObjectPathBuf {}
+
+impl DBusType for ObjectPathBuf {
+    fn signature(sig: &mut Vec<u8>) {
+        ObjectPath::signature(sig)
+    }
+
+    fn encode_into(&self, msg: &mut Data) -> Result<(), EncodeError>
+    {
+        (**self).encode_into(msg)
+    }
+}
+
+impl DecodeOwned for ObjectPathBuf {
+    fn decode_from(iter: &mut DataIter) -> Result<Self, DecodeError> {
+        let len = u32::decode_from(iter)? as usize;
+        let offset = iter.pos;
+        let s = decode_str(iter, len)?;
+        ObjectPathBuf::new(s).map_err(|_| DecodeError::Invalid { offset, reason: "object path is not valid" })
+    }
+}
+
+impl<'a> BasicType for Type<'a> {}
+
+/// Signatures (type 'g') are limited to 255 bytes
+impl<'a> DBusType for Type<'a> {
+    fn signature(sig: &mut Vec<u8>) {
+        sig.push(b'g');
+    }
+
+    fn encode_into(&self, msg: &mut Data) -> Result<(), EncodeError>
+    {
+        let s: &str = self.as_ref();
+        if s.len() > 255 {
+            return Err(EncodeError::TooLong);
+        }
+        msg.data.push(s.len() as u8);
+        msg.data.extend(s.as_bytes());
+        msg.data.push(0);
+        Ok(())
+    }
+}
+
+impl<'a> DecodeBorrowed<'a> for Type<'a> {
+    fn decode_borrowed(iter: &mut DataIter<'a>) -> Result<Self, DecodeError> {
+        let len = u8::decode_from(iter)? as usize;
+        let offset = iter.pos;
+        let s = decode_str(iter, len)?;
+        Type::from_str(s).map_err(|e| decode_type_error(e, offset))
+    }
+}
+
+impl BasicType for TypeBuf {}
+
+impl DBusType for TypeBuf {
+    fn signature(sig: &mut Vec<u8>) {
+        sig.push(b'g');
+    }
+
+    fn encode_into(&self, msg: &mut Data) -> Result<(), EncodeError>
+    {
+        self.as_type().encode_into(msg)
+    }
+}
+
+impl DecodeOwned for TypeBuf {
+    fn decode_from(iter: &mut DataIter) -> Result<Self, DecodeError> {
+        let len = u8::decode_from(iter)? as usize;
+        let offset = iter.pos;
+        let s = decode_str(iter, len)?;
+        TypeBuf::from_string(s.to_owned()).map_err(|e| decode_type_error(e, offset))
+    }
+}
+
 impl<T: DBusType> DBusType for [T]  {
+    fn signature(sig: &mut Vec<u8>) {
+        sig.push(b'a');
+        T::signature(sig);
+    }
+
+    /// The length is of the marshalled elements, which do not include the padding between the
+    /// length and the first element. That padding is present even if there are no elements.
+    fn encode_into(&self, msg: &mut Data) -> Result<(), EncodeError>
+    {
+        let mut sig = vec![];
+        T::signature(&mut sig);
+        encode_array(msg, alignment(sig[0]), |msg| {
+            for e in self.iter() {
+                e.encode_into(msg)?;
+            }
+            Ok(())
+        })
+    }
+}
+
+/**
+ * Encode an array whose elements are aligned to `align`, with `f` encoding the elements
+ *
+ * This writes the length, and the padding before the first element, for types which implement
+ * `DBusType` by hand.
+ */
+pub fn encode_array<F>(msg: &mut Data, align: usize, f: F) -> Result<(), EncodeError>
+    where F: FnOnce(&mut Data) -> Result<(), EncodeError>
+{
+    msg.nested(|msg| {
+        0u32.encode_into(msg)?;
+        let len_pos = msg.data.len() - 4;
+        unsafe {msg.align_to(align);}
+
+        let start = msg.data.len();
+        f(msg)?;
+        let len = msg.data.len() - start;
+        if len > MAX_ARRAY_LEN {
+            return Err(EncodeError::ArrayTooLong);
+        }
+        msg.patch_u32(len_pos, len as u32);
+        Ok(())
+    })
+}
+
+/**
+ * Decode an array whose elements are aligned to `align`, calling `f` to decode each element
+ *
+ * `f` is called until the elements it has decoded fill the length of the array.
+ */
+pub fn decode_array<'a, F>(iter: &mut DataIter<'a>, align: usize, mut f: F) -> Result<(), DecodeError>
+    where F: FnMut(&mut DataIter<'a>) -> Result<(), DecodeError>
+{
+    iter.align(4)?;
+    let len_pos = iter.pos;
+    let len = u32::decode_from(iter)? as usize;
+    if len > MAX_ARRAY_LEN {
+        return Err(DecodeError::ArrayTooLong { offset: len_pos });
+    }
+
+    iter.nested(|iter| {
+        /* the padding before the first element is present even if there are no elements */
+        iter.align(align)?;
+
+        let start = iter.pos;
+        if iter.data.len() - start < len {
+            return Err(DecodeError::Truncated { offset: iter.data.len() });
+        }
+
+        while iter.pos < start + len {
+            f(iter)?;
+        }
+        if iter.pos != start + len {
+            return Err(DecodeError::Invalid { offset: start, reason: "array elements overrun its length" });
+        }
+        Ok(())
+    })
+}
+
+/**
+ * Encode a struct or dict entry, with `f` encoding the fields
+ *
+ * This pads to the 8 byte boundary they start on, for types which implement `DBusType` by hand.
+ */
+pub fn encode_struct<F>(msg: &mut Data, f: F) -> Result<(), EncodeError>
+    where F: FnOnce(&mut Data) -> Result<(), EncodeError>
+{
+    msg.nested(|msg| {
+        unsafe {msg.align_to(8);}
+        f(msg)
+    })
+}
+
+/**
+ * Decode a struct or dict entry, with `f` decoding the fields
+ */
+pub fn decode_struct<'a, T, F>(iter: &mut DataIter<'a>, f: F) -> Result<T, DecodeError>
+    where F: FnOnce(&mut DataIter<'a>) -> Result<T, DecodeError>
+{
+    iter.nested(|iter| {
+        iter.align(8)?;
+        f(iter)
+    })
+}
+
+impl<T: DBusType> DBusType for Vec<T> {
+    fn signature(sig: &mut Vec<u8>) {
+        <[T]>::signature(sig)
+    }
+
+    fn encode_into(&self, msg: &mut Data) -> Result<(), EncodeError>
+    {
+        self[..].encode_into(msg)
+    }
+}
+
+impl<T: DecodeOwned> DecodeOwned for Vec<T> {
+    fn decode_from(iter: &mut DataIter) -> Result<Self, DecodeError> {
+        decode_vec(iter, T::decode_from)
+    }
+}
+
+impl<'a, T: DecodeBorrowed<'a>> DecodeBorrowed<'a> for Vec<T> {
+    fn decode_borrowed(iter: &mut DataIter<'a>) -> Result<Self, DecodeError> {
+        decode_vec(iter, T::decode_borrowed)
+    }
+}
+
+fn decode_vec<'a, T, F>(iter: &mut DataIter<'a>, mut f: F) -> Result<Vec<T>, DecodeError>
+    where T: DBusType, F: FnMut(&mut DataIter<'a>) -> Result<T, DecodeError>
+{
+    let mut sig = vec![];
+    T::signature(&mut sig);
+    let mut v = vec![];
+    decode_array(iter, alignment(sig[0]), |iter| {
+        v.push(f(iter)?);
+        Ok(())
+    })?;
+    Ok(v)
+}
+
+/// Arrays decode as `Cow::Owned`, and `DecodeBorrowed` borrows arrays of fixed size numbers when it
+/// can
+impl<'b, T: DBusType + Clone> DBusType for Cow<'b, [T]> {
+    fn signature(sig: &mut Vec<u8>) {
+        <[T]>::signature(sig)
+    }
+
+    fn encode_into(&self, msg: &mut Data) -> Result<(), EncodeError>
+    {
+        (**self).encode_into(msg)
+    }
+}
+
+impl<'b, T: DecodeOwned + Clone> DecodeOwned for Cow<'b, [T]> {
+    fn decode_from(iter: &mut DataIter) -> Result<Self, DecodeError> {
+        Vec::decode_from(iter).map(Cow::Owned)
+    }
+}
+
+/*
+ * Dicts are arrays of dict entries ('{kv}'), which are aligned to 8 bytes like structs
+ */
+fn dict_signature<K: BasicType, V: DBusType>(sig: &mut Vec<u8>) {
+    sig.extend(b"a{");
+    K::signature(sig);
+    V::signature(sig);
+    sig.push(b'}');
+}
+
+fn encode_dict<'b, K, V, I>(msg: &mut Data, entries: I) -> Result<(), EncodeError>
+    where K: BasicType + 'b, V: DBusType + 'b, I: Iterator<Item=(&'b K, &'b V)>
+{
+    encode_array(msg, 8, |msg| {
+        for (k, v) in entries {
+            encode_struct(msg, |msg| {
+                k.encode_into(msg)?;
+                v.encode_into(msg)
+            })?;
+        }
+        Ok(())
+    })
+}
+
+/*
+ * Calls `insert` with each entry, which `entry` decodes. `insert` returns false if the key was
+ * already present.
+ */
+fn decode_dict<'a, K, V, D, F>(iter: &mut DataIter<'a>, mut entry: D, mut insert: F) -> Result<(), DecodeError>
+    where D: FnMut(&mut DataIter<'a>) -> Result<(K, V), DecodeError>, F: FnMut(K, V) -> bool
+{
+    decode_array(iter, 8, |iter| {
+        iter.align(8)?;
+        let offset = iter.pos;
+        let (k, v) = decode_struct(iter, &mut entry)?;
+        if !insert(k, v) {
+            return Err(DecodeError::Invalid { offset, reason: "dict has a duplicate key" });
+        }
+        Ok(())
+    })
+}
+
+/// Dicts (type 'a{kv}') are encoded in iteration order. Decoding rejects dicts that repeat a key.
+impl<K, V, S> DBusType for HashMap<K, V, S>
+    where K: BasicType + Eq + Hash, V: DBusType, S: BuildHasher + Default
+{
+    fn signature(sig: &mut Vec<u8>) {
+        dict_signature::<K, V>(sig)
+    }
+
+    fn encode_into(&self, msg: &mut Data) -> Result<(), EncodeError>
+    {
+        encode_dict(msg, self.iter())
+    }
+}
+
+impl<K, V, S> DecodeOwned for HashMap<K, V, S>
+    where K: BasicType + DecodeOwned + Eq + Hash, V: DecodeOwned, S: BuildHasher + Default
+{
+    fn decode_from(iter: &mut DataIter) -> Result<Self, DecodeError> {
+        let mut m = HashMap::default();
+        decode_dict(iter, |i| Ok((K::decode_from(i)?, V::decode_from(i)?)), |k, v| m.insert(k, v).is_none())?;
+        Ok(m)
+    }
+}
+
+impl<'a, K, V, S> DecodeBorrowed<'a> for HashMap<K, V, S>
+    where K: BasicType + DecodeBorrowed<'a> + Eq + Hash, V: DecodeBorrowed<'a>, S: BuildHasher + Default
+{
+    fn decode_borrowed(iter: &mut DataIter<'a>) -> Result<Self, DecodeError> {
+        let mut m = HashMap::default();
+        decode_dict(iter, |i| Ok((K::decode_borrowed(i)?, V::decode_borrowed(i)?)), |k, v| m.insert(k, v).is_none())?;
+        Ok(m)
+    }
+}
+
+/// Dicts (type 'a{kv}') are encoded in key order. Decoding rejects dicts that repeat a key.
+impl<K, V> DBusType for BTreeMap<K, V>
+    where K: BasicType + Ord, V: DBusType
+{
+    fn signature(sig: &mut Vec<u8>) {
+        dict_signature::<K, V>(sig)
+    }
+
+    fn encode_into(&self, msg: &mut Data) -> Result<(), EncodeError>
+    {
+        encode_dict(msg, self.iter())
+    }
+}
+
+impl<K, V> DecodeOwned for BTreeMap<K, V>
+    where K: BasicType + DecodeOwned + Ord, V: DecodeOwned
+{
+    fn decode_from(iter: &mut DataIter) -> Result<Self, DecodeError> {
+        let mut m = BTreeMap::new();
+        decode_dict(iter, |i| Ok((K::decode_from(i)?, V::decode_from(i)?)), |k, v| m.insert(k, v).is_none())?;
+        Ok(m)
+    }
+}
+
+impl<'a, K, V> DecodeBorrowed<'a> for BTreeMap<K, V>
+    where K: BasicType + DecodeBorrowed<'a> + Ord, V: DecodeBorrowed<'a>
+{
+    fn decode_borrowed(iter: &mut DataIter<'a>) -> Result<Self, DecodeError> {
+        let mut m = BTreeMap::new();
+        decode_dict(iter, |i| Ok((K::decode_borrowed(i)?, V::decode_borrowed(i)?)), |k, v| m.insert(k, v).is_none())?;
+        Ok(m)
+    }
+}
+
+/**
+ * A value marshalled as a variant (type 'v'): the signature of `T`, followed by the value
+ *
+ * Decoding fails unless the variant holds a `T`. `value::Value` decodes variants of any type.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Variant<T>(pub T);
+
+impl<T: DBusType> DBusType for Variant<T> {
+    fn signature(sig: &mut Vec<u8>) {
+        sig.push(b'v');
+    }
+
+    fn encode_into(&self, msg: &mut Data) -> Result<(), EncodeError>
+    {
+        let mut sig = vec![];
+        T::signature(&mut sig);
+        let sig = String::from_utf8(sig).map_err(|_| EncodeError::Invalid("signature is not valid"))?;
+        let sig = Type::from_str(&sig).map_err(|e| encode_type_error(e, "signature is not valid"))?;
+        msg.nested(|msg| {
+            sig.encode_into(msg)?;
+            self.0.encode_into(msg)
+        })
+    }
+}
+
+impl<T: DecodeOwned> DecodeOwned for Variant<T> {
+    fn decode_from(iter: &mut DataIter) -> Result<Self, DecodeError> {
+        iter.nested(|iter| {
+            variant_type::<T>(iter)?;
+            T::decode_from(iter).map(Variant)
+        })
+    }
+}
+
+impl<'a, T: DecodeBorrowed<'a>> DecodeBorrowed<'a> for Variant<T> {
+    fn decode_borrowed(iter: &mut DataIter<'a>) -> Result<Self, DecodeError> {
+        iter.nested(|iter| {
+            variant_type::<T>(iter)?;
+            T::decode_borrowed(iter).map(Variant)
+        })
+    }
+}
+
+/*
+ * Decode the signature of a variant, which must be that of `T`
+ */
+fn variant_type<T: DBusType>(iter: &mut DataIter) -> Result<(), DecodeError> {
+    let offset = iter.pos;
+    let found = Type::decode_borrowed(iter)?;
+    let mut expected = vec![];
+    T::signature(&mut expected);
+    if found.as_ref().as_bytes() != &expected[..] {
+        return Err(DecodeError::WrongType {
+            offset,
+            expected: String::from_utf8_lossy(&expected).into_owned(),
+            found: found.as_ref().to_owned(),
+        });
+    }
+    Ok(())
+}
+
+/*
+ * Tuples are structs, which are aligned to 8 bytes. Each field is then aligned as usual.
+ */
+macro_rules! tuple_type {
+    ($($n:tt $t:ident),+) => {
+        impl<$($t: DBusType),+> DBusType for ($($t,)+) {
+            fn signature(sig: &mut Vec<u8>) {
+                sig.push(b'(');
+                $($t::signature(sig);)+
+                sig.push(b')');
+            }
+
+            fn encode_into(&self, msg: &mut Data) -> Result<(), EncodeError>
+            {
+                encode_struct(msg, |msg| {
+                    $(self.$n.encode_into(msg)?;)+
+                    Ok(())
+                })
+            }
+        }
+
+        impl<$($t: DecodeOwned),+> DecodeOwned for ($($t,)+) {
+            fn decode_from(iter: &mut DataIter) -> Result<Self, DecodeError> {
+                decode_struct(iter, |iter| Ok(($($t::decode_from(iter)?,)+)))
+            }
+        }
+
+        impl<'a, $($t: DecodeBorrowed<'a>),+> DecodeBorrowed<'a> for ($($t,)+) {
+            fn decode_borrowed(iter: &mut DataIter<'a>) -> Result<Self, DecodeError> {
+                decode_struct(iter, |iter| Ok(($($t::decode_borrowed(iter)?,)+)))
+            }
+        }
+    }
+}
+
+tuple_type!(0 A);
+tuple_type!(0 A, 1 B);
+tuple_type!(0 A, 1 B, 2 C);
+tuple_type!(0 A, 1 B, 2 C, 3 D);
+tuple_type!(0 A, 1 B, 2 C, 3 D, 4 E);
+tuple_type!(0 A, 1 B, 2 C, 3 D, 4 E, 5 F);
+tuple_type!(0 A, 1 B, 2 C, 3 D, 4 E, 5 F, 6 G);
+tuple_type!(0 A, 1 B, 2 C, 3 D, 4 E, 5 F, 6 G, 7 H);
+tuple_type!(0 A, 1 B, 2 C, 3 D, 4 E, 5 F, 6 G, 7 H, 8 I);
+tuple_type!(0 A, 1 B, 2 C, 3 D, 4 E, 5 F, 6 G, 7 H, 8 I, 9 J);
+tuple_type!(0 A, 1 B, 2 C, 3 D, 4 E, 5 F, 6 G, 7 H, 8 I, 9 J, 10 K);
+tuple_type!(0 A, 1 B, 2 C, 3 D, 4 E, 5 F, 6 G, 7 H, 8 I, 9 J, 10 K, 11 L);
+
+impl<'a> BasicType for BorrowedFd<'a> {}
+
+/// File descriptors (type 'h') are duplicated into the message's fd table, and the body holds their
+/// index in that table.
+impl<'a> DBusType for BorrowedFd<'a> {
+    fn signature(sig: &mut Vec<u8>) {
+        sig.push(b'h');
+    }
+
+    fn encode_into(&self, msg: &mut Data) -> Result<(), EncodeError>
+    {
+        let fd = self.try_clone_to_owned().map_err(EncodeError::Fd)?;
+        let idx = try_cast(msg.fds.len())?;
+        idx.encode_into(msg)?;
+        msg.fds.push(fd);
+        Ok(())
+    }
+}
+
+/// Decoding duplicates the fd, leaving the one held by the data in place
+impl BasicType for OwnedFd {}
+
+impl DBusType for OwnedFd {
+    fn signature(sig: &mut Vec<u8>) {
+        sig.push(b'h');
+    }
+
     fn encode_into(&self, msg: &mut Data) -> Result<(), EncodeError>
     {
-        try!(try!(try_cast(::std::mem::size_of_val(self))).encode_into(msg));
-        for e in self.iter() {
-            try!(e.encode_into(msg));
+        self.as_fd().encode_into(msg)
+    }
+}
+
+impl DecodeOwned for OwnedFd {
+    fn decode_from(iter: &mut DataIter) -> Result<Self, DecodeError> {
+        iter.align(4)?;
+        let offset = iter.pos;
+        let idx = u32::decode_from(iter)? as usize;
+        let fd = iter.fds.get(idx)
+            .ok_or(DecodeError::Invalid { offset, reason: "unix fd index is out of range" })?;
+        fd.try_clone().map_err(|_| DecodeError::Invalid { offset, reason: "unix fd could not be duplicated" })
+    }
+}
+
+/*
+// conflicts with the &'a T impl
+impl<T: DBusType, I: Iterator<Item=T> + ExactSizeIterator + Clone> DBusType for I  {
+    fn encode_into(&self, msg: &mut Data) -> Result<(), EncodeError>
+    {
+        try!(msg.append(try!(try_cast(self.len()))));
+        let mut i = self.clone();
+        for e in i {
+            try!(msg.append(e));
+        }
+        Ok(())
+    }
+}
+*/
+
+/**
+ * Check that `data` holds values with the signature `sig` in the byte order `endian`, without
+ * decoding them.
+ *
+ * This checks everything decoding would: padding is zero, strings are UTF-8 and nul terminated,
+ * object paths and signatures are valid, booleans are 0 or 1, array lengths match their
+ * elements, no dict repeats a key, and the limits on nesting and length are kept. The data must hold nothing after the
+ * last value. Unix fd indexes are not checked against the fds the data came with.
+ */
+pub fn validate(data: &[u8], sig: Type, endian: Endian) -> Result<(), DecodeError> {
+    let sig = sig.as_ref().as_bytes();
+    let mut iter = DataIter::new(data, sig, &[], endian);
+    validate_fields(&mut iter, sig)?;
+    if iter.pos != data.len() {
+        return Err(DecodeError::Invalid { offset: iter.pos, reason: "data continues after the last value" });
+    }
+    Ok(())
+}
+
+/*
+ * Check the values of each complete type in `sig`, in turn
+ */
+fn validate_fields(iter: &mut DataIter, mut sig: &[u8]) -> Result<(), DecodeError> {
+    while !sig.is_empty() {
+        let l = single_type_len(sig)
+            .ok_or(DecodeError::Invalid { offset: iter.pos, reason: "signature is not valid" })?;
+        validate_value(iter, &sig[..l])?;
+        sig = &sig[l..];
+    }
+    Ok(())
+}
+
+/*
+ * Check the value of the single complete type `sig`
+ */
+fn validate_value(iter: &mut DataIter, sig: &[u8]) -> Result<(), DecodeError> {
+    let offset = iter.pos;
+    match sig[0] {
+        b'y' => { u8::decode_from(iter)?; }
+        b'b' => { bool::decode_from(iter)?; }
+        b'n' | b'q' => { u16::decode_from(iter)?; }
+        b'i' | b'u' | b'h' => { u32::decode_from(iter)?; }
+        b'x' | b't' | b'd' => { u64::decode_from(iter)?; }
+        b's' => { <&str>::decode_borrowed(iter)?; }
+        b'o' => { <&ObjectPath>::decode_borrowed(iter)?; }
+        b'g' => { Type::decode_borrowed(iter)?; }
+        b'v' => iter.nested(|iter| {
+            let t = Type::decode_borrowed(iter)?;
+            let t = t.as_ref().as_bytes();
+            if single_type_len(t) != Some(t.len()) {
+                return Err(DecodeError::Invalid { offset, reason: "variant signature is not a single complete type" });
+            }
+            validate_value(iter, t)
+        })?,
+        /* any bytes are a valid array of numbers, so only its length needs checking */
+        b'a' if b"ynqiuxtdh".contains(&sig[1]) => { fixed_array_bytes(iter, alignment(sig[1]))?; }
+        /* keys are basic types, so equal keys are marshalled as the same bytes */
+        b'a' if sig[1] == b'{' => {
+            let data = iter.data;
+            let mut keys = HashSet::new();
+            decode_array(iter, 8, |iter| {
+                iter.align(8)?;
+                let entry = iter.pos;
+                decode_struct(iter, |iter| {
+                    validate_value(iter, &sig[2..3])?;
+                    if !keys.insert(&data[entry..iter.pos]) {
+                        return Err(DecodeError::Invalid { offset: entry, reason: "dict has a duplicate key" });
+                    }
+                    validate_value(iter, &sig[3..sig.len() - 1])
+                })
+            })?
+        }
+        b'a' => decode_array(iter, alignment(sig[1]), |iter| validate_value(iter, &sig[1..]))?,
+        b'(' => decode_struct(iter, |iter| validate_fields(iter, &sig[1..sig.len() - 1]))?,
+        _ => return Err(DecodeError::Invalid { offset, reason: "signature is not valid" }),
+    }
+    Ok(())
+}
+
+/**
+ * A cursor for decoding values from marshalled data
+ *
+ * Each `read()` checks the requested type against the next complete type in the signature, and
+ * then decodes it.
+ */
+pub struct DataIter<'a> {
+    pub(crate) data: &'a [u8],
+    sig: &'a [u8],
+    fds: &'a [OwnedFd],
+    big: bool,
+    pub(crate) pos: usize,
+    pub(crate) sig_pos: usize,
+    /// The number of containers being decoded
+    depth: usize,
+}
+
+impl<'a> DataIter<'a> {
+    /**
+     * Decode `data`, which holds values with the signature `sig` in the byte order `endian`.
+     * `fds` are the unix fds that values of type 'h' refer to.
+     *
+     * `data` must start at an 8 byte boundary of the message it came from, as a message body does.
+     */
+    pub fn new(data: &'a [u8], sig: &'a [u8], fds: &'a [OwnedFd], endian: Endian) -> DataIter<'a> {
+        DataIter { data, sig, fds, big: endian == header::ENDIAN_BIG, pos: 0, sig_pos: 0, depth: 0 }
+    }
+
+    /// The offset of the next byte to be decoded
+    pub fn offset(&self) -> usize {
+        self.pos
+    }
+
+    /// The part of the signature not yet decoded
+    pub fn signature(&self) -> &'a [u8] {
+        &self.sig[self.sig_pos..]
+    }
+
+    /// Whether every value in the signature has been decoded
+    pub fn is_empty(&self) -> bool {
+        self.sig_pos == self.sig.len()
+    }
+
+    /**
+     * Decode the next value, which must have type `T`
+     */
+    pub fn read<T: DecodeOwned>(&mut self) -> Result<T, DecodeError> {
+        self.next_type::<T>()?;
+        T::decode_from(self)
+    }
+
+    /**
+     * Decode the next value, which must have type `T`, borrowing from the data where `T` can. See
+     * `DecodeBorrowed`.
+     */
+    pub fn read_borrowed<T: DecodeBorrowed<'a>>(&mut self) -> Result<T, DecodeError> {
+        self.next_type::<T>()?;
+        T::decode_borrowed(self)
+    }
+
+    /*
+     * Check that the next type in the signature is that of `T`, and move past it
+     */
+    fn next_type<T: DBusType>(&mut self) -> Result<(), DecodeError> {
+        let mut expected = vec![];
+        T::signature(&mut expected);
+        let rest = self.signature();
+        let found = match single_type_len(rest) {
+            Some(l) => &rest[..l],
+            None => rest,
+        };
+        if found != &expected[..] {
+            return Err(DecodeError::WrongType {
+                offset: self.pos,
+                expected: String::from_utf8_lossy(&expected).into_owned(),
+                found: String::from_utf8_lossy(found).into_owned(),
+            });
+        }
+
+        self.sig_pos += found.len();
+        Ok(())
+    }
+
+    /**
+     * Decode the next value, whatever its type
+     */
+    pub fn read_value(&mut self) -> Result<Value, DecodeError> {
+        let rest = self.signature();
+        let l = single_type_len(rest)
+            .ok_or(DecodeError::Invalid { offset: self.pos, reason: "signature does not begin with a complete type" })?;
+        self.sig_pos += l;
+        Value::decode_contents(self, &rest[..l])
+    }
+
+    /*
+     * Enter a container, failing if that nests containers more than `MAX_DEPTH` deep. `leave()`
+     * must be called once it has been decoded.
+     */
+    pub(crate) fn enter(&mut self) -> Result<(), DecodeError> {
+        if self.depth == MAX_DEPTH {
+            return Err(DecodeError::TooDeep { offset: self.pos });
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    pub(crate) fn leave(&mut self) {
+        self.depth -= 1;
+    }
+
+    /*
+     * Decode a container with `f`
+     */
+    pub(crate) fn nested<T, F>(&mut self, f: F) -> Result<T, DecodeError>
+        where F: FnOnce(&mut DataIter<'a>) -> Result<T, DecodeError>
+    {
+        self.enter()?;
+        let r = f(self);
+        self.leave();
+        r
+    }
+
+    /**
+     * Consume `n` bytes
+     */
+    pub fn take(&mut self, n: usize) -> Result<&'a [u8], DecodeError> {
+        if self.data.len() - self.pos < n {
+            return Err(DecodeError::Truncated { offset: self.data.len() });
         }
-        Ok(())
+        let b = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(b)
     }
-}
 
-/*
-// conflicts with the &'a T impl
-impl<T: DBusType, I: Iterator<Item=T> + ExactSizeIterator + Clone> DBusType for I  {
-    fn encode_into(&self, msg: &mut Data) -> Result<(), EncodeError>
-    {
-        try!(msg.append(try!(try_cast(self.len()))));
-        let mut i = self.clone();
-        for e in i {
-            try!(msg.append(e));
+    /**
+     * Skip the padding before a value aligned to `n` bytes, checking that it is all zero
+     */
+    pub fn align(&mut self, n: usize) -> Result<(), DecodeError> {
+        let offset = self.pos;
+        let pad = (n - self.pos % n) % n;
+        if self.take(pad)?.iter().any(|&b| b != 0) {
+            return Err(DecodeError::NonZeroPadding { offset });
         }
         Ok(())
     }
 }
-*/
 
 /// Stores marshalled data and the signature that corresponds to it
 pub struct Data {
-    data: Vec<u8>,
-    sig: Vec<u8>
+    pub(crate) data: Vec<u8>,
+    pub(crate) sig: Vec<u8>,
+    /// unix fds refered to by index from the data
+    pub(crate) fds: Vec<OwnedFd>,
+    endian: Endian,
+    /// The number of containers being encoded
+    pub(crate) depth: usize,
 }
 
 impl ::std::default::Default for Data {
@@ -114,9 +1236,61 @@ impl ::std::default::Default for Data {
 }
 
 impl Data {
-    /// Create a new, completely empty, marshalled data container
+    /// Create a new, completely empty, marshalled data container. Values are marshalled little
+    /// endian.
     pub fn new() -> Data {
-        Data { data: vec![], sig: vec![] }
+        Data::with_endian(header::ENDIAN_LITTLE)
+    }
+
+    /// Create an empty container which marshals values in the byte order `endian`
+    pub fn with_endian(endian: Endian) -> Data {
+        Data { data: vec![], sig: vec![], fds: vec![], endian, depth: 0 }
+    }
+
+    /// Wrap already marshalled data (such as a received message body)
+    pub fn from_parts(data: Vec<u8>, sig: Vec<u8>, fds: Vec<OwnedFd>, endian: Endian) -> Data {
+        Data { data, sig, fds, endian, depth: 0 }
+    }
+
+    /// The byte order of the marshalled data
+    pub fn endian(&self) -> Endian {
+        self.endian
+    }
+
+    /// Whether the marshalled data is big endian
+    pub fn is_big(&self) -> bool {
+        self.endian == header::ENDIAN_BIG
+    }
+
+    /// The marshalled bytes
+    pub fn bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// The signature of the marshalled data
+    pub fn signature_bytes(&self) -> &[u8] {
+        &self.sig
+    }
+
+    /**
+     * The signature of the marshalled data, as the `SIGNATURE` header field of a message holds it.
+     *
+     * Fails if the signature was supplied to `from_parts()` and is not valid.
+     */
+    pub fn signature(&self) -> Result<Type<'_>, TypeError> {
+        let s = ::std::str::from_utf8(&self.sig)
+            .map_err(|_| TypeError::Invalid(::std::char::REPLACEMENT_CHARACTER))?;
+        Type::from_str(s)
+    }
+
+    /// The unix fds refered to by the data, in index order
+    pub fn fds(&self) -> &[OwnedFd] {
+        &self.fds
+    }
+
+    /// Remove the unix fds from the data, leaving the indexes that refered to them dangling
+    pub fn take_fds(&mut self) -> Vec<OwnedFd> {
+        ::std::mem::take(&mut self.fds)
     }
 
     /**
@@ -132,24 +1306,93 @@ impl Data {
      *
      *  - allows us to break the dbus message format requirements wrt padding
      */
-    unsafe fn align_to(&mut self, align: usize)
+    pub(crate) unsafe fn align_to(&mut self, align: usize)
     {
-        let rem = self.data.len() % align;
-        for _ in 0..rem {
+        let pad = (align - self.data.len() % align) % align;
+        for _ in 0..pad {
             self.data.push(0);
         }
     }
 
-    fn append<T: DBusType>(&mut self, value: T) -> Result<(), EncodeError>
+    /**
+     * Overwrite the u32 at `pos`, such as the length of an array once its elements are known
+     */
+    pub(crate) fn patch_u32(&mut self, pos: usize, v: u32) {
+        let v = if self.is_big() { v.to_be_bytes() } else { v.to_le_bytes() };
+        self.data[pos..pos + 4].copy_from_slice(&v);
+    }
+
+    /*
+     * Enter a container, failing if that nests containers more than `MAX_DEPTH` deep. `leave()`
+     * must be called once it has been encoded.
+     */
+    pub(crate) fn enter(&mut self) -> Result<(), EncodeError> {
+        if self.depth == MAX_DEPTH {
+            return Err(EncodeError::TooDeep);
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    pub(crate) fn leave(&mut self) {
+        self.depth -= 1;
+    }
+
+    /*
+     * Encode a container with `f`
+     */
+    pub(crate) fn nested<F>(&mut self, f: F) -> Result<(), EncodeError>
+        where F: FnOnce(&mut Data) -> Result<(), EncodeError>
+    {
+        self.enter()?;
+        let r = f(self);
+        self.leave();
+        r
+    }
+
+    /**
+     * Marshal `value`, and append its type to the signature.
+     *
+     * If this fails the data and signature are left as they were.
+     */
+    pub fn append<T: DBusType>(&mut self, value: T) -> Result<(), EncodeError>
     {
-        value.encode_into(self)
+        /* nesting is limited by the signature, so check it before anything is encoded */
+        let mut sig = vec![];
+        T::signature(&mut sig);
+        let s = ::std::str::from_utf8(&sig).map_err(|_| EncodeError::Invalid("signature is not valid"))?;
+        Type::from_str(s).map_err(|e| encode_type_error(e, "signature is not valid"))?;
+
+        let (len, fds) = (self.data.len(), self.fds.len());
+        if let Err(e) = value.encode_into(self) {
+            self.data.truncate(len);
+            self.fds.truncate(fds);
+            return Err(e);
+        }
+        self.sig.extend(sig);
+        Ok(())
+    }
+
+    /**
+     * Check that the data holds values of its signature without decoding them, as `validate()`
+     * does
+     */
+    pub fn validate(&self) -> Result<(), DecodeError> {
+        let sig = self.signature().map_err(|e| decode_type_error(e, 0))?;
+        validate(&self.data, sig, self.endian)
+    }
+
+    /// Decode the values held by the data
+    pub fn iter(&self) -> DataIter<'_> {
+        DataIter::new(&self.data, &self.sig, &self.fds, self.endian)
     }
 }
 
 
 #[cfg(test)]
 mod test {
-    use super::Data;
+    use super::{Data, DataIter, DecodeError};
+    use header::{ENDIAN_BIG, ENDIAN_LITTLE as LE};
 
     #[test]
     fn append()
@@ -161,6 +1404,108 @@ mod test {
         assert_eq!(m.data, [24,0,0,0,1,0,0,0]);
     }
 
+    #[test]
+    fn signature() {
+        use std::collections::BTreeMap;
+        use value::Value;
+
+        let mut m = Data::new();
+        assert_eq!(m.signature().unwrap().as_ref(), "");
+        m.append(1u8).unwrap();
+        m.append(&[(2u32, "a")][..]).unwrap();
+        m.append(BTreeMap::<String, Value>::new()).unwrap();
+        m.append(Value::Byte(3)).unwrap();
+        assert_eq!(m.signature().unwrap().as_ref(), "ya(us)a{sv}v");
+
+        let mut i = m.iter();
+        assert_eq!(i.read::<u8>().unwrap(), 1);
+        assert_eq!(i.read::<Vec<(u32, String)>>().unwrap(), [(2, "a".to_owned())]);
+        assert_eq!(i.read::<BTreeMap<String, Value>>().unwrap(), BTreeMap::new());
+        assert_eq!(i.read::<Value>().unwrap(), Value::Byte(3));
+        assert!(i.is_empty());
+
+        /* the second element fails after the first has been written */
+        let len = m.bytes().len();
+        let bad = Value::Array {
+            element: ::type_sig::TypeBuf::from_string("u".to_owned()).unwrap(),
+            values: vec![Value::UInt32(1), Value::Byte(2)],
+        };
+        m.append(&bad).err().unwrap();
+        assert_eq!(m.bytes().len(), len);
+        assert_eq!(m.signature_bytes(), b"ya(us)a{sv}v");
+
+        let d = Data::from_parts(vec![], b"a{vs}".to_vec(), vec![], LE);
+        d.signature().err().unwrap();
+    }
+
+    #[test]
+    fn borrowed() {
+        use std::borrow::Cow;
+        use names::{ObjectPath, ObjectPathBuf};
+        use type_sig::Type;
+
+        let mut m = Data::new();
+        m.append("hello").unwrap();
+        m.append(&[1u8, 2, 3][..]).unwrap();
+        m.append(&[4u32, 5][..]).unwrap();
+        m.append(ObjectPathBuf::new("/a/b").unwrap()).unwrap();
+        m.append(Type::from_str("a{sv}").unwrap()).unwrap();
+        m.append(vec![("x", 6u64)]).unwrap();
+
+        let mut i = m.iter();
+        let s: &str = i.read_borrowed().unwrap();
+        assert_eq!(s, "hello");
+        assert_eq!(s.as_ptr(), m.bytes()[4..].as_ptr());
+        let b: &[u8] = i.read_borrowed().unwrap();
+        assert_eq!(b, [1, 2, 3]);
+        assert_eq!(b.as_ptr(), m.bytes()[16..].as_ptr());
+        let u: Cow<[u32]> = i.read_borrowed().unwrap();
+        assert_eq!(*u, [4, 5]);
+        assert_eq!(matches!(u, Cow::Borrowed(_)), m.bytes().as_ptr() as usize % 4 == 0);
+        let p: &ObjectPath = i.read_borrowed().unwrap();
+        assert_eq!(p.to_bytes(), b"/a/b");
+        let t: Type = i.read_borrowed().unwrap();
+        assert_eq!(t.as_ref(), "a{sv}");
+        assert_eq!(i.read_borrowed::<Vec<(&str, u64)>>().unwrap(), [("x", 6)]);
+        assert!(i.is_empty());
+
+        /* arrays in the other byte order are copied */
+        let other = if cfg!(target_endian = "big") { LE } else { ENDIAN_BIG };
+        let mut m = Data::with_endian(other);
+        m.append(&[4u32, 5][..]).unwrap();
+        match m.iter().read_borrowed::<Cow<[u32]>>().unwrap() {
+            Cow::Owned(v) => assert_eq!(v, [4, 5]),
+            Cow::Borrowed(_) => panic!("array was borrowed"),
+        }
+        assert_eq!(m.iter().read_borrowed::<&[u32]>(), Err(DecodeError::Invalid { offset: 0,
+            reason: "array is not in native byte order or is not aligned, decode it as a Cow" }));
+        assert_eq!(m.iter().read::<Cow<[u32]>>().unwrap(), Cow::Borrowed(&[4u32, 5][..]));
+
+        /* but bytes are the same in either byte order, so are always borrowed */
+        for &endian in &[LE, ENDIAN_BIG] {
+            let mut m = Data::with_endian(endian);
+            m.append(&[1u8, 2, 3][..]).unwrap();
+            let b: &[u8] = m.iter().read_borrowed().unwrap();
+            assert_eq!(b, [1, 2, 3]);
+            assert_eq!(b.as_ptr(), m.bytes()[4..].as_ptr());
+        }
+    }
+
+    #[test]
+    fn unix_fd() {
+        use std::os::unix::io::AsFd;
+
+        let f = ::std::fs::File::open("/dev/null").unwrap();
+        let mut m = Data::new();
+        m.append(true).unwrap();
+        m.append(f.as_fd()).unwrap();
+        m.append(f.as_fd()).unwrap();
+        assert_eq!(m.data, [1,0,0,0, 0,0,0,0, 1,0,0,0]);
+        assert_eq!(m.fds().len(), 2);
+        assert_eq!(m.take_fds().len(), 2);
+        assert_eq!(m.fds().len(), 0);
+    }
+
     #[test]
     fn align_to() {
         let mut m = Data::new();
@@ -175,6 +1520,12 @@ mod test {
         assert_eq!(m.data, [2, 0]);
         unsafe { m.align_to(2); }
         assert_eq!(m.data, [2, 0]);
+        m.data.push(3);
+        unsafe { m.align_to(8); }
+        assert_eq!(m.data, [2, 0, 3, 0, 0, 0, 0, 0]);
+        m.data.push(4);
+        unsafe { m.align_to(4); }
+        assert_eq!(m.data.len(), 12);
     }
 
     #[test]
@@ -201,4 +1552,562 @@ mod test {
         ]);
     }
 
+    fn data(d: &Data, sig: &[u8]) -> Data {
+        Data::from_parts(d.data.clone(), sig.to_vec(), vec![], d.endian)
+    }
+
+    #[test]
+    fn decode() {
+        let mut m = Data::new();
+        m.append(true).unwrap();
+        m.append("foo").unwrap();
+        m.append(7u64).unwrap();
+        m.append(&[1u32, 2, 3][..]).unwrap();
+        m.append(String::from("bar")).unwrap();
+        let d = data(&m, b"bstauss");
+
+        let mut i = d.iter();
+        assert!(i.read::<bool>().unwrap());
+        assert_eq!(i.read::<String>().unwrap(), "foo");
+        assert_eq!(i.read::<u64>().unwrap(), 7);
+        assert_eq!(i.read::<Vec<u32>>().unwrap(), [1, 2, 3]);
+        assert_eq!(i.read::<String>().unwrap(), "bar");
+        assert!(!i.is_empty());
+        assert_eq!(i.read::<String>(), Err(DecodeError::Truncated { offset: d.data.len() }));
+    }
+
+    #[test]
+    fn decode_wrong_type() {
+        let mut m = Data::new();
+        m.append(1u32).unwrap();
+        m.append(&[5u64][..]).unwrap();
+        let d = data(&m, b"uat");
+
+        let mut i = d.iter();
+        assert_eq!(i.read::<String>(), Err(DecodeError::WrongType {
+            offset: 0, expected: "s".to_owned(), found: "u".to_owned() }));
+        assert_eq!(i.read::<u32>().unwrap(), 1);
+        assert_eq!(i.read::<Vec<u32>>(), Err(DecodeError::WrongType {
+            offset: 4, expected: "au".to_owned(), found: "at".to_owned() }));
+        assert_eq!(i.read::<Vec<u64>>().unwrap(), [5]);
+        assert!(i.is_empty());
+        assert_eq!(i.read::<u32>(), Err(DecodeError::WrongType {
+            offset: 16, expected: "u".to_owned(), found: "".to_owned() }));
+        assert_eq!(i.read_borrowed::<&str>(), Err(DecodeError::WrongType {
+            offset: 16, expected: "s".to_owned(), found: "".to_owned() }));
+    }
+
+    #[test]
+    fn decode_invalid() {
+        let mut i = DataIter::new(&[2, 0, 0, 0], b"b", &[], LE);
+        assert_eq!(i.read::<bool>(), Err(DecodeError::Invalid {
+            offset: 0, reason: "boolean is neither 0 nor 1" }));
+
+        let mut i = DataIter::new(&[1, 0, 0, 0, 1, 1, 0, 0, 5, 0, 0, 0], b"bu", &[], LE);
+        assert!(i.read::<bool>().unwrap());
+        let mut i = DataIter::new(&[1, 0, 0, 0, 0, 0, 0, 1, 5, 0, 0, 0, 0, 0, 0, 0], b"bt", &[], LE);
+        i.read::<bool>().unwrap();
+        assert_eq!(i.read::<u64>(), Err(DecodeError::NonZeroPadding { offset: 4 }));
+
+        let mut i = DataIter::new(&[3, 0, 0, 0, b'f', b'o', b'o', b'!'], b"s", &[], LE);
+        assert_eq!(i.read::<String>(), Err(DecodeError::Invalid {
+            offset: 7, reason: "string is not nul terminated" }));
+        let mut i = DataIter::new(&[3, 0, 0, 0, b'f', 0, b'o', 0], b"s", &[], LE);
+        assert_eq!(i.read::<String>(), Err(DecodeError::Invalid {
+            offset: 4, reason: "string contains a nul byte" }));
+        let mut i = DataIter::new(&[2, 0, 0, 0, 0xc3, 0x28, 0], b"s", &[], LE);
+        assert_eq!(i.read::<String>(), Err(DecodeError::Invalid {
+            offset: 4, reason: "string is not valid UTF-8" }));
+
+        /* an array whose length cuts its last element in half */
+        let mut i = DataIter::new(&[6, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0], b"au", &[], LE);
+        assert_eq!(i.read::<Vec<u32>>(), Err(DecodeError::Invalid {
+            offset: 4, reason: "array elements overrun its length" }));
+        let mut i = DataIter::new(&[16, 0, 0, 0, 1, 0, 0, 0], b"au", &[], LE);
+        assert_eq!(i.read::<Vec<u32>>(), Err(DecodeError::Truncated { offset: 8 }));
+
+        /* empty arrays are still padded to their element alignment */
+        let mut i = DataIter::new(&[0, 0, 0, 0, 0, 0, 0, 0, 9, 0, 0, 0], b"atu", &[], LE);
+        assert_eq!(i.read::<Vec<u64>>().unwrap(), []);
+        assert_eq!(i.read::<u32>().unwrap(), 9);
+    }
+
+    #[test]
+    fn limits() {
+        use super::{encode_array, EncodeError, MAX_ARRAY_LEN};
+        use value::Value;
+
+        /* a variant holds a variant at each level, so 64 of them are as deep as may be nested */
+        let mut v = Value::Byte(5);
+        for _ in 0..63 {
+            v = Value::Variant(Box::new(v));
+        }
+        let mut m = Data::new();
+        m.append(&v).unwrap();
+        assert_eq!(m.iter().read::<Value>().unwrap(), v);
+        match m.append(Value::Variant(Box::new(v))) {
+            Err(EncodeError::TooDeep) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+
+        let mut data = b"\x01v\0".repeat(64);
+        data.extend_from_slice(b"\x01y\0\x05");
+        assert_eq!(DataIter::new(&data, b"v", &[], LE).read::<Value>(), Err(DecodeError::TooDeep { offset: 192 }));
+        let mut i = DataIter::new(&data[3..], b"v", &[], LE);
+        i.read::<Value>().unwrap();
+        assert!(i.is_empty());
+
+
+        /* the signatures of arrays and structs limit how deeply they nest */
+        let sig = format!("{}y", "a".repeat(33));
+        let mut data = vec![sig.len() as u8];
+        data.extend_from_slice(sig.as_bytes());
+        data.push(0);
+        assert_eq!(DataIter::new(&data, b"v", &[], LE).read::<Value>(), Err(DecodeError::ArrayTooDeep { offset: 1 }));
+        let mut v = Value::Byte(1);
+        for _ in 0..33 {
+            v = Value::Struct(vec![v]);
+        }
+        match Data::new().append(v) {
+            Err(EncodeError::StructTooDeep) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+
+        /* typed values are checked against their signature before any of them is encoded */
+        macro_rules! nest {
+            ($wrap:ident, $v:expr;) => { $v };
+            ($wrap:ident, $v:expr; $x:tt $($rest:tt)*) => { nest!($wrap, $wrap!($v); $($rest)*) };
+        }
+        macro_rules! one {
+            ($v:expr) => { ($v,) };
+        }
+        let structs = nest!(one, 1u8; x x x x x x x x x x x x x x x x x x x x x x x x x x x x x x x x);
+        let arrays = nest!(vec, 1u8; x x x x x x x x x x x x x x x x x x x x x x x x x x x x x x x x);
+        let mut m = Data::new();
+        m.append(structs).unwrap();
+        m.append(&arrays).unwrap();
+        let (bytes, sig) = (m.bytes().to_vec(), m.signature_bytes().to_vec());
+        match m.append((&structs,)) {
+            Err(EncodeError::StructTooDeep) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+        match m.append(vec![&arrays]) {
+            Err(EncodeError::ArrayTooDeep) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+        assert_eq!((m.bytes(), m.signature_bytes()), (&bytes[..], &sig[..]));
+
+        /* arrays are at most 64 MiB */
+        let long = [1, 0, 0, 4, 0, 0, 0, 0];
+        assert_eq!(DataIter::new(&long, b"ay", &[], LE).read_borrowed::<&[u8]>(),
+                   Err(DecodeError::ArrayTooLong { offset: 0 }));
+        assert_eq!(DataIter::new(&long, b"as", &[], LE).read::<Vec<String>>(),
+                   Err(DecodeError::ArrayTooLong { offset: 0 }));
+        let mut m = Data::new();
+        let r = encode_array(&mut m, 1, |m| {
+            m.data.resize(m.data.len() + MAX_ARRAY_LEN + 1, 0);
+            Ok(())
+        });
+        match r {
+            Err(EncodeError::ArrayTooLong) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+
+    #[test]
+    fn validate() {
+        use super::validate;
+        use type_sig::Type;
+        use value::Value;
+
+        let mut m = Data::with_endian(ENDIAN_BIG);
+        m.append(true).unwrap();
+        m.append((1u8, "ab", ::names::ObjectPathBuf::new("/a").unwrap())).unwrap();
+        m.append(vec![vec![1u16], vec![]]).unwrap();
+        m.append(&[true, false][..]).unwrap();
+        m.append(Value::Variant(Box::new(Value::Signature(::type_sig::TypeBuf::from_string("a{sv}".to_owned()).unwrap())))).unwrap();
+        let mut d = ::std::collections::BTreeMap::new();
+        d.insert("k", super::Variant(5u32));
+        m.append(&d).unwrap();
+        m.validate().unwrap();
+
+        let check = |data: &[u8], sig: &str| validate(data, Type::from_str(sig).unwrap(), LE);
+        check(&[], "").unwrap();
+        assert_eq!(check(&[2, 0, 0, 0], "b"), Err(DecodeError::Invalid {
+            offset: 0, reason: "boolean is neither 0 nor 1" }));
+        assert_eq!(check(&[1, 0, 0, 0, 0xff, 0], "s"), Err(DecodeError::Invalid {
+            offset: 4, reason: "string is not valid UTF-8" }));
+        assert_eq!(check(&[1, 0, 0, 0, b'a', b'b'], "s"), Err(DecodeError::Invalid {
+            offset: 5, reason: "string is not nul terminated" }));
+        check(&[2, 0, 0, 0, b'/', b'a', 0], "o").unwrap();
+        check(&[2, 0, 0, 0, b'a', b'/', 0], "o").err().unwrap();
+        check(&[2, b'a', b'(', 0], "g").err().unwrap();
+        assert_eq!(check(&[1, 1, 0, 0, 0, 0, 0, 0], "yt"), Err(DecodeError::NonZeroPadding { offset: 1 }));
+        assert_eq!(check(&[6, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0], "au"), Err(DecodeError::Invalid {
+            offset: 4, reason: "array elements overrun its length" }));
+        assert_eq!(check(&[8, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0], "ab"), Err(DecodeError::Invalid {
+            offset: 8, reason: "boolean is neither 0 nor 1" }));
+        assert_eq!(check(&[2, b'y', b'y', 0, 1, 2], "v"), Err(DecodeError::Invalid {
+            offset: 0, reason: "variant signature is not a single complete type" }));
+        assert_eq!(check(&[5, 0, 0, 0], "y"), Err(DecodeError::Invalid {
+            offset: 1, reason: "data continues after the last value" }));
+        assert_eq!(check(&[1, 0, 0, 0], "uu"), Err(DecodeError::Truncated { offset: 4 }));
+
+        /* an array of structs is marshalled as a dict would be, but may repeat its first field */
+        let mut m = Data::new();
+        m.append(vec![(1u32, 2u32), (3, 2)]).unwrap();
+        check(m.bytes(), "a{uu}").unwrap();
+        let mut m = Data::new();
+        m.append(vec![(1u32, 2u32), (1, 3)]).unwrap();
+        let duplicate = Err(DecodeError::Invalid { offset: 16, reason: "dict has a duplicate key" });
+        assert_eq!(check(m.bytes(), "a{uu}"), duplicate);
+        let d = Data::from_parts(m.bytes().to_vec(), b"a{uu}".to_vec(), vec![], LE);
+        assert_eq!(d.iter().read::<::std::collections::BTreeMap<u32, u32>>().map(|_| ()), duplicate);
+
+        let mut data = b"\x01v\0".repeat(64);
+        data.extend_from_slice(b"\x01y\0\x05");
+        assert_eq!(check(&data, "v"), Err(DecodeError::TooDeep { offset: 192 }));
+        check(&data[3..], "v").unwrap();
+    }
+
+    #[test]
+    fn decode_unix_fd() {
+        use std::io::{Seek, SeekFrom};
+        use std::os::unix::io::{AsFd, OwnedFd};
+
+        let f = ::std::fs::File::open("/dev/null").unwrap();
+        let mut m = Data::new();
+        m.append(f.as_fd()).unwrap();
+        m.append(3u32).unwrap();
+        let d = Data::from_parts(m.data.clone(), b"hh".to_vec(), m.take_fds(), LE);
+
+        let mut i = d.iter();
+        let mut dup = ::std::fs::File::from(i.read::<OwnedFd>().unwrap());
+        dup.seek(SeekFrom::Start(0)).unwrap();
+        assert_eq!(i.read::<OwnedFd>().err(), Some(DecodeError::Invalid {
+            offset: 4, reason: "unix fd index is out of range" }));
+        assert_eq!(d.fds().len(), 1);
+    }
+
+    #[test]
+    fn big_endian() {
+        let mut m = Data::with_endian(ENDIAN_BIG);
+        m.append(true).unwrap();
+        m.append("abc").unwrap();
+        m.append(0x01020304u32).unwrap();
+        m.append(0x0102030405060708u64).unwrap();
+        assert_eq!(m.data, [
+                   0,0,0,1, 0,0,0,3,
+                   b'a',b'b',b'c',0, 1,2,3,4,
+                   1,2,3,4,5,6,7,8,
+        ]);
+
+        let d = data(&m, b"bsut");
+        let mut i = d.iter();
+        assert!(i.read::<bool>().unwrap());
+        assert_eq!(i.read::<String>().unwrap(), "abc");
+        assert_eq!(i.read::<u32>().unwrap(), 0x01020304);
+        assert_eq!(i.read::<u64>().unwrap(), 0x0102030405060708);
+
+        let mut i = DataIter::new(&m.data, b"bsut", &[], LE);
+        assert_eq!(i.read::<bool>(), Err(DecodeError::Invalid {
+            offset: 0, reason: "boolean is neither 0 nor 1" }));
+    }
+
+    #[test]
+    fn basic_types() {
+        use names::{ObjectPath, ObjectPathBuf};
+        use type_sig::{Type, TypeBuf};
+
+        let mut m = Data::new();
+        m.append(0x12u8).unwrap();
+        m.append(-2i16).unwrap();
+        m.append(-3i32).unwrap();
+        m.append(-4i64).unwrap();
+        m.append(1.5f64).unwrap();
+        m.append(0x0102u16).unwrap();
+        m.append(ObjectPath::from_bytes(b"/a\0").unwrap()).unwrap();
+        m.append(Type::from_str("ai").unwrap()).unwrap();
+        assert_eq!(m.data, [
+                   0x12,0, 0xfe,0xff, 0xfd,0xff,0xff,0xff,
+                   0xfc,0xff,0xff,0xff,0xff,0xff,0xff,0xff,
+                   0,0,0,0,0,0,0xf8,0x3f,
+                   2,1, 0,0, 2,0,0,0,
+                   b'/',b'a',0, 2,b'a',b'i',0,
+        ]);
+
+        let d = data(&m, b"ynixdqog");
+        let mut i = d.iter();
+        assert_eq!(i.read::<u8>().unwrap(), 0x12);
+        assert_eq!(i.read::<i16>().unwrap(), -2);
+        assert_eq!(i.read::<i32>().unwrap(), -3);
+        assert_eq!(i.read::<i64>().unwrap(), -4);
+        assert_eq!(i.read::<f64>().unwrap(), 1.5);
+        assert_eq!(i.read::<u16>().unwrap(), 0x0102);
+        assert_eq!(i.read::<ObjectPathBuf>().unwrap().as_str(), "/a");
+        assert_eq!(i.read::<TypeBuf>().unwrap().as_ref(), "ai");
+        assert!(i.is_empty());
+
+        let mut i = d.iter();
+        assert_eq!(i.read::<u16>(), Err(DecodeError::WrongType {
+            offset: 0, expected: "q".to_owned(), found: "y".to_owned() }));
+
+        /* strings that are not valid paths or signatures */
+        let mut i = DataIter::new(&[3, 0, 0, 0, b'/', b'a', b'/', 0, 2, b'a', b'a', 0], b"og", &[], LE);
+        assert_eq!(i.read::<ObjectPathBuf>(), Err(DecodeError::Invalid {
+            offset: 4, reason: "object path is not valid" }));
+        assert_eq!(i.read::<TypeBuf>(), Err(DecodeError::Invalid {
+            offset: 9, reason: "signature is not valid" }));
+
+        let mut m = Data::new();
+        let long = "y".repeat(256);
+        assert!(m.append(Type::from_str(&long).unwrap()).is_err());
+    }
+
+    #[test]
+    fn tuples() {
+        use super::DBusType;
+
+        let v = (1u8, (String::from("in"), 2u64), vec![(3i32,)]);
+        let mut sig = vec![];
+        <(u8, (String, u64), Vec<(i32,)>)>::signature(&mut sig);
+        assert_eq!(sig, b"(y(st)a(i))");
+        ::type_sig::Type::from_str(::std::str::from_utf8(&sig).unwrap()).unwrap();
+
+        let mut m = Data::new();
+        m.append(7u8).unwrap();
+        m.append(&v).unwrap();
+        let d = data(&m, b"y(y(st)a(i))");
+        let mut i = d.iter();
+        assert_eq!(i.read::<u8>().unwrap(), 7);
+        assert_eq!(i.read::<(u8, (String, u32), Vec<(i32,)>)>(), Err(DecodeError::WrongType {
+            offset: 1, expected: "(y(su)a(i))".to_owned(), found: "(y(st)a(i))".to_owned() }));
+        assert_eq!(i.read::<(u8, (String, u64), Vec<(i32,)>)>().unwrap(), v);
+        assert!(i.is_empty());
+
+        /* non-zero padding before a struct */
+        let mut b = d.bytes().to_vec();
+        b[3] = 1;
+        let d = Data::from_parts(b, b"y(y(st)a(i))".to_vec(), vec![], LE);
+        let mut i = d.iter();
+        i.read::<u8>().unwrap();
+        assert_eq!(i.read::<(u8, (String, u64), Vec<(i32,)>)>(),
+                   Err(DecodeError::NonZeroPadding { offset: 1 }));
+
+        let mut m = Data::new();
+        m.append((1u8, 2u8, 3u8, 4u8, 5u8, 6u8, 7u8, 8u8, 9u8, 10u8, 11u8, 12u32)).unwrap();
+        assert_eq!(m.data, [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 0, 12, 0, 0, 0]);
+    }
+
+    #[test]
+    fn dicts() {
+        use std::collections::{BTreeMap, HashMap};
+        use super::DBusType;
+
+        let mut sig = vec![];
+        <HashMap<String, Vec<(u8, bool)>>>::signature(&mut sig);
+        assert_eq!(sig, b"a{sa(yb)}");
+        ::type_sig::Type::from_str(::std::str::from_utf8(&sig).unwrap()).unwrap();
+
+        let mut b = BTreeMap::new();
+        b.insert(2u32, String::from("two"));
+        b.insert(1u32, String::from("one"));
+        let mut h = HashMap::new();
+        h.insert(String::from("a"), vec![1u64, 2]);
+        h.insert(String::from("b"), vec![]);
+
+        let mut m = Data::new();
+        m.append(&b).unwrap();
+        m.append(&h).unwrap();
+        m.append(BTreeMap::<u8, u8>::new()).unwrap();
+        let d = data(&m, b"a{us}a{sat}a{yy}");
+        let mut i = d.iter();
+        assert_eq!(i.read::<HashMap<u32, String>>().unwrap().len(), 2);
+        assert_eq!(i.read::<HashMap<String, Vec<u64>>>().unwrap(), h);
+        assert_eq!(i.read::<BTreeMap<u8, u8>>().unwrap(), BTreeMap::new());
+        assert!(i.is_empty());
+
+        let mut i = d.iter();
+        assert_eq!(i.read::<BTreeMap<u32, String>>().unwrap(), b);
+        assert_eq!(i.read::<BTreeMap<String, Vec<u32>>>(), Err(DecodeError::WrongType {
+            offset: 36, expected: "a{sau}".to_owned(), found: "a{sat}".to_owned() }));
+
+        /* the second entry repeats the key of the first */
+        let b = [16, 0, 0, 0, 0, 0, 0, 0, 7, 0, 0, 0, 1, 0, 0, 0, 7, 0, 0, 0, 2, 0, 0, 0];
+        let mut i = DataIter::new(&b, b"a{uu}", &[], LE);
+        assert_eq!(i.read::<BTreeMap<u32, u32>>(), Err(DecodeError::Invalid {
+            offset: 16, reason: "dict has a duplicate key" }));
+        let mut i = DataIter::new(&b, b"a{uu}", &[], LE);
+        assert_eq!(i.read::<HashMap<u32, u32>>(), Err(DecodeError::Invalid {
+            offset: 16, reason: "dict has a duplicate key" }));
+    }
+}
+
+/*
+ * Byte exact checks against the worked examples in the D-Bus specification, and the rules of its
+ * "Marshaling (Wire Format)" section.
+ */
+#[cfg(test)]
+mod spec {
+    use super::Data;
+    use header::ENDIAN_BIG;
+
+    fn encode<T: super::DBusType>(v: T) -> Vec<u8> {
+        let mut m = Data::new();
+        m.append(v).unwrap();
+        m.data
+    }
+
+    /* "an array containing only the 64-bit integer 5", from "Marshaling containers" */
+    #[test]
+    fn array_of_int64_big_endian() {
+        let mut m = Data::with_endian(ENDIAN_BIG);
+        m.append(&[5u64][..]).unwrap();
+        assert_eq!(m.data, [
+            0, 0, 0, 8,
+            0, 0, 0, 0,
+            0, 0, 0, 0, 0, 0, 0, 5,
+        ]);
+    }
+
+    /* the length counts from the first element, which may not need padding */
+    #[test]
+    fn array_length_excludes_leading_padding() {
+        let mut m = Data::new();
+        m.append(1u32).unwrap();
+        m.append(&[5u64][..]).unwrap();
+        assert_eq!(m.data, [
+            1, 0, 0, 0,
+            8, 0, 0, 0,
+            5, 0, 0, 0, 0, 0, 0, 0,
+        ]);
+    }
+
+    /* "the padding for the first element must be present" even for an empty array */
+    #[test]
+    fn empty_arrays() {
+        assert_eq!(encode(&[0u64; 0][..]), [0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(encode(&[0u32; 0][..]), [0, 0, 0, 0]);
+
+        let mut m = Data::new();
+        m.append(1u32).unwrap();
+        m.append(&[0u64; 0][..]).unwrap();
+        assert_eq!(m.data, [1, 0, 0, 0, 0, 0, 0, 0]);
+    }
+
+    /* the length includes padding between elements, and is of marshalled (not in memory) data */
+    #[test]
+    fn arrays_of_strings() {
+        assert_eq!(encode(&["foo", "ba"][..]), [
+            15, 0, 0, 0,
+            3, 0, 0, 0, b'f', b'o', b'o', 0,
+            2, 0, 0, 0, b'b', b'a', 0,
+        ]);
+    }
+
+    #[test]
+    fn arrays_of_small_types() {
+        assert_eq!(encode(&[1u8, 2, 3][..]), [3, 0, 0, 0, 1, 2, 3]);
+        assert_eq!(encode(&[true, false][..]), [8, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(encode(&[-1i16, 2][..]), [4, 0, 0, 0, 0xff, 0xff, 2, 0]);
+    }
+
+    #[test]
+    fn nested_arrays() {
+        assert_eq!(encode(&[vec![1u32], vec![2, 3]][..]), [
+            20, 0, 0, 0,
+            4, 0, 0, 0, 1, 0, 0, 0,
+            8, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0,
+        ]);
+
+        /* each inner array pads to its first element, and that padding is in the outer length */
+        assert_eq!(encode(&[vec![5u64], vec![6]][..]), [
+            28, 0, 0, 0,
+            8, 0, 0, 0, 5, 0, 0, 0, 0, 0, 0, 0,
+            8, 0, 0, 0, 0, 0, 0, 0, 6, 0, 0, 0, 0, 0, 0, 0,
+        ]);
+    }
+
+    /* each value is aligned to its size, from the start of the data */
+    #[test]
+    fn basic_alignment() {
+        let mut m = Data::new();
+        m.append(1u8).unwrap();
+        m.append(2u32).unwrap();
+        m.append(3u8).unwrap();
+        m.append(4u16).unwrap();
+        m.append(5u8).unwrap();
+        m.append(6.0f64).unwrap();
+        assert_eq!(m.data, [
+            1, 0, 0, 0, 2, 0, 0, 0,
+            3, 0, 4, 0, 5, 0, 0, 0,
+            0, 0, 0, 0, 0, 0, 0x18, 0x40,
+        ]);
+    }
+
+    /* strings are padded to 4 for their length, and are followed by a nul, but are not padded */
+    #[test]
+    fn string_alignment() {
+        let mut m = Data::new();
+        m.append(1u8).unwrap();
+        m.append("a").unwrap();
+        m.append("").unwrap();
+        assert_eq!(m.data, [1, 0, 0, 0, 1, 0, 0, 0, b'a', 0, 0, 0, 0, 0, 0, 0, 0]);
+    }
+
+    /* encoded arrays decode back to what was encoded */
+    #[test]
+    fn round_trip() {
+        let v = vec![vec![5u64], vec![], vec![6, 7]];
+        let mut m = Data::with_endian(ENDIAN_BIG);
+        m.append(1u8).unwrap();
+        m.append(&v).unwrap();
+        m.append(&["x", "yz"][..]).unwrap();
+        let d = Data::from_parts(m.data.clone(), b"yaatas".to_vec(), vec![], ENDIAN_BIG);
+        let mut i = d.iter();
+        assert_eq!(i.read::<u8>().unwrap(), 1);
+        assert_eq!(i.read::<Vec<Vec<u64>>>().unwrap(), v);
+        assert_eq!(i.read::<Vec<String>>().unwrap(), ["x", "yz"]);
+    }
+
+    /* structs start on an 8 byte boundary, and their fields are aligned within them */
+    #[test]
+    fn structs() {
+        let mut m = Data::new();
+        m.append(1u8).unwrap();
+        m.append((2u8, 3u32, "a")).unwrap();
+        assert_eq!(m.data, [
+            1, 0, 0, 0, 0, 0, 0, 0,
+            2, 0, 0, 0, 3, 0, 0, 0,
+            1, 0, 0, 0, b'a', 0,
+        ]);
+    }
+
+    /* elements of an array of structs are padded to 8, and the padding counts toward the length */
+    #[test]
+    fn arrays_of_structs() {
+        assert_eq!(encode(&[(1u8, 2u32), (3, 4)][..]), [
+            16, 0, 0, 0, 0, 0, 0, 0,
+            1, 0, 0, 0, 2, 0, 0, 0,
+            3, 0, 0, 0, 4, 0, 0, 0,
+        ]);
+        assert_eq!(encode(&[(1u8,); 0][..]), [0, 0, 0, 0, 0, 0, 0, 0]);
+    }
+
+    /* dict entries are aligned to 8 like structs, and the array length counts from the first */
+    #[test]
+    fn dicts() {
+        let mut d = ::std::collections::BTreeMap::new();
+        d.insert(1u8, "a");
+        d.insert(2u8, "bc");
+        let mut m = Data::new();
+        m.append(1u8).unwrap();
+        m.append(&d).unwrap();
+        assert_eq!(m.data, [
+            1, 0, 0, 0, 27, 0, 0, 0,
+            1, 0, 0, 0, 1, 0, 0, 0, b'a', 0, 0, 0, 0, 0, 0, 0,
+            2, 0, 0, 0, 2, 0, 0, 0, b'b', b'c', 0,
+        ]);
+        assert_eq!(encode(::std::collections::BTreeMap::<u8, u8>::new()), [0, 0, 0, 0, 0, 0, 0, 0]);
+    }
 }
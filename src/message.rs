@@ -1,7 +1,25 @@
+use std::os::unix::io::OwnedFd;
+use std::str;
+
+use error::Error;
+use header::{self, Fields};
+use marshal::{self, DBusType, Data, DecodeError, EncodeError, MAX_MESSAGE_LEN};
+use names::{self, BusName, InterfaceName, MemberName, ObjectPath};
+use type_sig::Type;
+
 /// A DBus message is composed of a header and a body. The header has a fixed type signature, while
 /// the body has a variable type signature that is included in the message header
 pub struct Message {
     header: ::header::Bus,
+    path: Option<String>,
+    interface: Option<String>,
+    member: Option<String>,
+    error_name: Option<String>,
+    reply_serial: Option<u32>,
+    destination: Option<String>,
+    sender: Option<String>,
+    /// `UNIX_FDS` of a received message. Outgoing messages use the number of fds in the body.
+    unix_fds: u32,
     body: ::marshal::Data,
 }
 
@@ -11,19 +29,608 @@ impl ::std::default::Default for Message {
     }
 }
 
+/*
+ * The name validators want nul terminated input
+ */
+fn validate<T: ?Sized>(s: &str, f: fn(&[u8]) -> Result<&T, Error>) -> Result<String, Error>
+{
+    let mut b = Vec::with_capacity(s.len() + 1);
+    b.extend_from_slice(s.as_bytes());
+    b.push(0);
+    f(&b)?;
+    Ok(s.to_owned())
+}
+
 impl Message {
     pub fn new() -> Message {
         Message {
             header: ::header::Bus::new(),
+            path: None,
+            interface: None,
+            member: None,
+            error_name: None,
+            reply_serial: None,
+            destination: None,
+            sender: None,
+            unix_fds: 0,
             body: ::marshal::Data::new(),
         }
     }
 
+    /**
+     * Create a call of the method `member` on the object at `path`
+     */
+    pub fn method_call(path: &str, member: &str) -> Result<Message, Error> {
+        let mut m = Message::new();
+        m.header.set_typ(header::TYPE_METHOD_CALL);
+        m.path = Some(validate(path, names::ObjectPath::from_bytes)?);
+        m.member = Some(validate(member, names::MemberName::from_bytes)?);
+        Ok(m)
+    }
+
+    /**
+     * Create a reply to the method call `call`
+     */
+    pub fn method_return(call: &Message) -> Message {
+        let mut m = Message::new();
+        m.header.set_typ(header::TYPE_METHOD_RETURN);
+        m.reply_serial = Some(call.serial());
+        m.destination = call.sender.clone();
+        m
+    }
+
+    pub fn set_interface(&mut self, interface: &str) -> Result<(), Error> {
+        self.interface = Some(validate(interface, names::InterfaceName::from_bytes)?);
+        Ok(())
+    }
+
+    pub fn set_destination(&mut self, destination: &str) -> Result<(), Error> {
+        self.destination = Some(validate(destination, names::BusName::from_bytes)?);
+        Ok(())
+    }
+
+    pub fn typ(&self) -> header::Type {
+        self.header.typ()
+    }
+
+    pub fn flags(&self) -> header::Flags {
+        self.header.flags()
+    }
+
+    pub fn set_flags(&mut self, flags: header::Flags) {
+        self.header.set_flags(flags);
+    }
+
+    /// The byte order of the message, which is that of its body
+    pub fn endian(&self) -> header::Endian {
+        self.body.endian()
+    }
+
+    /**
+     * Choose the byte order the message is encoded in. Messages are little endian unless this is
+     * called.
+     *
+     * Values already appended to the body are in the old byte order, so this fails unless the body
+     * is empty.
+     */
+    pub fn set_endian(&mut self, endian: header::Endian) -> Result<(), Error> {
+        if endian == self.body.endian() {
+            return Ok(());
+        }
+        if !self.body.bytes().is_empty() {
+            let e = "cannot change the byte order of a message with a body";
+            return Err(Error::Unsupported(e.to_owned()));
+        }
+        self.body = Data::with_endian(endian);
+        Ok(())
+    }
+
+    /// The serial the sender assigned the message. 0 for messages that have not been sent.
+    pub fn serial(&self) -> u32 {
+        self.header.serial()
+    }
+
+    pub fn path(&self) -> Option<&str> {
+        self.path.as_deref()
+    }
+
+    pub fn interface(&self) -> Option<&str> {
+        self.interface.as_deref()
+    }
+
+    pub fn member(&self) -> Option<&str> {
+        self.member.as_deref()
+    }
+
+    pub fn error_name(&self) -> Option<&str> {
+        self.error_name.as_deref()
+    }
+
+    pub fn reply_serial(&self) -> Option<u32> {
+        self.reply_serial
+    }
+
+    pub fn destination(&self) -> Option<&str> {
+        self.destination.as_deref()
+    }
+
+    pub fn sender(&self) -> Option<&str> {
+        self.sender.as_deref()
+    }
+
+    /// The number of unix fds the message carries
+    pub fn unix_fds(&self) -> u32 {
+        if self.body.fds().is_empty() {
+            self.unix_fds
+        } else {
+            self.body.fds().len() as u32
+        }
+    }
+
+    pub fn body(&self) -> &Data {
+        &self.body
+    }
+
+    pub fn body_mut(&mut self) -> &mut Data {
+        &mut self.body
+    }
+
+    pub fn append<T: DBusType>(&mut self, item: T) -> Result<(), EncodeError> {
+        self.body.append(item)
+    }
+
+    /**
+     * Attach the fds that arrived with a received message. See `unix_fds()` for how many there
+     * should be.
+     */
+    pub fn set_fds(&mut self, fds: Vec<OwnedFd>) {
+        let body = ::std::mem::take(&mut self.body);
+        let sig = body.signature_bytes().to_vec();
+        self.body = Data::from_parts(body.bytes().to_vec(), sig, fds, body.endian());
+    }
+
+    /// Take ownership of the fds the message carries
+    pub fn take_fds(&mut self) -> Vec<OwnedFd> {
+        self.body.take_fds()
+    }
+
+    /**
+     * Serialize the message, giving it the serial `serial`.
+     *
+     * The fds refered to by the body (`body().fds()`) must be sent along with the returned bytes.
+     */
+    pub fn encode(&self, serial: u32) -> Result<Vec<u8>, EncodeError> {
+        let mut w = FieldWriter {
+            buf: vec![0u8; header::FIXED_LEN],
+            big: self.body.endian() == header::ENDIAN_BIG,
+        };
+        if let Some(ref p) = self.path {
+            w.string(header::PATH, b'o', p);
+        }
+        if let Some(ref i) = self.interface {
+            w.string(header::INTERFACE, b's', i);
+        }
+        if let Some(ref m) = self.member {
+            w.string(header::MEMBER, b's', m);
+        }
+        if let Some(ref e) = self.error_name {
+            w.string(header::ERROR_NMAME, b's', e);
+        }
+        if let Some(r) = self.reply_serial {
+            w.u32(header::REPLY_SERIAL, r);
+        }
+        if let Some(ref d) = self.destination {
+            w.string(header::DESTINATION, b's', d);
+        }
+        if let Some(ref s) = self.sender {
+            w.string(header::SENDER, b's', s);
+        }
+        let sig = self.body.signature()
+            .map_err(|e| marshal::encode_type_error(e, "body signature is not valid"))?;
+        if !sig.as_ref().is_empty() {
+            w.signature(header::SIGNATURE, sig)?;
+        }
+        if !self.body.fds().is_empty() {
+            w.u32(header::UNIX_FDS, self.body.fds().len() as u32);
+        }
+
+        let fields_len = w.buf.len() - header::FIXED_LEN;
+        w.align(8);
+
+        let mut h = ::header::Bus::new();
+        h.set_endian(self.body.endian());
+        h.set_typ(self.header.typ());
+        h.set_flags(self.header.flags());
+        h.set_serial(serial);
+        h.set_body_size(to_u32(self.body.bytes().len())?);
+
+        let mut buf = w.buf;
+        if buf.len() + self.body.bytes().len() > MAX_MESSAGE_LEN {
+            return Err(EncodeError::MessageTooLong);
+        }
+        buf[..header::FIXED_LEN].copy_from_slice(&h.to_bytes(to_u32(fields_len)?));
+        buf.extend_from_slice(self.body.bytes());
+        Ok(buf)
+    }
+
+    /**
+     * Parse a complete message, as returned by `framing::MessageReader`.
+     *
+     * Any fds the message carries must be attached afterwards with `set_fds()`. `unix_fds_in()`
+     * says how many there are.
+     */
+    pub fn decode(buf: &[u8]) -> Result<Message, Error> {
+        if buf.len() > MAX_MESSAGE_LEN {
+            return Err(Error::Decode(DecodeError::MessageTooLong { len: buf.len() }));
+        }
+        let m = Message::parse(buf).map_err(Error::Protocol)?;
+        m.body.signature().map_err(|e| Error::Decode(marshal::decode_type_error(e, 0)))?;
+        Ok(m)
+    }
+
+    /**
+     * The number of unix fds the complete message in `buf` carries, from its `UNIX_FDS` header
+     * field.
+     *
+     * Nothing else is decoded, so the fds of a message that `decode()` rejects can still be
+     * claimed.
+     */
+    pub fn unix_fds_in(buf: &[u8]) -> Result<u32, Error> {
+        let find = || {
+            let (_, mut r) = Message::fields(buf)?;
+            while r.pos < r.buf.len() {
+                r.align(8)?;
+                let code = r.u8()?;
+                let field_sig = r.signature()?;
+                if code == header::UNIX_FDS.bits() && field_sig == b"u" {
+                    return r.u32();
+                }
+                r.skip(field_sig)?;
+            }
+            Ok(0)
+        };
+        find().map_err(Error::Protocol)
+    }
+
     /*
-    fn append<T: Type>(&mut self, item: T) {
-        unimplemented!();
+     * Check the fixed part of the header against the length of `buf`, and return it along with a
+     * reader over the header field array
+     */
+    fn fields(buf: &[u8]) -> Result<(header::Bus, FieldReader<'_>), String> {
+        if buf.len() < header::FIXED_LEN {
+            return Err("message is shorter than its header".to_owned());
+        }
+        let mut prefix = [0u8; header::FIXED_LEN];
+        prefix.copy_from_slice(&buf[..header::FIXED_LEN]);
+        let (h, fields_len) = header::Bus::from_bytes(&prefix)
+            .ok_or_else(|| "message has an invalid endian marker".to_owned())?;
+
+        let fields_end = header::FIXED_LEN + fields_len as usize;
+        let body_start = (fields_end + 7) & !7;
+        let body_end = body_start + h.body_size() as usize;
+        if buf.len() != body_end {
+            return Err(format!("message length {} does not match its header ({})", buf.len(), body_end));
+        }
+
+        let r = FieldReader {
+            buf: &buf[..fields_end],
+            pos: header::FIXED_LEN,
+            big: h.endian() == header::ENDIAN_BIG,
+        };
+        Ok((h, r))
+    }
+
+    fn parse(buf: &[u8]) -> Result<Message, String> {
+        let (h, mut r) = Message::fields(buf)?;
+        let fields_end = r.buf.len();
+        let body_start = (fields_end + 7) & !7;
+
+        let mut m = Message::new();
+        let mut sig = vec![];
+        while r.pos < fields_end {
+            r.align(8)?;
+            let code = r.u8()?;
+            let field_sig = r.signature()?.to_vec();
+            /* Fields is a bitflags type, so only truncate codes we know to be valid */
+            let known = if (1..=9).contains(&code) {
+                Some(Fields::from_bits_truncate(code))
+            } else {
+                None
+            };
+            match (known, &field_sig[..]) {
+                (Some(header::PATH), b"o") => m.path = Some(r.name(ObjectPath::from_bytes)?),
+                (Some(header::INTERFACE), b"s") => m.interface = Some(r.name(InterfaceName::from_bytes)?),
+                (Some(header::MEMBER), b"s") => m.member = Some(r.name(MemberName::from_bytes)?),
+                (Some(header::ERROR_NMAME), b"s") => m.error_name = Some(r.name(InterfaceName::from_bytes)?),
+                (Some(header::REPLY_SERIAL), b"u") => m.reply_serial = Some(r.u32()?),
+                (Some(header::DESTINATION), b"s") => m.destination = Some(r.name(BusName::from_bytes)?),
+                (Some(header::SENDER), b"s") => m.sender = Some(r.name(BusName::from_bytes)?),
+                (Some(header::SIGNATURE), b"g") => sig = r.signature()?.to_vec(),
+                (Some(header::UNIX_FDS), b"u") => m.unix_fds = r.u32()?,
+                (Some(_), _) => {
+                    return Err(format!("header field {} has the wrong type", code));
+                }
+                /* unknown fields must be ignored */
+                (_, _) => r.skip(&field_sig)?,
+            }
+        }
+
+        if r.pos != fields_end {
+            return Err("header fields overrun their array".to_owned());
+        }
+
+        let required: &[(bool, &str)] = match h.typ() {
+            header::TYPE_METHOD_CALL => &[(m.path.is_some(), "PATH"), (m.member.is_some(), "MEMBER")],
+            header::TYPE_METHOD_RETURN => &[(m.reply_serial.is_some(), "REPLY_SERIAL")],
+            header::TYPE_METHOD_ERROR => &[(m.error_name.is_some(), "ERROR_NAME"),
+                                           (m.reply_serial.is_some(), "REPLY_SERIAL")],
+            header::TYPE_METHOD_SIGNAL => &[(m.path.is_some(), "PATH"), (m.interface.is_some(), "INTERFACE"),
+                                            (m.member.is_some(), "MEMBER")],
+            _ => return Err(format!("unknown message type {}", h.typ().bits())),
+        };
+        if let Some(&(_, name)) = required.iter().find(|&&(present, _)| !present) {
+            return Err(format!("message is missing required header field {}", name));
+        }
+
+        m.body = Data::from_parts(buf[body_start..].to_vec(), sig, vec![], h.endian());
+        m.header = h;
+        Ok(m)
+    }
+}
+
+fn to_u32(v: usize) -> Result<u32, EncodeError> {
+    if v > u32::MAX as usize {
+        Err(EncodeError::TooLong)
+    } else {
+        Ok(v as u32)
+    }
+}
+
+/*
+ * Writes the header field array, `a(yv)`. Offsets are from the start of the message, which is
+ * where `buf` starts.
+ */
+struct FieldWriter {
+    buf: Vec<u8>,
+    big: bool,
+}
+
+impl FieldWriter {
+    fn align(&mut self, n: usize) {
+        while self.buf.len() & (n - 1) != 0 {
+            self.buf.push(0);
+        }
     }
-    */
 
+    fn start(&mut self, code: Fields, sig: u8) {
+        self.align(8);
+        self.buf.extend_from_slice(&[code.bits(), 1, sig, 0]);
+    }
+
+    fn string(&mut self, code: Fields, sig: u8, v: &str) {
+        self.start(code, sig);
+        self.align(4);
+        self.put_u32(v.len() as u32);
+        self.buf.extend_from_slice(v.as_bytes());
+        self.buf.push(0);
+    }
+
+    fn u32(&mut self, code: Fields, v: u32) {
+        self.start(code, b'u');
+        self.align(4);
+        self.put_u32(v);
+    }
+
+    fn put_u32(&mut self, v: u32) {
+        let b = if self.big { v.to_be_bytes() } else { v.to_le_bytes() };
+        self.buf.extend_from_slice(&b);
+    }
+
+    fn signature(&mut self, code: Fields, v: Type) -> Result<(), EncodeError> {
+        let v: &str = v.as_ref();
+        if v.len() > 255 {
+            return Err(EncodeError::TooLong);
+        }
+        self.start(code, b'g');
+        self.buf.push(v.len() as u8);
+        self.buf.extend_from_slice(v.as_bytes());
+        self.buf.push(0);
+        Ok(())
+    }
 }
 
+/*
+ * Reads the header field array
+ */
+struct FieldReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    big: bool,
+}
+
+impl<'a> FieldReader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if self.buf.len() - self.pos < n {
+            return Err("header field array is truncated".to_owned());
+        }
+        let b = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(b)
+    }
+
+    fn align(&mut self, n: usize) -> Result<(), String> {
+        let pad = (n - self.pos % n) % n;
+        if self.take(pad)?.iter().any(|&b| b != 0) {
+            return Err("header contains non-zero padding".to_owned());
+        }
+        Ok(())
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        self.align(4)?;
+        let b = self.take(4)?;
+        let v = [b[0], b[1], b[2], b[3]];
+        Ok(if self.big { u32::from_be_bytes(v) } else { u32::from_le_bytes(v) })
+    }
+
+    fn string(&mut self) -> Result<&'a str, String> {
+        let len = self.u32()? as usize;
+        let b = self.take(len)?;
+        if self.u8()? != 0 {
+            return Err("header string is not nul terminated".to_owned());
+        }
+        str::from_utf8(b).map_err(|_| "header string is not UTF-8".to_owned())
+    }
+
+    fn name<T: ?Sized>(&mut self, f: fn(&[u8]) -> Result<&T, Error>) -> Result<String, String> {
+        validate(self.string()?, f).map_err(|e| e.to_string())
+    }
+
+    fn signature(&mut self) -> Result<&'a [u8], String> {
+        let len = self.u8()? as usize;
+        let b = self.take(len)?;
+        if self.u8()? != 0 {
+            return Err("header signature is not nul terminated".to_owned());
+        }
+        Ok(b)
+    }
+
+    /*
+     * Skip the value of an unknown field. Only single basic types are handled.
+     */
+    fn skip(&mut self, sig: &[u8]) -> Result<(), String> {
+        match sig {
+            b"y" => { self.u8()?; }
+            b"b" | b"u" | b"i" | b"h" => { self.u32()?; }
+            b"n" | b"q" => { self.align(2)?; self.take(2)?; }
+            b"x" | b"t" | b"d" => { self.align(8)?; self.take(8)?; }
+            b"s" | b"o" => { self.string()?; }
+            b"g" => { self.signature()?; }
+            _ => return Err(format!("cannot skip unknown header field of type '{}'",
+                                    String::from_utf8_lossy(sig))),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Message;
+    use header;
+
+    #[test]
+    fn method_call_encode() {
+        let mut m = Message::method_call("/org/freedesktop/DBus", "Hello").unwrap();
+        m.set_interface("org.freedesktop.DBus").unwrap();
+        m.set_destination("org.freedesktop.DBus").unwrap();
+
+        let b = m.encode(1).unwrap();
+        let mut expected = vec![b'l', 1, 0, 1, 0, 0, 0, 0, 1, 0, 0, 0, 0x6d, 0, 0, 0];
+        expected.extend_from_slice(&[1, 1, b'o', 0, 21, 0, 0, 0]);
+        expected.extend_from_slice(b"/org/freedesktop/DBus\0\0\0");
+        expected.extend_from_slice(&[2, 1, b's', 0, 20, 0, 0, 0]);
+        expected.extend_from_slice(b"org.freedesktop.DBus\0\0\0\0");
+        expected.extend_from_slice(&[3, 1, b's', 0, 5, 0, 0, 0]);
+        expected.extend_from_slice(b"Hello\0\0\0");
+        expected.extend_from_slice(&[6, 1, b's', 0, 20, 0, 0, 0]);
+        expected.extend_from_slice(b"org.freedesktop.DBus\0\0\0\0");
+        assert_eq!(b, expected);
+
+        let d = Message::decode(&b).unwrap();
+        assert_eq!(d.typ(), header::TYPE_METHOD_CALL);
+        assert_eq!(d.serial(), 1);
+        assert_eq!(d.path(), Some("/org/freedesktop/DBus"));
+        assert_eq!(d.interface(), Some("org.freedesktop.DBus"));
+        assert_eq!(d.member(), Some("Hello"));
+        assert_eq!(d.destination(), Some("org.freedesktop.DBus"));
+        assert_eq!(d.sender(), None);
+        assert_eq!(d.unix_fds(), 0);
+    }
+
+    #[test]
+    fn validation() {
+        Message::method_call("no/slash", "Hello").err().unwrap();
+        Message::method_call("/a", "Hel.lo").err().unwrap();
+        let mut m = Message::method_call("/a", "Hello").unwrap();
+        m.set_interface("nodots").err().unwrap();
+        m.set_destination(":1.42").unwrap();
+    }
+
+    #[test]
+    fn reply_with_fds() {
+        use std::os::unix::io::AsFd;
+
+        let mut call = Message::method_call("/a", "Get").unwrap();
+        call.set_destination(":1.1").unwrap();
+        let call = Message::decode(&call.encode(7).unwrap()).unwrap();
+
+        let f = ::std::fs::File::open("/dev/null").unwrap();
+        let mut r = Message::method_return(&call);
+        r.append(f.as_fd()).unwrap();
+        assert_eq!(r.unix_fds(), 1);
+
+        let b = r.encode(8).unwrap();
+        assert_eq!(Message::unix_fds_in(&b).unwrap(), 1);
+        let mut d = Message::decode(&b).unwrap();
+        assert_eq!(d.typ(), header::TYPE_METHOD_RETURN);
+        assert_eq!(d.reply_serial(), Some(7));
+        assert_eq!(d.unix_fds(), 1);
+        assert_eq!(d.body().bytes(), [0, 0, 0, 0]);
+        assert_eq!(d.body().signature_bytes(), b"h");
+        assert!(d.take_fds().is_empty());
+    }
+
+    #[test]
+    fn decode_errors() {
+        let m = Message::method_call("/a", "Get").unwrap();
+        let b = m.encode(1).unwrap();
+        Message::decode(&b[..b.len() - 1]).err().unwrap();
+
+        /* a method return without REPLY_SERIAL */
+        let mut bad = b.clone();
+        bad[1] = 2;
+        Message::decode(&bad).err().unwrap();
+
+        /* non-zero padding after the path */
+        let mut bad = b.clone();
+        bad[16 + 8 + 3] = 1;
+        Message::decode(&bad).err().unwrap();
+
+        /* unknown fields are skipped */
+        let mut b = vec![b'l', 1, 0, 1, 0, 0, 0, 0, 1, 0, 0, 0, 0x28, 0, 0, 0];
+        b.extend_from_slice(&[1, 1, b'o', 0, 2, 0, 0, 0, b'/', b'a', 0, 0, 0, 0, 0, 0]);
+        b.extend_from_slice(&[3, 1, b's', 0, 3, 0, 0, 0, b'G', b'e', b't', 0, 0, 0, 0, 0]);
+        b.extend_from_slice(&[99, 1, b'u', 0, 5, 0, 0, 0]);
+        let d = Message::decode(&b).unwrap();
+        assert_eq!(d.member(), Some("Get"));
+    }
+
+    #[test]
+    fn big_endian() {
+        /* a reply as a big endian (s390x) peer would send it */
+        let mut b = vec![b'B', 2, 0, 1, 0, 0, 0, 9, 0, 0, 0, 3, 0, 0, 0, 15];
+        b.extend_from_slice(&[5, 1, b'u', 0, 0, 0, 0, 7]);
+        b.extend_from_slice(&[8, 1, b'g', 0, 1, b's', 0, 0]);
+        b.extend_from_slice(&[0, 0, 0, 4, b':', b'1', b'.', b'5', 0]);
+
+        let d = Message::decode(&b).unwrap();
+        assert_eq!(d.endian(), header::ENDIAN_BIG);
+        assert_eq!(d.serial(), 3);
+        assert_eq!(d.reply_serial(), Some(7));
+        assert_eq!(d.body().iter().read::<String>().unwrap(), ":1.5");
+        assert_eq!(d.encode(3).unwrap(), b);
+
+        let mut m = Message::method_call("/a", "Get").unwrap();
+        m.set_endian(header::ENDIAN_BIG).unwrap();
+        m.append(0x01020304u32).unwrap();
+        let b = m.encode(0x0a0b0c0d).unwrap();
+        assert_eq!(b[..16], [b'B', 1, 0, 1, 0, 0, 0, 4, 0x0a, 0x0b, 0x0c, 0x0d, 0, 0, 0, 0x27]);
+        assert_eq!(b[b.len() - 4..], [1, 2, 3, 4]);
+        assert_eq!(Message::decode(&b).unwrap().body().iter().read::<u32>().unwrap(), 0x01020304);
+        m.set_endian(header::ENDIAN_LITTLE).err().unwrap();
+    }
+}
@@ -6,11 +6,13 @@ extern crate utf8_cstr;
 
 use std::os::raw::c_char;
 use std::{str};
-use std::ffi::CStr;
+use std::ffi::{CStr, CString};
 use std::mem::{transmute};
 use std::ops::{Deref};
 use std::result;
 
+use error::Error;
+
 /**
  * A wrapper which promises it always holds a valid dbus object path
  *
@@ -33,17 +35,17 @@ impl ObjectPath {
      * Create a path reference from a u8 slice. Performs all checking needed to ensure requirements
      * are met.
      */
-    pub fn from_bytes(b: &[u8]) -> result::Result<&ObjectPath, &'static str> {
-        if b.len() < 1 {
-            return Err("Path must have at least 1 character ('/')");
+    pub fn from_bytes(b: &[u8]) -> result::Result<&ObjectPath, Error> {
+        if b.is_empty() {
+            return Err(invalid(b, "Path must have at least 1 character ('/')"));
         }
 
         if b.len() > 255 {
-            return Err("Path may not have more than 255 characters");
+            return Err(invalid(b, "Path may not have more than 255 characters"));
         }
 
-        if b[0] != b'/' as u8 {
-            return Err("Path must begin with '/'");
+        if b[0] != b'/' {
+            return Err(invalid(b, "Path must begin with '/'"));
         }
 
         for w in b.windows(2) {
@@ -53,33 +55,43 @@ impl ObjectPath {
             match c {
                 b'/' => {
                     if prev == b'/' {
-                        return Err("Path must not have 2 '/' next to each other");
+                        return Err(invalid(b, "Path must not have 2 '/' next to each other"));
                     }
                 }
-                b'A'...b'Z' | b'a'...b'z' | b'0'...b'9' | b'_' => {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'_' => {
                     // Ok
                 }
                 b'\0' => {
                     if prev == b'/' && b.len() != 2 {
-                        return Err("Path must not end in '/' unless it is the root path");
+                        return Err(invalid(b, "Path must not end in '/' unless it is the root path"));
                     }
 
                     return Ok(unsafe { ObjectPath::from_bytes_unchecked(b) });
                 }
                 _ => {
-                    return Err("Invalid character in path, only '[A-Z][a-z][0-9]_/' allowed");
+                    return Err(invalid(b, "Invalid character in path, only \
+                                            '[A-Z][a-z][0-9]_/' allowed"));
                 }
             }
         }
 
-        return Err("Path must be terminated in a '\\0' byte (for use by sd-bus)");
+        Err(invalid(b, "Path must be terminated in a '\\0' byte (for use by sd-bus)"))
     }
 
+    /// # Safety
+    ///
+    ///  - `b` must be a nul terminated string
+    ///  - `b` must contain a valid path
     #[inline]
     pub unsafe fn from_bytes_unchecked(b: &[u8]) -> &ObjectPath {
         transmute(b)
     }
 
+    /// # Safety
+    ///
+    ///  - lifetime `'b` must be valid
+    ///  - `b` must be a nul terminated string
+    ///  - `b` must contain a valid path
     #[inline]
     pub unsafe fn from_ptr_unchecked<'b>(b: *const c_char) -> &'b ObjectPath {
        Self::from_bytes_unchecked(CStr::from_ptr(b).to_bytes())
@@ -94,6 +106,64 @@ impl Deref for ObjectPath {
     }
 }
 
+/*
+ * The error for a rejected name. `b` may or may not be nul terminated, and need not be utf-8.
+ */
+fn invalid(b: &[u8], reason: &'static str) -> Error {
+    let name = match b.split_last() {
+        Some((&0, rest)) => rest,
+        _ => b,
+    };
+    Error::InvalidName { name: String::from_utf8_lossy(name).into_owned(), reason }
+}
+
+/*
+ * Run a validator, which wants nul termination, over `name`, and keep a copy of it if it passes
+ */
+fn validate_owned<T: ?Sized>(name: &str, f: fn(&[u8]) -> result::Result<&T, Error>)
+    -> result::Result<CString, Error>
+{
+    let mut b = Vec::with_capacity(name.len() + 1);
+    b.extend_from_slice(name.as_bytes());
+    b.push(0);
+    f(&b)?;
+    b.pop();
+    CString::new(b).map_err(|_| Error::InvalidName {
+        name: name.to_owned(),
+        reason: "Name must not contain '\\0'",
+    })
+}
+
+/**
+ * An owned object path, for holding on to paths received from the bus
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjectPathBuf {
+    inner: CString,
+}
+
+impl ObjectPathBuf {
+    /**
+     * Validate `path` (which should not be nul terminated) as an object path and take a copy of it.
+     */
+    pub fn new(path: &str) -> result::Result<ObjectPathBuf, Error> {
+        Ok(ObjectPathBuf { inner: validate_owned(path, ObjectPath::from_bytes)? })
+    }
+
+    pub fn as_str(&self) -> &str {
+        /* validation only allows ascii */
+        unsafe { str::from_utf8_unchecked(self.inner.as_bytes()) }
+    }
+}
+
+impl Deref for ObjectPathBuf {
+    type Target = ObjectPath;
+    #[inline]
+    fn deref(&self) -> &ObjectPath {
+        unsafe { ObjectPath::from_bytes_unchecked(self.inner.as_bytes_with_nul()) }
+    }
+}
+
 #[test]
 fn t_path() {
     ObjectPath::from_bytes(b"/\0").unwrap();
@@ -104,6 +174,22 @@ fn t_path() {
     ObjectPath::from_bytes(b"/hello/\0").err().unwrap();
     ObjectPath::from_bytes(b"/hello/goodbye/013/4/HA\0").unwrap();
     ObjectPath::from_bytes(b"/hello/goodbye/013/4?/HA\0").err().unwrap();
+
+    let p = ObjectPathBuf::new("/org/freedesktop/DBus").unwrap();
+    assert_eq!(p.as_str(), "/org/freedesktop/DBus");
+    assert_eq!(p.to_bytes(), b"/org/freedesktop/DBus");
+    ObjectPathBuf::new("/a/").err().unwrap();
+    match ObjectPathBuf::new("/a\0") {
+        Err(Error::InvalidName { ref name, .. }) => assert_eq!(name, "/a\0"),
+        r => panic!("unexpected result: {:?}", r),
+    }
+    match ObjectPath::from_bytes(b"/a//b\0") {
+        Err(Error::InvalidName { ref name, reason }) => {
+            assert_eq!(name, "/a//b");
+            assert_eq!(reason, "Path must not have 2 '/' next to each other");
+        }
+        r => panic!("unexpected result: {:?}", r),
+    }
 }
 
 /**
@@ -130,22 +216,22 @@ impl InterfaceName {
      *  Interface names must not being with a '.' character
      * sd-bus additionally requires nul ('\0') termination of the interface name.
      */
-    pub fn from_bytes(b: &[u8]) -> result::Result<&InterfaceName, &'static str> {
+    pub fn from_bytes(b: &[u8]) -> result::Result<&InterfaceName, Error> {
 
-        if b.len() < 1 {
-            return Err("Name must have more than 0 characters");
+        if b.is_empty() {
+            return Err(invalid(b, "Name must have more than 0 characters"));
         }
 
         if b.len() > 255 {
-            return Err("Name may not have more than 255 characters");
+            return Err(invalid(b, "Name may not have more than 255 characters"));
         }
 
         match b[0] {
-            b'.' => return Err("Name must not begin with '.'"),
-            b'A'...b'Z' | b'a'...b'z' | b'_' => {
+            b'.' => return Err(invalid(b, "Name must not begin with '.'")),
+            b'A'..=b'Z' | b'a'..=b'z' | b'_' => {
                 // Ok
             }
-            _ => return Err("Name must only begin with '[A-Z][a-z]_'"),
+            _ => return Err(invalid(b, "Name must only begin with '[A-Z][a-z]_'")),
         }
 
 
@@ -156,41 +242,41 @@ impl InterfaceName {
             match c {
                 b'.' => {
                     if prev == b'.' {
-                        return Err("Name must not have 2 '.' next to each other");
+                        return Err(invalid(b, "Name must not have 2 '.' next to each other"));
                     }
 
                     periods += 1;
                 }
-                b'A'...b'Z' | b'a'...b'z' | b'_' => {
+                b'A'..=b'Z' | b'a'..=b'z' | b'_' => {
                     // Ok
                 }
-                b'0'...b'9' => {
+                b'0'..=b'9' => {
                     if prev == b'.' {
-                        return Err("Name element must not start with '[0-9]'");
+                        return Err(invalid(b, "Name element must not start with '[0-9]'"));
                     }
                     // otherwise, Ok
                 }
                 b'\0' => {
                     if prev == b'.' && b.len() != 1 {
-                        return Err("Name must not end in '.'");
+                        return Err(invalid(b, "Name must not end in '.'"));
                     }
 
                     if periods < 1 {
-                        return Err("Name must have at least 2 elements");
+                        return Err(invalid(b, "Name must have at least 2 elements"));
                     }
                     return Ok(unsafe { InterfaceName::from_bytes_unchecked(b) });
                 }
                 _ => {
-                    return Err("Invalid character in interface name, only '[A-Z][a-z][0-9]_\\.' \
-                                allowed");
+                    return Err(invalid(b, "Invalid character in interface name, only \
+                                            '[A-Z][a-z][0-9]_\\.' allowed"));
                 }
             }
         }
 
-        return Err("Name must be terminated in a '\\0' byte (for use by sd-bus)");
+        Err(invalid(b, "Name must be terminated in a '\\0' byte (for use by sd-bus)"))
     }
 
-    /// Unsafety:
+    /// # Safety
     ///
     ///  - `b` must be a nul terminated string
     ///  - `b` must contain a valid interface
@@ -199,7 +285,7 @@ impl InterfaceName {
         transmute(b)
     }
 
-    /// Unsafety:
+    /// # Safety
     ///
     ///  - lifetime `'a` must be valid
     ///  - `b` must be a nul terminated string
@@ -255,26 +341,26 @@ impl BusName {
      *
      * sd-bus additionally requires nul ('\0') termination of the bus name.
      */
-    pub fn from_bytes(b: &[u8]) -> result::Result<&Self, &'static str> {
+    pub fn from_bytes(b: &[u8]) -> result::Result<&Self, Error> {
 
-        if b.len() < 1 {
-            return Err("Name must have more than 0 characters");
+        if b.is_empty() {
+            return Err(invalid(b, "Name must have more than 0 characters"));
         }
 
         if b.len() > 255 {
-            return Err("Name must not be greater than 255 characters");
+            return Err(invalid(b, "Name must not be greater than 255 characters"));
         }
 
         let mut is_unique = false;
         match b[0] {
-            b'.' => return Err("Name must not begin with '.'"),
-            b'A'...b'Z' | b'a'...b'z' | b'_' | b'-' => {
+            b'.' => return Err(invalid(b, "Name must not begin with '.'")),
+            b'A'..=b'Z' | b'a'..=b'z' | b'_' | b'-' => {
                 // Ok
             }
             b':' => {
                 is_unique = true; /* Ok */
             }
-            _ => return Err("Name must only begin with '[A-Z][a-z]_'"),
+            _ => return Err(invalid(b, "Name must only begin with '[A-Z][a-z]_'")),
         }
 
         let mut periods = 0;
@@ -284,44 +370,54 @@ impl BusName {
             match c {
                 b'.' => {
                     if prev == b'.' || prev == b':' {
-                        return Err("Elements may not be empty");
+                        return Err(invalid(b, "Elements may not be empty"));
                     }
 
                     periods += 1;
                 }
-                b'A'...b'Z' | b'a'...b'z' | b'_' | b'-' => {
+                b'A'..=b'Z' | b'a'..=b'z' | b'_' | b'-' => {
                     // Ok
                 }
-                b'0'...b'9' => {
+                b'0'..=b'9' => {
                     if prev == b'.' && !is_unique {
-                        return Err("Name element must not start with '[0-9]'");
+                        return Err(invalid(b, "Name element must not start with '[0-9]'"));
                     }
                     // otherwise, Ok
                 }
                 b'\0' => {
                     if prev == b'.' && b.len() != 1 {
-                        return Err("Name must not end in '.'");
+                        return Err(invalid(b, "Name must not end in '.'"));
                     }
 
                     if periods < 1 {
-                        return Err("Name must have at least 2 elements");
+                        return Err(invalid(b, "Name must have at least 2 elements"));
                     }
                     return Ok(unsafe { BusName::from_bytes_unchecked(b) });
                 }
                 _ => {
-                    return Err("Invalid character in bus name, only '[A-Z][a-z][0-9]_\\.' allowed");
+                    return Err(invalid(b, "Invalid character in bus name, only \
+                                            '[A-Z][a-z][0-9]_\\.' allowed"));
                 }
             }
         }
 
-        return Err("Name must be terminated in a '\\0' byte (for use by sd-bus)");
+        Err(invalid(b, "Name must be terminated in a '\\0' byte (for use by sd-bus)"))
     }
 
+    /// # Safety
+    ///
+    ///  - `b` must be a nul terminated string
+    ///  - `b` must contain a valid bus name
     #[inline]
     pub unsafe fn from_bytes_unchecked(b: &[u8]) -> &Self {
         transmute(b)
     }
 
+    /// # Safety
+    ///
+    ///  - lifetime `'a` must be valid
+    ///  - `b` must be a nul terminated string
+    ///  - `b` must contain a valid bus name
     #[inline]
     pub unsafe fn from_ptr_unchecked<'a>(b: *const c_char) -> &'a Self {
         Self::from_bytes_unchecked(CStr::from_ptr(b).to_bytes())
@@ -336,6 +432,36 @@ impl Deref for BusName {
     }
 }
 
+/**
+ * An owned bus name, for holding on to names received from the bus
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BusNameBuf {
+    inner: CString,
+}
+
+impl BusNameBuf {
+    /**
+     * Validate `name` (which should not be nul terminated) as a bus name and take a copy of it.
+     */
+    pub fn new(name: &str) -> result::Result<BusNameBuf, Error> {
+        Ok(BusNameBuf { inner: validate_owned(name, BusName::from_bytes)? })
+    }
+
+    pub fn as_str(&self) -> &str {
+        /* validation only allows ascii */
+        unsafe { str::from_utf8_unchecked(self.inner.as_bytes()) }
+    }
+}
+
+impl Deref for BusNameBuf {
+    type Target = BusName;
+    #[inline]
+    fn deref(&self) -> &BusName {
+        unsafe { BusName::from_bytes_unchecked(self.inner.as_bytes_with_nul()) }
+    }
+}
+
 #[test]
 fn t_busname() {
     BusName::from_bytes(b"a.b\0").unwrap();
@@ -346,6 +472,12 @@ fn t_busname() {
     BusName::from_bytes(b"a.b-c.0a\0").err().unwrap();
     BusName::from_bytes(b":a.b-c\0").unwrap();
     BusName::from_bytes(b":a.b-c.1\0").unwrap();
+
+    let n = BusNameBuf::new(":1.42").unwrap();
+    assert_eq!(n.as_str(), ":1.42");
+    assert_eq!(n.to_bytes_with_nul(), b":1.42\0");
+    BusNameBuf::new("a.b\0").err().unwrap();
+    BusNameBuf::new("a").err().unwrap();
 }
 
 #[derive(Debug)]
@@ -368,43 +500,53 @@ impl MemberName {
      *
      * sd-bus additionally requires nul ('\0') termination of the bus name.
      */
-    pub fn from_bytes(b: &[u8]) -> result::Result<&Self, &'static str> {
+    pub fn from_bytes(b: &[u8]) -> result::Result<&Self, Error> {
 
         if b.len() < 2 {
-            return Err("Name must have more than 0 characters");
+            return Err(invalid(b, "Name must have more than 0 characters"));
         }
 
         if b.len() > 256 {
-            return Err("Must be shorter than 255 characters");
+            return Err(invalid(b, "Must be shorter than 255 characters"));
         }
 
         match b[0] {
-            b'A'...b'Z' | b'a'...b'z' | b'_' => {
+            b'A'..=b'Z' | b'a'..=b'z' | b'_' => {
                 // Ok
             }
-            _ => return Err("Must begin with '[A-Z][a-z]_'"),
+            _ => return Err(invalid(b, "Must begin with '[A-Z][a-z]_'")),
         }
 
         for c in b {
             match *c {
-                b'A'...b'Z' | b'a'...b'z' | b'0'...b'9' | b'_' => {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'_' => {
                     // Ok
                 }
                 b'\0' => return Ok(unsafe { Self::from_bytes_unchecked(b) }),
                 _ => {
-                    return Err("Invalid character in member name, only '[A-Z][a-z][0-9]_' allowed");
+                    return Err(invalid(b, "Invalid character in member name, only \
+                                            '[A-Z][a-z][0-9]_' allowed"));
                 }
             }
         }
 
-        return Err("Name must be terminated in a '\\0' byte (for use by sd-bus)");
+        Err(invalid(b, "Name must be terminated in a '\\0' byte (for use by sd-bus)"))
     }
 
+    /// # Safety
+    ///
+    ///  - `b` must be a nul terminated string
+    ///  - `b` must contain a valid member name
     #[inline]
     pub unsafe fn from_bytes_unchecked(b: &[u8]) -> &Self {
         transmute(b)
     }
 
+    /// # Safety
+    ///
+    ///  - lifetime `'a` must be valid
+    ///  - `b` must be a nul terminated string
+    ///  - `b` must contain a valid member name
     #[inline]
     pub unsafe fn from_ptr_unchecked<'a>(b: *const c_char) -> &'a Self {
         Self::from_bytes_unchecked(CStr::from_ptr(b).to_bytes())
@@ -0,0 +1,613 @@
+//! Marshal types that implement `serde::Serialize`, producing the same bytes as `DBusType` does
+//!
+//! - structs, tuples, and tuple structs are D-Bus structs, and must have at least one field
+//! - sequences are arrays, and maps are dicts, whose keys must be basic types
+//! - newtype structs are their inner type, and `Some` is its contents
+//! - unit enum variants are strings holding the name of the variant
+//! - `i8` is widened to `i16` (`n`), `f32` to `f64` (`d`), and `char` is a string
+//!
+//! `None`, units, and enum variants holding data have no D-Bus equivalent, and fail to serialize.
+//!
+//! The signature is taken from the values serialized, so the type of an empty sequence or map is
+//! not known. `append_with_signature()` takes the signature up front to provide it.
+
+use std::error;
+use std::fmt;
+
+use serde::ser::{self, Impossible, Serialize};
+
+use marshal::{self, DBusType, Data, EncodeError};
+use type_sig::Type;
+
+#[derive(Debug)]
+pub enum Error {
+    /// A value could not be marshalled
+    Encode(EncodeError),
+    /// The value cannot be represented in D-Bus
+    Invalid(&'static str),
+    /// The value does not have the signature it was expected to
+    WrongType { expected: String, found: String },
+    /// An error reported by a `Serialize` impl
+    Custom(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Encode(ref e) => e.fmt(fmt),
+            Error::Invalid(e) => write!(fmt, "cannot marshal value: {}", e),
+            Error::WrongType { ref expected, ref found } => {
+                write!(fmt, "expected a value of type '{}', but found '{}'", expected, found)
+            }
+            Error::Custom(ref e) => write!(fmt, "{}", e),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Encode(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl ser::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Error {
+        Error::Custom(msg.to_string())
+    }
+}
+
+impl From<EncodeError> for Error {
+    fn from(e: EncodeError) -> Error {
+        Error::Encode(e)
+    }
+}
+
+/**
+ * Marshal `value` into `data`, appending its type to the signature of `data`.
+ *
+ * If this fails the data and signature are left as they were.
+ */
+pub fn append<T: Serialize + ?Sized>(data: &mut Data, value: &T) -> Result<(), Error> {
+    append_inner(data, value, None)
+}
+
+/**
+ * Marshal `value`, which must have the signature `sig`, into `data`
+ */
+pub fn append_with_signature<T: Serialize + ?Sized>(data: &mut Data, value: &T, sig: Type) -> Result<(), Error> {
+    append_inner(data, value, Some(sig))
+}
+
+fn append_inner<T: Serialize + ?Sized>(data: &mut Data, value: &T, expected: Option<Type>) -> Result<(), Error> {
+    let (len, fds, depth) = (data.data.len(), data.fds.len(), data.depth);
+    let hint = expected.map(|t| { let s: &str = t.as_ref(); s.as_bytes().to_vec() }).unwrap_or_default();
+    let mut ser = Serializer { data, sig: vec![], hint };
+    let r = value.serialize(&mut ser).and_then(|()| {
+        match expected {
+            Some(t) if t.as_ref().as_bytes() != &ser.sig[..] => Err(Error::WrongType {
+                expected: t.as_ref().to_owned(),
+                found: String::from_utf8_lossy(&ser.sig).into_owned(),
+            }),
+            /* the nesting of arrays and structs is only limited by checking the signature */
+            _ => {
+                let s = ::std::str::from_utf8(&ser.sig).map_err(|_| Error::Invalid("signature is not valid"))?;
+                Type::from_str(s).map_err(|e| marshal::encode_type_error(e, "signature is not valid"))?;
+                Ok(())
+            }
+        }
+    });
+
+    let sig = ser.sig;
+    if let Err(e) = r {
+        data.data.truncate(len);
+        data.fds.truncate(fds);
+        data.depth = depth;
+        return Err(e);
+    }
+    data.sig.extend(sig);
+    Ok(())
+}
+
+/**
+ * A serde `Serializer` that marshals into a `Data`. Use `append()` to create one.
+ */
+pub struct Serializer<'a> {
+    data: &'a mut Data,
+    /// The signature of what has been serialized
+    sig: Vec<u8>,
+    /// What is known of the signature ahead of `sig`, which gives the types of empty arrays
+    hint: Vec<u8>,
+}
+
+impl<'a> Serializer<'a> {
+    fn basic<T: DBusType>(&mut self, v: T) -> Result<(), Error> {
+        v.encode_into(self.data)?;
+        T::signature(&mut self.sig);
+        Ok(())
+    }
+
+    /*
+     * The complete type the hint has at `at` in the signature
+     */
+    fn hinted(&self, at: usize) -> Option<Vec<u8>> {
+        let h = self.hint.get(at..)?;
+        marshal::single_type_len(h).map(|l| h[..l].to_vec())
+    }
+
+    /*
+     * Get ready for an element after the first of an array whose element type starts at `at`. Its
+     * signature is written over that of the first, which is the hint for what it holds.
+     */
+    fn next_element(&mut self, at: usize, first: &[u8]) {
+        self.sig.truncate(at);
+        if self.hint.len() <= at {
+            self.hint = self.sig.clone();
+            self.hint.extend(first);
+        }
+    }
+
+    /*
+     * Check that an element after the first has the same type as it
+     */
+    fn same_element(&self, at: usize, first: &[u8]) -> Result<(), Error> {
+        if self.sig[at..] != *first {
+            return Err(Error::Invalid("the elements of a sequence or map have different types"));
+        }
+        Ok(())
+    }
+}
+
+/*
+ * The offset the first element of an array starts at, given where its length is
+ */
+fn elements_start(len_pos: usize, element: &[u8]) -> usize {
+    let align = marshal::alignment(element[0]);
+    (len_pos + 4).div_ceil(align) * align
+}
+
+/*
+ * Fill in the length of an array whose elements start at `start`, once they have been serialized
+ */
+fn array_end(data: &mut Data, len_pos: usize, start: usize) -> Result<(), Error> {
+    let len = data.data.len() - start;
+    if len > marshal::MAX_ARRAY_LEN {
+        return Err(Error::Encode(EncodeError::ArrayTooLong));
+    }
+    data.patch_u32(len_pos, len as u32);
+    data.leave();
+    Ok(())
+}
+
+impl<'s, 'a> ser::Serializer for &'s mut Serializer<'a> {
+    type Ok = ();
+    type Error = Error;
+    type SerializeSeq = Seq<'s, 'a>;
+    type SerializeTuple = Struct<'s, 'a>;
+    type SerializeTupleStruct = Struct<'s, 'a>;
+    type SerializeTupleVariant = Impossible<(), Error>;
+    type SerializeMap = Map<'s, 'a>;
+    type SerializeStruct = Struct<'s, 'a>;
+    type SerializeStructVariant = Impossible<(), Error>;
+
+    fn serialize_bool(self, v: bool) -> Result<(), Error> {
+        self.basic(v)
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<(), Error> {
+        self.basic(v as i16)
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<(), Error> {
+        self.basic(v)
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<(), Error> {
+        self.basic(v)
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<(), Error> {
+        self.basic(v)
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<(), Error> {
+        self.basic(v)
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<(), Error> {
+        self.basic(v)
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<(), Error> {
+        self.basic(v)
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<(), Error> {
+        self.basic(v)
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<(), Error> {
+        self.basic(v as f64)
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<(), Error> {
+        self.basic(v)
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), Error> {
+        self.basic(v.encode_utf8(&mut [0u8; 4]) as &str)
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), Error> {
+        self.basic(v)
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), Error> {
+        self.basic(v)
+    }
+
+    fn serialize_none(self) -> Result<(), Error> {
+        Err(Error::Invalid("None cannot be marshalled, skip it with #[serde(skip_serializing_if)]"))
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), Error> {
+        Err(Error::Invalid("units cannot be marshalled"))
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), Error> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(self, _name: &'static str, _index: u32, variant: &'static str)
+        -> Result<(), Error>
+    {
+        self.basic(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _name: &'static str, value: &T)
+        -> Result<(), Error>
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(self, _name: &'static str, _index: u32,
+                                                        _variant: &'static str, _value: &T)
+        -> Result<(), Error>
+    {
+        Err(Error::Invalid("enum variants with data cannot be marshalled"))
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Seq<'s, 'a>, Error> {
+        self.data.enter()?;
+        0u32.encode_into(self.data)?;
+        let len_pos = self.data.data.len() - 4;
+        self.sig.push(b'a');
+        let elem_at = self.sig.len();
+        Ok(Seq { ser: self, len_pos, elem_at, first: None })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Struct<'s, 'a>, Error> {
+        if len == 0 {
+            return Err(Error::Invalid("structs must have at least one field"));
+        }
+        self.data.enter()?;
+        unsafe {self.data.align_to(8);}
+        self.sig.push(b'(');
+        Ok(Struct { ser: self })
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, len: usize) -> Result<Struct<'s, 'a>, Error> {
+        self.serialize_tuple(len)
+    }
+
+    fn serialize_tuple_variant(self, _name: &'static str, _index: u32, _variant: &'static str,
+                               _len: usize)
+        -> Result<Impossible<(), Error>, Error>
+    {
+        Err(Error::Invalid("enum variants with data cannot be marshalled"))
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Map<'s, 'a>, Error> {
+        self.data.enter()?;
+        0u32.encode_into(self.data)?;
+        let len_pos = self.data.data.len() - 4;
+        unsafe {self.data.align_to(8);}
+        let start = self.data.data.len();
+        self.sig.extend(b"a{");
+        let entry_at = self.sig.len();
+        Ok(Map { ser: self, len_pos, start, entry_at, first: None })
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<Struct<'s, 'a>, Error> {
+        self.serialize_tuple(len)
+    }
+
+    fn serialize_struct_variant(self, _name: &'static str, _index: u32, _variant: &'static str,
+                                _len: usize)
+        -> Result<Impossible<(), Error>, Error>
+    {
+        Err(Error::Invalid("enum variants with data cannot be marshalled"))
+    }
+}
+
+/// Serializes the elements of an array
+pub struct Seq<'s, 'a: 's> {
+    ser: &'s mut Serializer<'a>,
+    len_pos: usize,
+    /// Where the element type starts in the signature
+    elem_at: usize,
+    /// The signature of the first element, and the offset it starts at
+    first: Option<(Vec<u8>, usize)>,
+}
+
+impl<'s, 'a> ser::SerializeSeq for Seq<'s, 'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        if let Some((ref first, _)) = self.first {
+            self.ser.next_element(self.elem_at, first);
+            value.serialize(&mut *self.ser)?;
+            return self.ser.same_element(self.elem_at, first);
+        }
+
+        value.serialize(&mut *self.ser)?;
+        let element = self.ser.sig[self.elem_at..].to_vec();
+        let start = elements_start(self.len_pos, &element);
+        self.first = Some((element, start));
+        Ok(())
+    }
+
+    fn end(self) -> Result<(), Error> {
+        let start = match self.first {
+            Some((_, start)) => start,
+            None => {
+                let element = self.ser.hinted(self.elem_at)
+                    .ok_or(Error::Invalid("the type of an empty sequence is not known, give a signature"))?;
+                unsafe {self.ser.data.align_to(marshal::alignment(element[0]));}
+                self.ser.sig.extend(element);
+                self.ser.data.data.len()
+            }
+        };
+        array_end(self.ser.data, self.len_pos, start)
+    }
+}
+
+/// Serializes the entries of a dict
+pub struct Map<'s, 'a: 's> {
+    ser: &'s mut Serializer<'a>,
+    len_pos: usize,
+    /// The offset the first entry starts at
+    start: usize,
+    /// Where the key type starts in the signature
+    entry_at: usize,
+    /// The key and value signature of the first entry
+    first: Option<Vec<u8>>,
+}
+
+impl<'s, 'a> ser::SerializeMap for Map<'s, 'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        if let Some(ref first) = self.first {
+            self.ser.next_element(self.entry_at, first);
+        }
+        self.ser.data.enter()?;
+        unsafe {self.ser.data.align_to(8);}
+        key.serialize(&mut *self.ser)?;
+        match self.ser.sig[self.entry_at..] {
+            [b'y'] | [b'b'] | [b'n'] | [b'q'] | [b'i'] | [b'u'] | [b'x'] | [b't'] | [b'd'] |
+            [b'h'] | [b's'] | [b'o'] | [b'g'] => Ok(()),
+            _ => Err(Error::Invalid("map keys must be basic types")),
+        }
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        value.serialize(&mut *self.ser)?;
+        self.ser.data.leave();
+        match self.first {
+            Some(ref first) => self.ser.same_element(self.entry_at, first),
+            None => {
+                self.first = Some(self.ser.sig[self.entry_at..].to_vec());
+                Ok(())
+            }
+        }
+    }
+
+    fn end(self) -> Result<(), Error> {
+        if self.first.is_none() {
+            let key = self.ser.hinted(self.entry_at);
+            let value = self.ser.hinted(self.entry_at + 1);
+            match (key, value) {
+                (Some(k), Some(v)) => {
+                    self.ser.sig.extend(k);
+                    self.ser.sig.extend(v);
+                }
+                _ => return Err(Error::Invalid("the type of an empty map is not known, give a signature")),
+            }
+        }
+        self.ser.sig.push(b'}');
+        array_end(self.ser.data, self.len_pos, self.start)
+    }
+}
+
+/// Serializes the fields of a struct
+pub struct Struct<'s, 'a: 's> {
+    ser: &'s mut Serializer<'a>,
+}
+
+impl<'s, 'a> Struct<'s, 'a> {
+    fn field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        value.serialize(&mut *self.ser)
+    }
+
+    fn finish(self) -> Result<(), Error> {
+        if self.ser.sig.last() == Some(&b'(') {
+            return Err(Error::Invalid("structs must have at least one field"));
+        }
+        self.ser.sig.push(b')');
+        self.ser.data.leave();
+        Ok(())
+    }
+}
+
+impl<'s, 'a> ser::SerializeTuple for Struct<'s, 'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.field(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl<'s, 'a> ser::SerializeTupleStruct for Struct<'s, 'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.field(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl<'s, 'a> ser::SerializeStruct for Struct<'s, 'a> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, _key: &'static str, value: &T) -> Result<(), Error> {
+        self.field(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use super::{append, append_with_signature, Error};
+    use marshal::Data;
+    use type_sig::Type;
+
+    #[derive(Serialize)]
+    struct Config {
+        name: String,
+        level: Level,
+        ids: Vec<(u8, u64)>,
+        env: BTreeMap<String, String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        extra: Option<u32>,
+    }
+
+    #[derive(Serialize)]
+    enum Level {
+        High,
+    }
+
+    #[derive(Serialize)]
+    struct Meters(f64);
+
+    fn native<F: FnOnce(&mut Data)>(f: F) -> Data {
+        let mut m = Data::new();
+        m.append(1u8).unwrap();
+        f(&mut m);
+        m
+    }
+
+    #[test]
+    fn same_as_native() {
+        let mut env = BTreeMap::new();
+        env.insert("A".to_owned(), "b".to_owned());
+        let c = Config {
+            name: "x".to_owned(),
+            level: Level::High,
+            ids: vec![(1, 2), (3, 4)],
+            env: env.clone(),
+            extra: Some(5),
+        };
+
+        let mut m = Data::new();
+        m.append(1u8).unwrap();
+        append(&mut m, &c).unwrap();
+        append(&mut m, &Meters(1.5)).unwrap();
+        append(&mut m, &[true, false][..]).unwrap();
+
+        let n = native(|n| {
+            n.append((&c.name, "High", &c.ids, &env, 5u32)).unwrap();
+            n.append(1.5f64).unwrap();
+            n.append(&[true, false][..]).unwrap();
+        });
+        assert_eq!(m.bytes(), n.bytes());
+        assert_eq!(m.signature_bytes(), n.signature_bytes());
+        assert_eq!(m.signature_bytes(), b"y(ssa(yt)a{ss}u)dab");
+    }
+
+    #[test]
+    fn empty_containers() {
+        let empty: Vec<Vec<u64>> = vec![vec![], vec![]];
+        let mut m = Data::new();
+        match append(&mut m, &empty) {
+            Err(Error::Invalid(_)) => {}
+            r => panic!("{:?}", r),
+        }
+        assert!(m.bytes().is_empty());
+        assert!(m.signature_bytes().is_empty());
+
+        /* the signature gives the type of empty arrays, including those after the first element */
+        let v: Vec<Vec<u64>> = vec![vec![5], vec![]];
+        append_with_signature(&mut m, &v, Type::from_str("aat").unwrap()).unwrap();
+        append_with_signature(&mut m, &empty, Type::from_str("aat").unwrap()).unwrap();
+        append_with_signature(&mut m, &BTreeMap::<String, u8>::new(), Type::from_str("a{sy}").unwrap()).unwrap();
+        let mut n = Data::new();
+        n.append(&v).unwrap();
+        n.append(&empty).unwrap();
+        n.append(BTreeMap::<String, u8>::new()).unwrap();
+        assert_eq!(m.bytes(), n.bytes());
+        assert_eq!(m.signature_bytes(), b"aataata{sy}");
+
+        /* after the first element, the rest are inferred to be the same */
+        let mut m = Data::new();
+        let v: Vec<(u8, Vec<u32>)> = vec![(1, vec![2]), (3, vec![])];
+        append(&mut m, &v).unwrap();
+        let mut n = Data::new();
+        n.append(&v).unwrap();
+        assert_eq!(m.bytes(), n.bytes());
+    }
+
+    #[test]
+    fn errors() {
+        let mut m = Data::new();
+        match append(&mut m, &vec![Some(1u8), None]) {
+            Err(Error::Invalid(_)) => {}
+            r => panic!("{:?}", r),
+        }
+        match append_with_signature(&mut m, &1u8, Type::from_str("u").unwrap()) {
+            Err(Error::WrongType { ref expected, ref found }) if expected == "u" && found == "y" => {}
+            r => panic!("{:?}", r),
+        }
+        let mut k = BTreeMap::new();
+        k.insert(vec![1u8], 1u8);
+        match append(&mut m, &k) {
+            Err(Error::Invalid("map keys must be basic types")) => {}
+            r => panic!("{:?}", r),
+        }
+        assert!(append(&mut m, &()).is_err());
+        assert!(m.bytes().is_empty());
+    }
+}
@@ -0,0 +1,594 @@
+//! Marshal values straight to an `io::Write`, or into `IoSlice`s for `writev()`
+//!
+//! `marshal::Data` holds everything it encodes in a single buffer. `Encoder` instead borrows the
+//! byte arrays given to `append_bytes()` rather than copying them, and hands out bytes as soon as
+//! they are final. The length of an array is only known once it has ended, so the bytes from the
+//! length of the outermost array still open onwards are held back until then.
+//!
+//! Alignment is from the first byte encoded, which must be the start of a message or of something
+//! on an 8 byte boundary within one, such as a message body.
+
+use std::cmp;
+use std::io::{self, IoSlice, Write};
+use std::mem;
+use std::os::unix::io::OwnedFd;
+
+use header::{self, Endian};
+use marshal::{self, DBusType, Data, EncodeError, MAX_ARRAY_LEN};
+use type_sig::{Type, TypeError, MAX_ARRAY_DEPTH, MAX_STRUCT_DEPTH};
+
+/*
+ * A container that has been started and not yet ended. `sig_at` is where its signature starts.
+ */
+enum Open {
+    /// The length is at `len_at` in the buffer, and the elements start at the offset `elements`
+    Array { len_at: usize, elements: usize, sig_at: usize, element: Vec<u8> },
+    /// `close` ends the signature of a struct (')') or a dict entry ('}')
+    Struct { close: u8, sig_at: usize },
+}
+
+/*
+ * How deeply arrays, and structs and dict entries, nest within the single complete type `sig`
+ */
+fn nesting(sig: &[u8]) -> (usize, usize) {
+    match sig.first() {
+        Some(&b'a') => {
+            let (arrays, structs) = nesting(&sig[1..]);
+            (arrays + 1, structs)
+        }
+        Some(&b'(') | Some(&b'{') => {
+            let (mut arrays, mut structs) = (0, 0);
+            let mut rest = &sig[1..sig.len() - 1];
+            while let Some(l) = marshal::single_type_len(rest) {
+                let (a, s) = nesting(&rest[..l]);
+                arrays = cmp::max(arrays, a);
+                structs = cmp::max(structs, s);
+                rest = &rest[l..];
+            }
+            (arrays, structs + 1)
+        }
+        _ => (0, 0),
+    }
+}
+
+/**
+ * Encodes values in the D-Bus wire format, handing out the bytes as they become final
+ *
+ * Values are added with `append()`, and byte arrays with `append_bytes()`, which borrows them for
+ * `'a`. Arrays of other types, structs, and dict entries may also be built up a value at a time
+ * with `start_array()`, `start_struct()`, and `start_dict_entry()`.
+ *
+ * If appending a value fails nothing of it is encoded, as with `Data::append()`. If ending a
+ * container fails the encoder should be discarded.
+ */
+pub struct Encoder<'a> {
+    /// Encoded bytes that have not been consumed, other than the borrowed byte arrays
+    buf: Vec<u8>,
+    /// Byte arrays, each following the bytes of `buf` before the index it is paired with
+    borrowed: Vec<(usize, &'a [u8])>,
+    /// The offset of the next byte to be encoded, from the first
+    pos: usize,
+    endian: Endian,
+    sig: Vec<u8>,
+    fds: Vec<OwnedFd>,
+    open: Vec<Open>,
+}
+
+impl<'a> Encoder<'a> {
+    /// Create an encoder which marshals values in the byte order `endian`
+    pub fn new(endian: Endian) -> Encoder<'a> {
+        Encoder { buf: vec![], borrowed: vec![], pos: 0, endian, sig: vec![], fds: vec![], open: vec![] }
+    }
+
+    /// The offset of the next byte to be encoded, which is the number of bytes encoded so far
+    pub fn offset(&self) -> usize {
+        self.pos
+    }
+
+    /**
+     * The signature of the values encoded, as the `SIGNATURE` header field of a message holds it.
+     *
+     * This is only complete once every container has ended.
+     */
+    pub fn signature(&self) -> Result<Type<'_>, TypeError> {
+        let s = ::std::str::from_utf8(&self.sig)
+            .map_err(|_| TypeError::Invalid(::std::char::REPLACEMENT_CHARACTER))?;
+        Type::from_str(s)
+    }
+
+    /// The unix fds refered to by the encoded values, in index order
+    pub fn fds(&self) -> &[OwnedFd] {
+        &self.fds
+    }
+
+    /// Remove the unix fds, leaving the indexes that refered to them dangling
+    pub fn take_fds(&mut self) -> Vec<OwnedFd> {
+        mem::take(&mut self.fds)
+    }
+
+    /**
+     * Marshal `value`, and append its type to the signature
+     */
+    pub fn append<T: DBusType>(&mut self, value: T) -> Result<(), EncodeError> {
+        let mut sig = vec![];
+        T::signature(&mut sig);
+        let s = ::std::str::from_utf8(&sig).map_err(|_| EncodeError::Invalid("signature is not valid"))?;
+        Type::from_str(s).map_err(|e| marshal::encode_type_error(e, "signature is not valid"))?;
+        let (arrays, structs) = nesting(&sig);
+        self.check_depth(arrays, structs)?;
+
+        let from = self.sig.len();
+        self.sig.extend(sig);
+        self.complete(from)?;
+
+        /* `Data` aligns from its start, so give it the same alignment as the next byte has */
+        let pad = self.pos % 8;
+        let fds = self.fds.len();
+        let mut d = Data::from_parts(vec![0; pad], vec![], mem::take(&mut self.fds), self.endian);
+        d.depth = self.open.len();
+        let r = value.encode_into(&mut d);
+        self.fds = d.take_fds();
+        if let Err(e) = r {
+            self.fds.truncate(fds);
+            self.sig.truncate(from);
+            return Err(e);
+        }
+
+        self.buf.extend_from_slice(&d.bytes()[pad..]);
+        self.pos += d.bytes().len() - pad;
+        Ok(())
+    }
+
+    /**
+     * Append an array of bytes (type 'ay'), which is borrowed rather than copied
+     */
+    pub fn append_bytes(&mut self, b: &'a [u8]) -> Result<(), EncodeError> {
+        self.check_depth(1, 0)?;
+        if b.len() > MAX_ARRAY_LEN {
+            return Err(EncodeError::ArrayTooLong);
+        }
+        let from = self.sig.len();
+        self.sig.extend(b"ay");
+        self.complete(from)?;
+
+        self.pad(4);
+        self.u32(b.len() as u32);
+        if !b.is_empty() {
+            self.borrowed.push((self.buf.len(), b));
+            self.pos += b.len();
+        }
+        Ok(())
+    }
+
+    /**
+     * Start an array of the type `array`, such as 'ai' or 'a{sv}'. Each value appended is an
+     * element, until `end_array()` is called.
+     */
+    pub fn start_array(&mut self, array: Type) -> Result<(), EncodeError> {
+        let array = array.as_ref().as_bytes();
+        if array.first() != Some(&b'a') || marshal::single_type_len(array) != Some(array.len()) {
+            return Err(EncodeError::Invalid("array type is not a single array type"));
+        }
+        if self.element_type().is_some_and(|e| e != array) {
+            return Err(EncodeError::Invalid("array element does not have the array's element type"));
+        }
+        let element = &array[1..];
+        let (arrays, structs) = nesting(array);
+        self.check_depth(arrays, structs)?;
+
+        self.pad(4);
+        let len_at = self.buf.len();
+        self.u32(0);
+        self.pad(marshal::alignment(element[0]));
+        let sig_at = self.sig.len();
+        self.sig.push(b'a');
+        self.sig.extend(element);
+        self.open.push(Open::Array { len_at, elements: self.pos, sig_at, element: element.to_vec() });
+        Ok(())
+    }
+
+    /**
+     * End the array started last, filling in its length
+     */
+    pub fn end_array(&mut self) -> Result<(), EncodeError> {
+        let (len_at, elements, sig_at) = match self.open.last() {
+            Some(&Open::Array { len_at, elements, sig_at, .. }) => (len_at, elements, sig_at),
+            _ => return Err(EncodeError::Invalid("the innermost open container is not an array")),
+        };
+        self.open.pop();
+
+        let len = self.pos - elements;
+        if len > MAX_ARRAY_LEN {
+            return Err(EncodeError::ArrayTooLong);
+        }
+        let len = len as u32;
+        let len = if self.endian == header::ENDIAN_BIG { len.to_be_bytes() } else { len.to_le_bytes() };
+        self.buf[len_at..len_at + 4].copy_from_slice(&len);
+        self.complete(sig_at)
+    }
+
+    /**
+     * Start a struct. Each value appended is a field, until `end_struct()` is called.
+     */
+    pub fn start_struct(&mut self) -> Result<(), EncodeError> {
+        self.start(b'(', b')')
+    }
+
+    /**
+     * Start a dict entry, which must be an element of an array. The key and then the value are
+     * appended, and `end_struct()` ends it.
+     */
+    pub fn start_dict_entry(&mut self) -> Result<(), EncodeError> {
+        match self.open.last() {
+            Some(&Open::Array { .. }) => self.start(b'{', b'}'),
+            _ => Err(EncodeError::Invalid("dict entries must be the elements of an array")),
+        }
+    }
+
+    fn start(&mut self, open: u8, close: u8) -> Result<(), EncodeError> {
+        if self.element_type().is_some_and(|e| e[0] != open) {
+            return Err(EncodeError::Invalid("array element does not have the array's element type"));
+        }
+        self.check_depth(0, 1)?;
+        self.pad(8);
+        self.open.push(Open::Struct { close, sig_at: self.sig.len() });
+        self.sig.push(open);
+        Ok(())
+    }
+
+    /**
+     * End the struct or dict entry started last
+     */
+    pub fn end_struct(&mut self) -> Result<(), EncodeError> {
+        let (close, sig_at) = match self.open.last() {
+            Some(&Open::Struct { close, sig_at }) => (close, sig_at),
+            _ => return Err(EncodeError::Invalid("the innermost open container is not a struct or dict entry")),
+        };
+        if self.sig.len() == sig_at + 1 {
+            return Err(EncodeError::Invalid("structs must have at least one field"));
+        }
+        self.open.pop();
+        self.sig.push(close);
+        self.complete(sig_at)
+    }
+
+    /*
+     * Check that a value with `arrays` arrays and `structs` structs nested within it may be added
+     * inside the containers that are open
+     */
+    fn check_depth(&self, arrays: usize, structs: usize) -> Result<(), EncodeError> {
+        let open_arrays = self.open.iter().filter(|o| matches!(**o, Open::Array { .. })).count();
+        if open_arrays + arrays > MAX_ARRAY_DEPTH {
+            return Err(EncodeError::ArrayTooDeep);
+        }
+        if self.open.len() - open_arrays + structs > MAX_STRUCT_DEPTH {
+            return Err(EncodeError::StructTooDeep);
+        }
+        Ok(())
+    }
+
+    /*
+     * The element type of the array open innermost, if what is added now is one of its elements
+     */
+    fn element_type(&self) -> Option<&[u8]> {
+        match self.open.last() {
+            Some(Open::Array { element, .. }) => Some(element),
+            _ => None,
+        }
+    }
+
+    /*
+     * A complete type has been added to the signature at `from`. Within an array it is an element,
+     * which must have the element type, and is not repeated in the signature.
+     */
+    fn complete(&mut self, from: usize) -> Result<(), EncodeError> {
+        if let Some(Open::Array { element, .. }) = self.open.last() {
+            let same = self.sig[from..] == element[..];
+            self.sig.truncate(from);
+            if !same {
+                return Err(EncodeError::Invalid("array element does not have the array's element type"));
+            }
+        }
+        Ok(())
+    }
+
+    fn pad(&mut self, align: usize) {
+        while self.pos % align != 0 {
+            self.buf.push(0);
+            self.pos += 1;
+        }
+    }
+
+    fn u32(&mut self, v: u32) {
+        let v = if self.endian == header::ENDIAN_BIG { v.to_be_bytes() } else { v.to_le_bytes() };
+        self.buf.extend_from_slice(&v);
+        self.pos += 4;
+    }
+
+    /*
+     * How much of `buf` is final: everything before the length of the outermost open array
+     */
+    fn ready(&self) -> usize {
+        self.open.iter()
+            .filter_map(|o| match *o { Open::Array { len_at, .. } => Some(len_at), _ => None })
+            .next()
+            .unwrap_or(self.buf.len())
+    }
+
+    /**
+     * The bytes that are final and have not been consumed, in order, for `writev()` or the like.
+     * Call `consume()` with however many of them are written.
+     */
+    pub fn io_slices(&self) -> Vec<IoSlice<'_>> {
+        let ready = self.ready();
+        let mut slices = vec![];
+        let mut from = 0;
+        for &(at, b) in self.borrowed.iter().take_while(|&&(at, _)| at <= ready) {
+            slices.push(IoSlice::new(&self.buf[from..at]));
+            slices.push(IoSlice::new(b));
+            from = at;
+        }
+        slices.push(IoSlice::new(&self.buf[from..ready]));
+        slices.retain(|s| !s.is_empty());
+        slices
+    }
+
+    /**
+     * Drop the first `n` bytes of `io_slices()`, once they have been written
+     *
+     * Panics if `n` is more than `io_slices()` holds.
+     */
+    pub fn consume(&mut self, mut n: usize) {
+        let ready = self.ready();
+        let available = ready + self.borrowed.iter()
+            .take_while(|&&(at, _)| at <= ready)
+            .map(|&(_, b)| b.len())
+            .sum::<usize>();
+        assert!(n <= available, "consumed more bytes than are ready");
+
+        let mut drained = 0;
+        while n > 0 {
+            let next = match self.borrowed.first() {
+                Some(&(at, _)) if at <= ready => at,
+                _ => ready,
+            };
+            let k = cmp::min(n, next - drained);
+            drained += k;
+            n -= k;
+            if n == 0 {
+                break;
+            }
+
+            let b = self.borrowed[0].1;
+            let k = cmp::min(n, b.len());
+            n -= k;
+            if k == b.len() {
+                self.borrowed.remove(0);
+            } else {
+                self.borrowed[0].1 = &b[k..];
+            }
+        }
+
+        self.buf.drain(..drained);
+        for &mut (ref mut at, _) in &mut self.borrowed {
+            *at -= drained;
+        }
+        for o in &mut self.open {
+            if let Open::Array { ref mut len_at, .. } = *o {
+                *len_at -= drained;
+            }
+        }
+    }
+
+    /**
+     * Write the bytes that are final to `w`, using `write_vectored()` so that borrowed byte arrays
+     * are not copied. Once every container has ended, this writes everything that is left.
+     */
+    pub fn write_to<W: Write + ?Sized>(&mut self, w: &mut W) -> io::Result<()> {
+        loop {
+            let r = {
+                let slices = self.io_slices();
+                if slices.is_empty() {
+                    return Ok(());
+                }
+                w.write_vectored(&slices)
+            };
+            match r {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write encoded bytes")),
+                Ok(n) => self.consume(n),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use std::io::{self, Write};
+
+    use super::Encoder;
+    use header::{ENDIAN_BIG, ENDIAN_LITTLE as LE};
+    use marshal::{Data, EncodeError, Variant, MAX_DEPTH};
+    use type_sig::{Type, MAX_ARRAY_DEPTH, MAX_STRUCT_DEPTH};
+    use value::Value;
+
+    /* a writer which takes at most `max` bytes per call */
+    struct Trickle {
+        out: Vec<u8>,
+        max: usize,
+    }
+
+    impl Write for Trickle {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let n = ::std::cmp::min(buf.len(), self.max);
+            self.out.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn same_as_data() {
+        let bytes = [1u8, 2, 3, 4, 5];
+        let mut d = BTreeMap::new();
+        d.insert("k", Variant(7u16));
+
+        for &endian in &[LE, ENDIAN_BIG] {
+            let mut m = Data::with_endian(endian);
+            m.append(1u8).unwrap();
+            m.append(&bytes[..]).unwrap();
+            m.append(vec![(2u8, 3u64), (4, 5)]).unwrap();
+            m.append(&d).unwrap();
+            m.append((6u8, "x")).unwrap();
+
+            let mut e = Encoder::new(endian);
+            e.append(1u8).unwrap();
+            e.append_bytes(&bytes).unwrap();
+            e.start_array(Type::from_str("a(yt)").unwrap()).unwrap();
+            e.append((2u8, 3u64)).unwrap();
+            e.start_struct().unwrap();
+            e.append(4u8).unwrap();
+            e.append(5u64).unwrap();
+            e.end_struct().unwrap();
+            e.end_array().unwrap();
+            e.start_array(Type::from_str("a{sv}").unwrap()).unwrap();
+            e.start_dict_entry().unwrap();
+            e.append("k").unwrap();
+            e.append(Variant(7u16)).unwrap();
+            e.end_struct().unwrap();
+            e.end_array().unwrap();
+            e.start_struct().unwrap();
+            e.append(6u8).unwrap();
+            e.append("x").unwrap();
+            e.end_struct().unwrap();
+
+            assert_eq!(e.signature().unwrap(), m.signature().unwrap());
+            assert_eq!(e.offset(), m.bytes().len());
+            let mut w = Trickle { out: vec![], max: 3 };
+            e.write_to(&mut w).unwrap();
+            assert_eq!(w.out, m.bytes());
+            assert!(e.io_slices().is_empty());
+        }
+    }
+
+    /* bytes from the length of an open array on are held back until it ends */
+    #[test]
+    fn streaming() {
+        let big = vec![9u8; 1000];
+        let mut e = Encoder::new(LE);
+        let mut w = Trickle { out: vec![], max: usize::MAX };
+        e.append(1u8).unwrap();
+        e.append_bytes(&big).unwrap();
+        e.start_array(Type::from_str("aay").unwrap()).unwrap();
+        e.append_bytes(&big[..3]).unwrap();
+
+        /* the byte array is handed out as it was given, and the open array is held back */
+        let slices = e.io_slices();
+        assert_eq!(slices.len(), 2);
+        assert_eq!(slices[1].as_ptr(), big.as_ptr());
+        drop(slices);
+
+        e.write_to(&mut w).unwrap();
+        assert_eq!(w.out.len(), 1008);
+        e.append_bytes(&[]).unwrap();
+        e.end_array().unwrap();
+        e.consume(2);
+        e.write_to(&mut w).unwrap();
+
+        let mut m = Data::new();
+        m.append(1u8).unwrap();
+        m.append(&big[..]).unwrap();
+        m.append(vec![&big[..3], &[]]).unwrap();
+        assert_eq!(w.out[..1008], m.bytes()[..1008]);
+        assert_eq!(w.out[1008..], m.bytes()[1010..]);
+        assert_eq!(e.signature().unwrap(), m.signature().unwrap());
+    }
+
+    #[test]
+    fn errors() {
+        let mut e = Encoder::new(LE);
+        e.start_array(Type::from_str("au").unwrap()).unwrap();
+        match e.append(1u8) {
+            Err(EncodeError::Invalid(_)) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+        /* the failed element left nothing behind */
+        e.append(2u32).unwrap();
+        e.end_array().unwrap();
+        assert_eq!(e.offset(), 8);
+        assert_eq!(e.signature().unwrap(), Type::from_str("au").unwrap());
+
+        e.end_array().err().unwrap();
+        e.start_array(Type::from_str("u").unwrap()).err().unwrap();
+        e.start_dict_entry().err().unwrap();
+        e.start_struct().unwrap();
+        e.end_struct().err().unwrap();
+        e.end_array().err().unwrap();
+
+        /* containers started within an array must be its elements, and leave nothing if not */
+        let mut e = Encoder::new(LE);
+        e.start_array(Type::from_str("a(y)").unwrap()).unwrap();
+        let offset = e.offset();
+        e.start_array(Type::from_str("ay").unwrap()).err().unwrap();
+        e.start_dict_entry().err().unwrap();
+        assert_eq!(e.offset(), offset);
+        e.start_struct().unwrap();
+        e.append(1u8).unwrap();
+        e.end_struct().unwrap();
+        e.end_array().unwrap();
+        assert_eq!(e.signature().unwrap(), Type::from_str("a(y)").unwrap());
+
+        let mut e = Encoder::new(LE);
+        e.start_array(Type::from_str("aay").unwrap()).unwrap();
+        let offset = e.offset();
+        e.start_array(Type::from_str("au").unwrap()).err().unwrap();
+        e.start_struct().err().unwrap();
+        assert_eq!(e.offset(), offset);
+        e.start_array(Type::from_str("ay").unwrap()).unwrap();
+        e.end_array().unwrap();
+        e.end_array().unwrap();
+        assert_eq!(e.signature().unwrap(), Type::from_str("aay").unwrap());
+
+        /* structs nest at most 32 deep, counting those within appended values */
+        let mut e = Encoder::new(LE);
+        for _ in 0..MAX_STRUCT_DEPTH {
+            e.start_struct().unwrap();
+        }
+        match e.start_struct() {
+            Err(EncodeError::StructTooDeep) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+        match e.append((1u8,)) {
+            Err(EncodeError::StructTooDeep) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+        e.append(Variant(1u8)).unwrap();
+
+        /* variants inside them count towards the total depth */
+        let mut v = Value::Byte(1);
+        for _ in 0..MAX_DEPTH - MAX_STRUCT_DEPTH - 1 {
+            v = Value::Variant(Box::new(v));
+        }
+        e.append(&v).unwrap();
+        match e.append(Value::Variant(Box::new(v))) {
+            Err(EncodeError::TooDeep) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+
+        /* as do arrays, counting those within the type of an array started */
+        let mut e = Encoder::new(LE);
+        e.start_array(Type::from_str("a(y)").unwrap()).unwrap();
+        e.start_struct().unwrap();
+        let deepest = format!("{}y", "a".repeat(MAX_ARRAY_DEPTH));
+        match e.start_array(Type::from_str(&deepest).unwrap()) {
+            Err(EncodeError::ArrayTooDeep) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+        e.start_array(Type::from_str(&deepest[1..]).unwrap()).unwrap();
+    }
+}
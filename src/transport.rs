@@ -0,0 +1,353 @@
+//! The byte streams a connection can run over
+//!
+//! Besides moving bytes, unix sockets can carry file descriptors as `SCM_RIGHTS` ancillary data.
+//! Transports that cannot do so keep the default methods, which refuse to send fds and never
+//! receive any.
+
+use libc;
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::os::unix::io::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::ptr;
+use std::time::Duration;
+
+use address::Family;
+
+/// Most fds a single `sendmsg()` may carry on linux (`SCM_MAX_FD`)
+pub const MAX_FDS: usize = 253;
+
+/**
+ * Unix fds received by `Transport::recv_with_fds()`, in the order they arrived
+ *
+ * This also keeps the buffer that `recvmsg()` fills with ancillary data, so reusing one `RecvFds`
+ * for every read allocates it only once.
+ */
+#[derive(Default)]
+pub struct RecvFds {
+    fds: VecDeque<OwnedFd>,
+    cmsg: Vec<u64>,
+}
+
+impl RecvFds {
+    pub fn new() -> RecvFds {
+        RecvFds::default()
+    }
+
+    pub fn push(&mut self, fd: OwnedFd) {
+        self.fds.push_back(fd);
+    }
+
+    pub fn len(&self) -> usize {
+        self.fds.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fds.is_empty()
+    }
+
+    /// Remove the first `n` fds, or return `None` if fewer than `n` have been received
+    pub fn take(&mut self, n: usize) -> Option<Vec<OwnedFd>> {
+        if self.fds.len() < n {
+            return None;
+        }
+        Some(self.fds.drain(..n).collect())
+    }
+}
+
+pub trait Transport: Read + Write {
+    /// Whether `send_with_fds()` can actually send fds
+    fn supports_fds(&self) -> bool {
+        false
+    }
+
+    /**
+     * Read bytes, appending any fds that arrive with them to `fds`
+     */
+    fn recv_with_fds(&mut self, buf: &mut [u8], _fds: &mut RecvFds) -> io::Result<usize> {
+        self.read(buf)
+    }
+
+    /**
+     * Write bytes, sending `fds` along with the first of them
+     *
+     * As with `write()`, not all of `buf` may be sent. The fds are sent if any bytes are.
+     */
+    fn send_with_fds(&mut self, buf: &[u8], fds: &[BorrowedFd]) -> io::Result<usize> {
+        if !fds.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "transport cannot send unix fds"));
+        }
+        self.write(buf)
+    }
+
+    /**
+     * Limit how long a receive may block, after which it fails with `WouldBlock` or `TimedOut`.
+     * `None` blocks indefinitely.
+     */
+    fn set_read_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "transport does not support read timeouts"))
+    }
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn supports_fds(&self) -> bool {
+        (**self).supports_fds()
+    }
+
+    fn recv_with_fds(&mut self, buf: &mut [u8], fds: &mut RecvFds) -> io::Result<usize> {
+        (**self).recv_with_fds(buf, fds)
+    }
+
+    fn send_with_fds(&mut self, buf: &[u8], fds: &[BorrowedFd]) -> io::Result<usize> {
+        (**self).send_with_fds(buf, fds)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        (**self).set_read_timeout(timeout)
+    }
+}
+
+/**
+ * Write all of `buf`, with `fds` attached to the first byte
+ */
+pub fn send_all<T: Transport + ?Sized>(t: &mut T, mut buf: &[u8], mut fds: &[BorrowedFd]) -> io::Result<()> {
+    while !buf.is_empty() {
+        match t.send_with_fds(buf, fds) {
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write whole message")),
+            Ok(n) => {
+                buf = &buf[n..];
+                fds = &[];
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    t.flush()
+}
+
+/* control message buffer, aligned suitably for a cmsghdr */
+fn cmsg_buf(nfds: usize) -> Vec<u64> {
+    let len = unsafe { libc::CMSG_SPACE((nfds * mem::size_of::<RawFd>()) as u32) } as usize;
+    vec![0u64; len.div_ceil(mem::size_of::<u64>())]
+}
+
+impl Transport for UnixStream {
+    fn supports_fds(&self) -> bool {
+        true
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn recv_with_fds(&mut self, buf: &mut [u8], fds: &mut RecvFds) -> io::Result<usize> {
+        if fds.cmsg.is_empty() {
+            fds.cmsg = cmsg_buf(MAX_FDS);
+        }
+        let cbuf = &mut fds.cmsg;
+        let mut iov = libc::iovec { iov_base: buf.as_mut_ptr() as *mut libc::c_void, iov_len: buf.len() };
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = cbuf.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = (cbuf.len() * mem::size_of::<u64>()) as _;
+
+        let n = unsafe { libc::recvmsg(self.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+            while !cmsg.is_null() {
+                if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                    let len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                    let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                    for i in 0..len / mem::size_of::<RawFd>() {
+                        fds.push(OwnedFd::from_raw_fd(ptr::read_unaligned(data.add(i))));
+                    }
+                }
+                cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+            }
+        }
+
+        if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "received unix fds were truncated"));
+        }
+
+        Ok(n as usize)
+    }
+
+    fn send_with_fds(&mut self, buf: &[u8], fds: &[BorrowedFd]) -> io::Result<usize> {
+        if fds.len() > MAX_FDS {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "too many unix fds in one message"));
+        }
+
+        let fds_len = (fds.len() * mem::size_of::<RawFd>()) as u32;
+        let mut cbuf = cmsg_buf(fds.len());
+        let mut iov = libc::iovec { iov_base: buf.as_ptr() as *mut libc::c_void, iov_len: buf.len() };
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+
+        if !fds.is_empty() {
+            msg.msg_control = cbuf.as_mut_ptr() as *mut libc::c_void;
+            msg.msg_controllen = unsafe { libc::CMSG_SPACE(fds_len) } as _;
+            unsafe {
+                let cmsg = libc::CMSG_FIRSTHDR(&msg);
+                (*cmsg).cmsg_level = libc::SOL_SOCKET;
+                (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+                (*cmsg).cmsg_len = libc::CMSG_LEN(fds_len) as _;
+                let data = libc::CMSG_DATA(cmsg) as *mut RawFd;
+                for (i, fd) in fds.iter().enumerate() {
+                    ptr::write_unaligned(data.add(i), fd.as_raw_fd());
+                }
+            }
+        }
+
+        let n = unsafe { libc::sendmsg(self.as_raw_fd(), &msg, libc::MSG_NOSIGNAL) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(n as usize)
+    }
+}
+
+impl Transport for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+/// Length of the nonce a `nonce-tcp:` client must send before anything else
+pub const NONCE_LEN: usize = 16;
+
+/**
+ * Connect to a `tcp:` address
+ *
+ * Every address `host` resolves to is tried in turn, skipping those outside `family` if it is
+ * given. A missing `host` means `localhost`.
+ */
+pub fn connect_tcp(host: Option<&str>, port: u16, family: Option<Family>) -> io::Result<TcpStream> {
+    let host = host.unwrap_or("localhost");
+    let mut last_err = None;
+    for a in (host, port).to_socket_addrs()? {
+        match family {
+            Some(Family::Ipv4) if !a.is_ipv4() => continue,
+            Some(Family::Ipv6) if !a.is_ipv6() => continue,
+            _ => {}
+        }
+
+        match TcpStream::connect(a) {
+            Ok(s) => {
+                s.set_nodelay(true)?;
+                return Ok(s);
+            }
+            Err(e) => last_err = Some(e),
+        }
+    }
+
+    Err(last_err.unwrap_or_else(|| {
+        let msg = format!("'{}' has no addresses of the requested family", host);
+        io::Error::new(io::ErrorKind::NotFound, msg)
+    }))
+}
+
+/**
+ * Connect to a `nonce-tcp:` address
+ *
+ * The server only talks to clients that prove they can read its nonce file, by sending the 16
+ * bytes it holds before the auth conversation starts.
+ */
+pub fn connect_nonce_tcp(host: Option<&str>, port: u16, family: Option<Family>, noncefile: &Path)
+    -> io::Result<TcpStream>
+{
+    let mut nonce = vec![];
+    fs::File::open(noncefile)?.take(NONCE_LEN as u64 + 1).read_to_end(&mut nonce)?;
+    if nonce.len() != NONCE_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+            format!("nonce file '{}' does not hold exactly {} bytes", noncefile.display(), NONCE_LEN)));
+    }
+
+    let mut s = connect_tcp(host, port, family)?;
+    s.write_all(&nonce)?;
+    Ok(s)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{connect_nonce_tcp, connect_tcp, send_all, RecvFds, Transport};
+    use address::Family;
+    use std::fs;
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use std::net::TcpListener;
+    use std::os::unix::io::AsFd;
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+
+    #[test]
+    fn pass_fds() {
+        let (mut a, mut b) = UnixStream::pair().unwrap();
+        let path = ::std::env::temp_dir().join(format!("rdbus-test-{}-fds", ::std::process::id()));
+        let mut f = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true)
+            .open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        f.write_all(b"through the socket").unwrap();
+
+        send_all(&mut a, b"hello", &[f.as_fd(), f.as_fd()]).unwrap();
+        send_all(&mut a, b" world", &[]).unwrap();
+        drop(a);
+
+        let mut fds = RecvFds::new();
+        let mut buf = [0u8; 64];
+        let mut got = vec![];
+        loop {
+            let n = b.recv_with_fds(&mut buf, &mut fds).unwrap();
+            if n == 0 {
+                break;
+            }
+            got.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(got, b"hello world");
+        assert_eq!(fds.len(), 2);
+
+        let mut r = fs::File::from(fds.take(2).unwrap().pop().unwrap());
+        r.seek(SeekFrom::Start(0)).unwrap();
+        let mut s = String::new();
+        r.read_to_string(&mut s).unwrap();
+        assert_eq!(s, "through the socket");
+    }
+
+    #[test]
+    fn tcp_nonce() {
+        let l = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = l.local_addr().unwrap().port();
+        let t = thread::spawn(move || {
+            let (mut s, _) = l.accept().unwrap();
+            let mut got = vec![];
+            s.read_to_end(&mut got).unwrap();
+            got
+        });
+
+        let path = ::std::env::temp_dir().join(format!("rdbus-test-{}-nonce", ::std::process::id()));
+        fs::write(&path, b"0123456789abcdef").unwrap();
+        let mut s = connect_nonce_tcp(Some("127.0.0.1"), port, Some(Family::Ipv4), &path).unwrap();
+        assert!(!s.supports_fds());
+        send_all(&mut s, b"\0AUTH", &[]).unwrap();
+        let f = s.try_clone().unwrap();
+        send_all(&mut s, b"x", &[f.as_fd()]).err().unwrap();
+        drop(f);
+        drop(s);
+        assert_eq!(t.join().unwrap(), b"0123456789abcdef\0AUTH");
+
+        fs::write(&path, b"too short").unwrap();
+        connect_nonce_tcp(Some("127.0.0.1"), port, None, &path).err().unwrap();
+        fs::remove_file(&path).unwrap();
+
+        connect_tcp(Some("127.0.0.1"), port, Some(Family::Ipv6)).err().unwrap();
+    }
+}
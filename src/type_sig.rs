@@ -4,96 +4,222 @@
  *  - builder vs completed
  *
  * TODO:
- *  - variants have a depth limit of 64
  *  - variants must only have a single type
  *  - some contexts may allow 'r' (struct) or 'e' (entry), but these don't
  *
  */
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Type<'a> {
     v: &'a str
 }
 
+/// An owned type signature, for holding on to signatures received from the bus
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TypeBuf {
+    v: String
+}
+
 #[derive(Debug)]
 pub enum TypeError {
     Invalid(char),
     ParenUnclosed(u64),
     ElementRequired,
     ParenClosedBeforeOpen,
+    DictEntryNotInArray,
+    DictEntryFields,
+    DictKeyNotBasic,
+    StructEmpty,
+    ArrayTooDeep,
+    StructTooDeep,
 }
 
+/// Arrays may be nested at most this deep within a signature
+pub const MAX_ARRAY_DEPTH: usize = 32;
+
+/// Structs and dict entries may be nested at most this deep within a signature
+pub const MAX_STRUCT_DEPTH: usize = 32;
+
 impl ::std::fmt::Display for TypeError {
     fn fmt(&self, fmt: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
-        match self {
-            &TypeError::Invalid(c) => write!(fmt, "Type spec contained invalid character '{}'", c),
-            &TypeError::ParenUnclosed(c) => write!(fmt, "Type spec left {} parens unclosed", c),
-            _ => write!(fmt, "{}", ::std::error::Error::description(self))
+        match *self {
+            TypeError::Invalid(c) => write!(fmt, "Type spec contained invalid character '{}'", c),
+            TypeError::ParenUnclosed(c) => write!(fmt, "Type spec left {} parens unclosed", c),
+            _ => fmt.write_str(self.message())
         }
     }
 }
 
-impl ::std::error::Error for TypeError {
-    fn description(&self) -> &str {
-        match self {
-            &TypeError::Invalid(_) => "Type spec contained invalid character",
-            &TypeError::ParenUnclosed(_) => "Type spec left parens unclosed",
-            &TypeError::ParenClosedBeforeOpen => "Type spec closed a paren without having any open",
-            &TypeError::ElementRequired => "Type spec is missing required element for array",
+impl ::std::error::Error for TypeError {}
+
+impl TypeError {
+    fn message(&self) -> &'static str {
+        match *self {
+            TypeError::Invalid(_) => "Type spec contained invalid character",
+            TypeError::ParenUnclosed(_) => "Type spec left parens unclosed",
+            TypeError::ParenClosedBeforeOpen => "Type spec closed a paren without having any open",
+            TypeError::ElementRequired => "Type spec is missing required element for array",
+            TypeError::DictEntryNotInArray => "Type spec has a dict entry that is not an array element",
+            TypeError::DictEntryFields => "Type spec has a dict entry without exactly two fields",
+            TypeError::DictKeyNotBasic => "Type spec has a dict entry whose key is not a basic type",
+            TypeError::StructEmpty => "Type spec has a struct with no fields",
+            TypeError::ArrayTooDeep => "Type spec nests arrays more than 32 deep",
+            TypeError::StructTooDeep => "Type spec nests structs and dict entries more than 32 deep",
         }
     }
 }
 
 impl<'a> Type<'a> {
+    /* not `FromStr`, as the type borrows the string it is parsed from */
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(v: &'a str) -> Result<Type<'a>, TypeError> {
-        let mut depth = 0u64;
-        let mut element_required = false;
-
-        /* validate */
-        for i in v.chars() {
-            match i {
-                'y'|'b'|'n'|'q'|'i'|'u'|'x'|'t'|'d'|'h'|
-                'v'|
-                's'|'o'|'g' => {
-                    /* valid types */
-                    element_required = false;
+        validate(v, false)?;
+        Ok(Type { v })
+    }
+
+    /**
+     * Parse a GVariant type string, which may also hold maybe types ('m' followed by the type it
+     * may hold) and the unit type, '()'.
+     */
+    pub fn from_gvariant_str(v: &'a str) -> Result<Type<'a>, TypeError> {
+        validate(v, true)?;
+        Ok(Type { v })
+    }
+
+    /*
+    pub fn append_type<T: BasicType>(&mut self) {
+        unimplemented!();
+    }
+    */
+
+    pub fn append_type_code(&mut self, _code: u8) -> Result<(), String> {
+        unimplemented!();
+    }
+}
+
+/*
+ * Check that `v` is a sequence of complete types. `gvariant` allows the types that only GVariant
+ * has, which are held to the same nesting limits.
+ */
+fn validate(v: &str, gvariant: bool) -> Result<(), TypeError> {
+    /* the open containers, the number of complete types in each, and the arrays around them */
+    let mut open: Vec<(char, usize, usize)> = vec![];
+    let mut element_required = false;
+    /* arrays whose element has not yet started */
+    let mut arrays = 0;
+
+    /* validate */
+    for i in v.chars() {
+        /* a dict entry holds a basic key and a value, and nothing else */
+        if let Some(&('{', fields, _)) = open.last() {
+            if !element_required && i != '}' {
+                if fields == 0 && !is_basic(i) {
+                    return Err(TypeError::DictKeyNotBasic);
                 }
-                'a' => {
-                    element_required = true;
+                if fields >= 2 {
+                    return Err(TypeError::DictEntryFields);
                 }
-                '(' => {
-                    depth += 1;
-                },
-                ')' => {
-                    if depth == 0 {
-                        return Err(TypeError::ParenClosedBeforeOpen);
+            }
+        }
+
+        match i {
+            'y'|'b'|'n'|'q'|'i'|'u'|'x'|'t'|'d'|'h'|
+            'v'|
+            's'|'o'|'g' => {
+                /* valid types */
+                element_required = false;
+            }
+            'a' => {
+                arrays += 1;
+                if open.last().map_or(0, |o| o.2) + arrays > MAX_ARRAY_DEPTH {
+                    return Err(TypeError::ArrayTooDeep);
+                }
+                element_required = true;
+                continue;
+            }
+            'm' if gvariant => {
+                element_required = true;
+                continue;
+            }
+            '(' | '{' => {
+                if i == '{' && !element_required {
+                    return Err(TypeError::DictEntryNotInArray);
+                }
+                if open.len() == MAX_STRUCT_DEPTH {
+                    return Err(TypeError::StructTooDeep);
+                }
+                let around = open.last().map_or(0, |o| o.2) + arrays;
+                open.push((i, 0, around));
+                arrays = 0;
+                element_required = false;
+                continue;
+            },
+            ')' | '}' => {
+                let start = if i == ')' { '(' } else { '{' };
+                match open.pop() {
+                    Some((c, fields, _)) if c == start => {
+                        if element_required {
+                            return Err(TypeError::ElementRequired);
+                        }
+                        if c == '{' && fields != 2 {
+                            return Err(TypeError::DictEntryFields);
+                        }
+                        if c == '(' && fields == 0 && !gvariant {
+                            return Err(TypeError::StructEmpty);
+                        }
                     }
-                    depth -= 1;
-                    element_required = false;
-                },
-                a => {
-                    /* invalid character */
-                    return Err(TypeError::Invalid(a))
+                    _ => return Err(TypeError::ParenClosedBeforeOpen),
                 }
+            },
+            a => {
+                /* invalid character */
+                return Err(TypeError::Invalid(a))
             }
         }
 
-        if depth != 0 {
-            Err(TypeError::ParenUnclosed(depth))
-        } else if element_required {
-            Err(TypeError::ElementRequired)
-        } else {
-            Ok(Type { v: v })
+        /* a complete type has ended */
+        arrays = 0;
+        if let Some(&mut (_, ref mut fields, _)) = open.last_mut() {
+            *fields += 1;
         }
     }
 
-    /*
-    pub fn append_type<T: BasicType>(&mut self) {
-        unimplemented!();
+    if !open.is_empty() {
+        Err(TypeError::ParenUnclosed(open.len() as u64))
+    } else if element_required {
+        Err(TypeError::ElementRequired)
+    } else {
+        Ok(())
     }
-    */
+}
 
-    pub fn append_type_code(&mut self, _code: u8) -> Result<(), String> {
-        unimplemented!();
+/*
+ * Basic types are those that may be the key of a dict entry
+ */
+fn is_basic(c: char) -> bool {
+    "ybnqiuxtdhsog".contains(c)
+}
+
+impl TypeBuf {
+    pub fn from_string(v: String) -> Result<TypeBuf, TypeError> {
+        Type::from_str(&v)?;
+        Ok(TypeBuf { v })
+    }
+
+    /// Take ownership of a GVariant type string, see `Type::from_gvariant_str()`
+    pub fn from_gvariant_string(v: String) -> Result<TypeBuf, TypeError> {
+        Type::from_gvariant_str(&v)?;
+        Ok(TypeBuf { v })
+    }
+
+    pub fn as_type(&self) -> Type<'_> {
+        Type { v: &self.v }
+    }
+}
+
+impl ::std::convert::AsRef<str> for TypeBuf {
+    fn as_ref(&self) -> &str {
+        &self.v
     }
 }
 
@@ -111,7 +237,7 @@ impl<'a> ::std::borrow::Borrow<str> for Type<'a> {
 
 #[cfg(test)]
 mod test {
-    use super::Type;
+    use super::{Type, TypeError};
 
     #[test]
     fn full_strings() {
@@ -122,7 +248,72 @@ mod test {
         Type::from_str("ii").unwrap();
         Type::from_str("aiai").unwrap();
         Type::from_str("(ii)(ii)").unwrap();
+        Type::from_str("()").err().unwrap();
+        Type::from_str("a(i())").err().unwrap();
 
         Type::from_str("").unwrap();
     }
+
+    #[test]
+    fn dict_entries() {
+        Type::from_str("a{sv}").unwrap();
+        Type::from_str("a{oa{sa{sv}}}").unwrap();
+        Type::from_str("a{y(ii)}a{ta{s(i)}}").unwrap();
+
+        Type::from_str("{sv}").err().unwrap();
+        Type::from_str("(a{sv)}").err().unwrap();
+        Type::from_str("a{s}").err().unwrap();
+        Type::from_str("a{}").err().unwrap();
+        Type::from_str("a{svs}").err().unwrap();
+        Type::from_str("a{vs}").err().unwrap();
+        Type::from_str("a{(i)s}").err().unwrap();
+        Type::from_str("a{ass}").err().unwrap();
+        Type::from_str("a{sa}").err().unwrap();
+        Type::from_str("a{sv").err().unwrap();
+        Type::from_str("a(i}").err().unwrap();
+    }
+
+    #[test]
+    fn depth() {
+        let arrays = "a".repeat(32);
+        Type::from_str(&(arrays.clone() + "y")).unwrap();
+        Type::from_str(&(arrays.clone() + "(yy)")).unwrap();
+        match Type::from_str(&(arrays.clone() + "ay")) {
+            Err(TypeError::ArrayTooDeep) => {}
+            r => panic!("{:?}", r),
+        }
+        match Type::from_str(&(arrays.clone() + "(y" + &arrays + "ay)")) {
+            Err(TypeError::ArrayTooDeep) => {}
+            r => panic!("{:?}", r),
+        }
+        /* arrays that have ended do not count */
+        Type::from_str(&("(".to_owned() + &arrays + "y" + &arrays + "y)")).unwrap();
+
+        let structs = |n| "(".repeat(n) + "y" + &")".repeat(n);
+        Type::from_str(&structs(32)).unwrap();
+        Type::from_str(&("a{s".to_owned() + &structs(31) + "}")).unwrap();
+        match Type::from_str(&structs(33)) {
+            Err(TypeError::StructTooDeep) => {}
+            r => panic!("{:?}", r),
+        }
+        match Type::from_str(&("a{s".to_owned() + &structs(32) + "}")) {
+            Err(TypeError::StructTooDeep) => {}
+            r => panic!("{:?}", r),
+        }
+    }
+
+    #[test]
+    fn gvariant() {
+        Type::from_gvariant_str("ms").unwrap();
+        Type::from_gvariant_str("mmai").unwrap();
+        Type::from_gvariant_str("a{sms}").unwrap();
+        Type::from_gvariant_str("(ma{sv}())").unwrap();
+        Type::from_gvariant_str("()").unwrap();
+
+        Type::from_gvariant_str("m").err().unwrap();
+        Type::from_gvariant_str("am").err().unwrap();
+        Type::from_gvariant_str("a{msv}").err().unwrap();
+        Type::from_str("ms").err().unwrap();
+        Type::from_str("()").err().unwrap();
+    }
 }
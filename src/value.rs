@@ -0,0 +1,639 @@
+//! Dynamically typed D-Bus values, for data whose signature is only known at runtime (such as the
+//! contents of variants)
+
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::error;
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+use std::os::unix::io::{AsRawFd, OwnedFd};
+
+use marshal::{self, BasicType, DBusType, Data, DataIter, DecodeError, DecodeOwned, EncodeError};
+use names::ObjectPathBuf;
+use type_sig::{Type, TypeBuf};
+
+/**
+ * Any D-Bus value
+ *
+ * Arrays and dicts carry the signature of their elements, so that empty ones still have a type.
+ *
+ * As a `DBusType`, a `Value` is a variant (type 'v'): it is encoded as its own signature followed
+ * by its contents. `Value::Variant` is a variant nested inside another. `Value::Maybe` is the one
+ * exception: it exists for GVariant data and cannot be marshalled for D-Bus.
+ */
+#[derive(Debug)]
+pub enum Value {
+    Byte(u8),
+    Bool(bool),
+    Int16(i16),
+    UInt16(u16),
+    Int32(i32),
+    UInt32(u32),
+    Int64(i64),
+    UInt64(u64),
+    Double(f64),
+    String(String),
+    ObjectPath(ObjectPathBuf),
+    Signature(TypeBuf),
+    UnixFd(OwnedFd),
+    Variant(Box<Value>),
+    Array { element: TypeBuf, values: Vec<Value> },
+    Struct(Vec<Value>),
+    Dict { key: TypeBuf, value: TypeBuf, entries: Vec<(Value, Value)> },
+    /// A GVariant maybe (type 'm'), only used by the `gvariant` module. D-Bus has no maybe type,
+    /// so this can never be sent over D-Bus: encoding it as a `DBusType` fails with
+    /// `EncodeError::Invalid`, and decoding D-Bus data never produces it.
+    Maybe { element: TypeBuf, value: Option<Box<Value>> },
+}
+
+/// A `Value` does not hold the type it is being converted to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WrongType {
+    /// The signature of the type asked for
+    pub expected: String,
+    /// The signature of the value
+    pub found: String,
+}
+
+impl fmt::Display for WrongType {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "expected a value of type '{}', but found '{}'", self.expected, self.found)
+    }
+}
+
+impl error::Error for WrongType {}
+
+/*
+ * The signature of `T`, which is always valid
+ */
+fn sig_of<T: DBusType>() -> TypeBuf {
+    let mut sig = vec![];
+    T::signature(&mut sig);
+    TypeBuf::from_string(String::from_utf8(sig).expect("signatures are ascii"))
+        .expect("DBusType signatures are valid")
+}
+
+fn type_buf(sig: &[u8], offset: usize) -> Result<TypeBuf, DecodeError> {
+    ::std::str::from_utf8(sig).ok()
+        .and_then(|s| TypeBuf::from_string(s.to_owned()).ok())
+        .ok_or(DecodeError::Invalid { offset, reason: "signature is not valid" })
+}
+
+impl Value {
+    /// The signature of the value, which is not checked for validity
+    pub fn signature_bytes(&self) -> Vec<u8> {
+        let mut sig = vec![];
+        self.append_signature(&mut sig);
+        sig
+    }
+
+    fn append_signature(&self, sig: &mut Vec<u8>) {
+        let code = match *self {
+            Value::Byte(_) => b'y',
+            Value::Bool(_) => b'b',
+            Value::Int16(_) => b'n',
+            Value::UInt16(_) => b'q',
+            Value::Int32(_) => b'i',
+            Value::UInt32(_) => b'u',
+            Value::Int64(_) => b'x',
+            Value::UInt64(_) => b't',
+            Value::Double(_) => b'd',
+            Value::String(_) => b's',
+            Value::ObjectPath(_) => b'o',
+            Value::Signature(_) => b'g',
+            Value::UnixFd(_) => b'h',
+            Value::Variant(_) => b'v',
+            Value::Array { ref element, .. } => {
+                sig.push(b'a');
+                sig.extend(element.as_ref().as_bytes());
+                return;
+            }
+            Value::Struct(ref fields) => {
+                sig.push(b'(');
+                for f in fields {
+                    f.append_signature(sig);
+                }
+                sig.push(b')');
+                return;
+            }
+            Value::Dict { ref key, ref value, .. } => {
+                sig.extend(b"a{");
+                sig.extend(key.as_ref().as_bytes());
+                sig.extend(value.as_ref().as_bytes());
+                sig.push(b'}');
+                return;
+            }
+            Value::Maybe { ref element, .. } => {
+                sig.push(b'm');
+                sig.extend(element.as_ref().as_bytes());
+                return;
+            }
+        };
+        sig.push(code);
+    }
+
+    /**
+     * Encode the contents of the value, without the signature that a variant is prefixed with
+     */
+    pub fn encode_contents(&self, msg: &mut Data) -> Result<(), EncodeError> {
+        match *self {
+            Value::Byte(v) => v.encode_into(msg),
+            Value::Bool(v) => v.encode_into(msg),
+            Value::Int16(v) => v.encode_into(msg),
+            Value::UInt16(v) => v.encode_into(msg),
+            Value::Int32(v) => v.encode_into(msg),
+            Value::UInt32(v) => v.encode_into(msg),
+            Value::Int64(v) => v.encode_into(msg),
+            Value::UInt64(v) => v.encode_into(msg),
+            Value::Double(v) => v.encode_into(msg),
+            Value::String(ref v) => v.encode_into(msg),
+            Value::ObjectPath(ref v) => v.encode_into(msg),
+            Value::Signature(ref v) => v.encode_into(msg),
+            Value::UnixFd(ref v) => v.encode_into(msg),
+            Value::Variant(ref v) => v.encode_into(msg),
+            Value::Array { ref element, ref values } => {
+                let element = element.as_ref().as_bytes();
+                let align = match element.first() {
+                    Some(&c) => marshal::alignment(c),
+                    None => return Err(EncodeError::Invalid("array has no element type")),
+                };
+                marshal::encode_array(msg, align, |msg| {
+                    for v in values {
+                        if v.signature_bytes() != element {
+                            return Err(EncodeError::Invalid("array element does not have the array's element type"));
+                        }
+                        v.encode_contents(msg)?;
+                    }
+                    Ok(())
+                })
+            }
+            Value::Struct(ref fields) => {
+                marshal::encode_struct(msg, |msg| {
+                    for f in fields {
+                        f.encode_contents(msg)?;
+                    }
+                    Ok(())
+                })
+            }
+            Value::Dict { ref key, ref value, ref entries } => {
+                let (key, value) = (key.as_ref().as_bytes(), value.as_ref().as_bytes());
+                marshal::encode_array(msg, 8, |msg| {
+                    for (k, v) in entries {
+                        if k.signature_bytes() != key || v.signature_bytes() != value {
+                            return Err(EncodeError::Invalid("dict entry does not have the dict's entry type"));
+                        }
+                        marshal::encode_struct(msg, |msg| {
+                            k.encode_contents(msg)?;
+                            v.encode_contents(msg)
+                        })?;
+                    }
+                    Ok(())
+                })
+            }
+            Value::Maybe { .. } => Err(EncodeError::Invalid("maybe types cannot be marshalled for D-Bus")),
+        }
+    }
+
+    /**
+     * Decode a value whose signature is `sig`, which must be a single complete type
+     */
+    pub fn decode_contents(iter: &mut DataIter, sig: &[u8]) -> Result<Value, DecodeError> {
+        let offset = iter.offset();
+        let v = match sig {
+            b"y" => Value::Byte(u8::decode_from(iter)?),
+            b"b" => Value::Bool(bool::decode_from(iter)?),
+            b"n" => Value::Int16(i16::decode_from(iter)?),
+            b"q" => Value::UInt16(u16::decode_from(iter)?),
+            b"i" => Value::Int32(i32::decode_from(iter)?),
+            b"u" => Value::UInt32(u32::decode_from(iter)?),
+            b"x" => Value::Int64(i64::decode_from(iter)?),
+            b"t" => Value::UInt64(u64::decode_from(iter)?),
+            b"d" => Value::Double(f64::decode_from(iter)?),
+            b"s" => Value::String(String::decode_from(iter)?),
+            b"o" => Value::ObjectPath(ObjectPathBuf::decode_from(iter)?),
+            b"g" => Value::Signature(TypeBuf::decode_from(iter)?),
+            b"h" => Value::UnixFd(OwnedFd::decode_from(iter)?),
+            b"v" => Value::Variant(Box::new(Value::decode_from(iter)?)),
+            _ if sig.starts_with(b"a{") && sig.len() > 4 => {
+                let (key, value) = (&sig[2..3], &sig[3..sig.len() - 1]);
+                let (key_t, value_t) = (type_buf(key, offset)?, type_buf(value, offset)?);
+                let mut entries = vec![];
+                marshal::decode_array(iter, 8, |iter| {
+                    let entry = marshal::decode_struct(iter, |iter| {
+                        Ok((Value::decode_contents(iter, key)?, Value::decode_contents(iter, value)?))
+                    })?;
+                    entries.push(entry);
+                    Ok(())
+                })?;
+                Value::Dict { key: key_t, value: value_t, entries }
+            }
+            _ if sig.len() > 1 && sig[0] == b'a' => {
+                let element = &sig[1..];
+                let element_t = type_buf(element, offset)?;
+                let mut values = vec![];
+                marshal::decode_array(iter, marshal::alignment(element[0]), |iter| {
+                    values.push(Value::decode_contents(iter, element)?);
+                    Ok(())
+                })?;
+                Value::Array { element: element_t, values }
+            }
+            _ if sig.len() > 2 && sig[0] == b'(' => {
+                let fields = marshal::decode_struct(iter, |iter| {
+                    let mut fields = vec![];
+                    let mut rest = &sig[1..sig.len() - 1];
+                    while !rest.is_empty() {
+                        let l = marshal::single_type_len(rest)
+                            .ok_or(DecodeError::Invalid { offset, reason: "signature is not valid" })?;
+                        fields.push(Value::decode_contents(iter, &rest[..l])?);
+                        rest = &rest[l..];
+                    }
+                    Ok(fields)
+                })?;
+                Value::Struct(fields)
+            }
+            _ => return Err(DecodeError::Invalid { offset, reason: "signature is not valid" }),
+        };
+        Ok(v)
+    }
+}
+
+/// Values are equal if they have the same type and contents. Unix fds are equal if they are the same
+/// fd number.
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Byte(a), Value::Byte(b)) => a == b,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Int16(a), Value::Int16(b)) => a == b,
+            (Value::UInt16(a), Value::UInt16(b)) => a == b,
+            (Value::Int32(a), Value::Int32(b)) => a == b,
+            (Value::UInt32(a), Value::UInt32(b)) => a == b,
+            (Value::Int64(a), Value::Int64(b)) => a == b,
+            (Value::UInt64(a), Value::UInt64(b)) => a == b,
+            (Value::Double(a), Value::Double(b)) => a == b,
+            (Value::String(a), Value::String(b)) => a == b,
+            (Value::ObjectPath(a), Value::ObjectPath(b)) => a == b,
+            (Value::Signature(a), Value::Signature(b)) => a == b,
+            (Value::UnixFd(a), Value::UnixFd(b)) => a.as_raw_fd() == b.as_raw_fd(),
+            (Value::Variant(a), Value::Variant(b)) => a == b,
+            (Value::Array { element: ae, values: a },
+             Value::Array { element: be, values: b }) => ae == be && a == b,
+            (Value::Struct(a), Value::Struct(b)) => a == b,
+            (Value::Dict { key: ak, value: av, entries: a },
+             Value::Dict { key: bk, value: bv, entries: b }) => ak == bk && av == bv && a == b,
+            (Value::Maybe { element: ae, value: a },
+             Value::Maybe { element: be, value: b }) => ae == be && a == b,
+            _ => false,
+        }
+    }
+}
+
+impl DBusType for Value {
+    fn signature(sig: &mut Vec<u8>) {
+        sig.push(b'v');
+    }
+
+    /// The signature must be valid and no more than 255 bytes long
+    fn encode_into(&self, msg: &mut Data) -> Result<(), EncodeError>
+    {
+        let sig = String::from_utf8(self.signature_bytes())
+            .map_err(|_| EncodeError::Invalid("value does not have a valid signature"))?;
+        let t = Type::from_str(&sig)
+            .map_err(|e| marshal::encode_type_error(e, "value does not have a valid signature"))?;
+        msg.nested(|msg| {
+            t.encode_into(msg)?;
+            self.encode_contents(msg)
+        })
+    }
+}
+
+impl DecodeOwned for Value {
+    /// Decodes the contents of the variant, rather than a `Value::Variant` holding them
+    fn decode_from(iter: &mut DataIter) -> Result<Self, DecodeError> {
+        iter.nested(|iter| {
+            let offset = iter.offset();
+            let sig = TypeBuf::decode_from(iter)?;
+            let sig = sig.as_ref().as_bytes();
+            if marshal::single_type_len(sig) != Some(sig.len()) {
+                return Err(DecodeError::Invalid { offset, reason: "variant signature is not a single complete type" });
+            }
+            Value::decode_contents(iter, sig)
+        })
+    }
+}
+
+fn wrong_type<T: DBusType>(v: &Value) -> WrongType {
+    let mut expected = vec![];
+    T::signature(&mut expected);
+    WrongType {
+        expected: String::from_utf8_lossy(&expected).into_owned(),
+        found: String::from_utf8_lossy(&v.signature_bytes()).into_owned(),
+    }
+}
+
+/**
+ * Types that convert to and from a `Value` of the same signature
+ *
+ * This is what `From` and `TryFrom` use to convert the elements of containers. `Value` itself is
+ * a variant, so it converts to and from `Value::Variant`.
+ */
+pub trait ValueType: DBusType + Sized {
+    fn into_value(self) -> Value;
+    fn from_value(v: Value) -> Result<Self, WrongType>;
+}
+
+impl ValueType for Value {
+    fn into_value(self) -> Value {
+        Value::Variant(Box::new(self))
+    }
+
+    fn from_value(v: Value) -> Result<Value, WrongType> {
+        match v {
+            Value::Variant(v) => Ok(*v),
+            v => Err(wrong_type::<Value>(&v)),
+        }
+    }
+}
+
+/*
+ * `From` and `TryFrom` for types that are `ValueType`
+ */
+macro_rules! value_conversions {
+    ([$($g:tt)*] $t:ty) => {
+        impl<$($g)*> From<$t> for Value {
+            fn from(v: $t) -> Value {
+                v.into_value()
+            }
+        }
+
+        impl<$($g)*> TryFrom<Value> for $t {
+            type Error = WrongType;
+
+            fn try_from(v: Value) -> Result<$t, WrongType> {
+                <$t>::from_value(v)
+            }
+        }
+    }
+}
+
+macro_rules! value_type {
+    ($t:ty, $variant:ident) => {
+        impl ValueType for $t {
+            fn into_value(self) -> Value {
+                Value::$variant(self)
+            }
+
+            fn from_value(v: Value) -> Result<$t, WrongType> {
+                match v {
+                    Value::$variant(v) => Ok(v),
+                    v => Err(wrong_type::<$t>(&v)),
+                }
+            }
+        }
+
+        value_conversions!([] $t);
+    }
+}
+
+value_type!(u8, Byte);
+value_type!(bool, Bool);
+value_type!(i16, Int16);
+value_type!(u16, UInt16);
+value_type!(i32, Int32);
+value_type!(u32, UInt32);
+value_type!(i64, Int64);
+value_type!(u64, UInt64);
+value_type!(f64, Double);
+value_type!(String, String);
+value_type!(ObjectPathBuf, ObjectPath);
+value_type!(TypeBuf, Signature);
+value_type!(OwnedFd, UnixFd);
+
+impl From<&str> for Value {
+    fn from(v: &str) -> Value {
+        Value::String(v.to_owned())
+    }
+}
+
+impl<T: ValueType> ValueType for Vec<T> {
+    fn into_value(self) -> Value {
+        Value::Array { element: sig_of::<T>(), values: self.into_iter().map(T::into_value).collect() }
+    }
+
+    fn from_value(v: Value) -> Result<Vec<T>, WrongType> {
+        if v.signature_bytes() != sig_of::<Self>().as_ref().as_bytes() {
+            return Err(wrong_type::<Self>(&v));
+        }
+        match v {
+            Value::Array { values, .. } => values.into_iter().map(T::from_value).collect(),
+            v => Err(wrong_type::<Self>(&v)),
+        }
+    }
+}
+
+value_conversions!([T: ValueType] Vec<T>);
+
+/*
+ * Convert a dict value into its entries, if it is of type `D`
+ */
+fn dict_entries<K: ValueType, V: ValueType, D: DBusType>(v: Value) -> Result<Vec<(K, V)>, WrongType> {
+    if v.signature_bytes() != sig_of::<D>().as_ref().as_bytes() {
+        return Err(wrong_type::<D>(&v));
+    }
+    match v {
+        Value::Dict { entries, .. } => {
+            entries.into_iter().map(|(k, v)| Ok((K::from_value(k)?, V::from_value(v)?))).collect()
+        }
+        v => Err(wrong_type::<D>(&v)),
+    }
+}
+
+fn dict_value<K: ValueType, V: ValueType, I: Iterator<Item=(K, V)>>(entries: I) -> Value {
+    Value::Dict {
+        key: sig_of::<K>(),
+        value: sig_of::<V>(),
+        entries: entries.map(|(k, v)| (k.into_value(), v.into_value())).collect(),
+    }
+}
+
+/// Later entries replace earlier ones with the same key
+impl<K, V, S> ValueType for HashMap<K, V, S>
+    where K: BasicType + ValueType + Eq + Hash, V: ValueType, S: BuildHasher + Default
+{
+    fn into_value(self) -> Value {
+        dict_value(self.into_iter())
+    }
+
+    fn from_value(v: Value) -> Result<Self, WrongType> {
+        Ok(dict_entries::<K, V, Self>(v)?.into_iter().collect())
+    }
+}
+
+value_conversions!([K: BasicType + ValueType + Eq + Hash, V: ValueType, S: BuildHasher + Default] HashMap<K, V, S>);
+
+/// Later entries replace earlier ones with the same key
+impl<K, V> ValueType for BTreeMap<K, V>
+    where K: BasicType + ValueType + Ord, V: ValueType
+{
+    fn into_value(self) -> Value {
+        dict_value(self.into_iter())
+    }
+
+    fn from_value(v: Value) -> Result<Self, WrongType> {
+        Ok(dict_entries::<K, V, Self>(v)?.into_iter().collect())
+    }
+}
+
+value_conversions!([K: BasicType + ValueType + Ord, V: ValueType] BTreeMap<K, V>);
+
+macro_rules! value_tuple {
+    ($($n:tt $t:ident),+) => {
+        impl<$($t: ValueType),+> ValueType for ($($t,)+) {
+            fn into_value(self) -> Value {
+                Value::Struct(vec![$(self.$n.into_value()),+])
+            }
+
+            fn from_value(v: Value) -> Result<Self, WrongType> {
+                if v.signature_bytes() != sig_of::<Self>().as_ref().as_bytes() {
+                    return Err(wrong_type::<Self>(&v));
+                }
+                match v {
+                    Value::Struct(fields) => {
+                        let mut fields = fields.into_iter();
+                        Ok(($($t::from_value(fields.next().expect("signature matched"))?,)+))
+                    }
+                    v => Err(wrong_type::<Self>(&v)),
+                }
+            }
+        }
+
+        value_conversions!([$($t: ValueType),+] ($($t,)+));
+    }
+}
+
+value_tuple!(0 A);
+value_tuple!(0 A, 1 B);
+value_tuple!(0 A, 1 B, 2 C);
+value_tuple!(0 A, 1 B, 2 C, 3 D);
+value_tuple!(0 A, 1 B, 2 C, 3 D, 4 E);
+value_tuple!(0 A, 1 B, 2 C, 3 D, 4 E, 5 F);
+value_tuple!(0 A, 1 B, 2 C, 3 D, 4 E, 5 F, 6 G);
+value_tuple!(0 A, 1 B, 2 C, 3 D, 4 E, 5 F, 6 G, 7 H);
+value_tuple!(0 A, 1 B, 2 C, 3 D, 4 E, 5 F, 6 G, 7 H, 8 I);
+value_tuple!(0 A, 1 B, 2 C, 3 D, 4 E, 5 F, 6 G, 7 H, 8 I, 9 J);
+value_tuple!(0 A, 1 B, 2 C, 3 D, 4 E, 5 F, 6 G, 7 H, 8 I, 9 J, 10 K);
+value_tuple!(0 A, 1 B, 2 C, 3 D, 4 E, 5 F, 6 G, 7 H, 8 I, 9 J, 10 K, 11 L);
+
+#[cfg(test)]
+mod test {
+    use std::collections::{BTreeMap, HashMap};
+    use std::convert::TryFrom;
+
+    use super::{Value, WrongType};
+    use header::ENDIAN_LITTLE as LE;
+    use marshal::{Data, DataIter, DecodeError, EncodeError};
+    use type_sig::TypeBuf;
+
+    fn sig(s: &str) -> TypeBuf {
+        TypeBuf::from_string(s.to_owned()).unwrap()
+    }
+
+    #[test]
+    fn variants() {
+        let mut m = Data::new();
+        m.append(Value::UInt32(7)).unwrap();
+        m.append(Value::from("ab")).unwrap();
+        assert_eq!(m.bytes(), [
+            1, b'u', 0, 0, 7, 0, 0, 0,
+            1, b's', 0, 0, 2, 0, 0, 0, b'a', b'b', 0,
+        ]);
+
+        let d = Data::from_parts(m.bytes().to_vec(), b"vv".to_vec(), vec![], LE);
+        let mut i = d.iter();
+        assert_eq!(i.read::<Value>().unwrap(), Value::UInt32(7));
+        assert_eq!(i.read::<Value>().unwrap(), Value::String("ab".to_owned()));
+
+        /* a variant holding a variant */
+        let mut m = Data::new();
+        m.append(Value::Variant(Box::new(Value::Byte(3)))).unwrap();
+        assert_eq!(m.bytes(), [1, b'v', 0, 1, b'y', 0, 3]);
+        let d = Data::from_parts(m.bytes().to_vec(), b"v".to_vec(), vec![], LE);
+        assert_eq!(d.iter().read::<Value>().unwrap(), Value::Variant(Box::new(Value::Byte(3))));
+
+        let mut i = DataIter::new(&[2, b'y', b'y', 0, 1, 2], b"v", &[], LE);
+        assert_eq!(i.read::<Value>(), Err(DecodeError::Invalid {
+            offset: 0, reason: "variant signature is not a single complete type" }));
+    }
+
+    #[test]
+    fn containers() {
+        let mut props = BTreeMap::new();
+        props.insert("Name".to_owned(), Value::from("x"));
+        props.insert("Ids".to_owned(), Value::from(vec![(1u8, 2u64)]));
+        let v = Value::from(vec![props]);
+        assert_eq!(v.signature_bytes(), b"aa{sv}");
+        assert_eq!(Value::from((1u8, vec![true])).signature_bytes(), b"(yab)");
+
+        let mut m = Data::new();
+        m.append(1u8).unwrap();
+        m.append(&v).unwrap();
+        m.append(7u32).unwrap();
+
+        /* the same bytes decode whether or not the types are known at compile time */
+        let d = Data::from_parts(m.bytes().to_vec(), b"yvu".to_vec(), vec![], LE);
+        let mut i = d.iter();
+        i.read::<u8>().unwrap();
+        assert_eq!(i.read::<Value>().unwrap(), v);
+        assert_eq!(i.read::<u32>().unwrap(), 7);
+
+        let mut m = Data::new();
+        v.encode_contents(&mut m).unwrap();
+        m.append(7u32).unwrap();
+        let d = Data::from_parts(m.bytes().to_vec(), b"aa{sv}u".to_vec(), vec![], LE);
+        let mut i = d.iter();
+        let dynamic = i.read_value().unwrap();
+        assert_eq!(i.read_value().unwrap(), Value::UInt32(7));
+        assert!(i.is_empty());
+        let mut i = d.iter();
+        let typed = i.read::<Vec<BTreeMap<String, Value>>>().unwrap();
+        assert_eq!(Value::from(typed), dynamic);
+        assert_eq!(dynamic, v);
+
+        let empty = Value::Array { element: sig("(ss)"), values: vec![] };
+        let mut m = Data::new();
+        m.append(&empty).unwrap();
+        assert_eq!(m.bytes(), [5, b'a', b'(', b's', b's', b')', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn conversions() {
+        assert_eq!(u32::try_from(Value::UInt32(3)), Ok(3));
+        assert_eq!(u32::try_from(Value::Int32(3)), Err(WrongType {
+            expected: "u".to_owned(), found: "i".to_owned() }));
+        assert_eq!(Vec::<u8>::try_from(Value::from(vec![1u8, 2])), Ok(vec![1, 2]));
+        assert_eq!(Vec::<u8>::try_from(Value::Array { element: sig("u"), values: vec![] }), Err(WrongType {
+            expected: "ay".to_owned(), found: "au".to_owned() }));
+        assert_eq!(<(String, bool)>::try_from(Value::from(("a".to_owned(), true))), Ok(("a".to_owned(), true)));
+        assert!(<(String, u8)>::try_from(Value::from(("a".to_owned(), true))).is_err());
+
+        let mut h = HashMap::new();
+        h.insert(1u16, vec!["a".to_owned()]);
+        assert_eq!(HashMap::try_from(Value::from(h.clone())), Ok(h.clone()));
+        assert!(BTreeMap::<u16, Vec<u8>>::try_from(Value::from(h)).is_err());
+
+        let mixed = Value::Array { element: sig("u"), values: vec![Value::UInt32(1), Value::Byte(2)] };
+        match Data::new().append(&mixed) {
+            Err(EncodeError::Invalid(_)) => {}
+            r => panic!("{:?}", r),
+        }
+        match Data::new().append(Value::Struct(vec![])) {
+            Err(EncodeError::Invalid(_)) => {}
+            r => panic!("{:?}", r),
+        }
+        /* maybe types only exist in GVariant */
+        match Data::new().append(Value::Maybe { element: sig("i"), value: None }) {
+            Err(EncodeError::Invalid(_)) => {}
+            r => panic!("{:?}", r),
+        }
+    }
+}